@@ -1,66 +1,615 @@
-use anyhow::Result;
-use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+use crate::opus::{OpusReader, OPUS_RATE};
+use anyhow::{anyhow, Context, Result};
+use std::fs::File;
 use std::path::Path;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL, CODEC_TYPE_OPUS};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
 use tch::{Device, Tensor};
 
-/// loads a wav file into a pytorch tensor with shape [channels, samples]
-/// normalizes to f32 and ensures stereo output
-pub fn load_wav_to_tensor(path: &Path, device: Device) -> Result<(Tensor, u32)> {
-    let mut reader = WavReader::open(path)?;
-    let spec = reader.spec();
+/// audio container extensions we can decode, used by upload validation
+pub const SUPPORTED_AUDIO_EXTENSIONS: &[&str] = &[
+    ".wav", ".mp3", ".flac", ".ogg", ".oga", ".opus", ".aiff", ".aif", ".m4a", ".aac",
+];
 
-    // collect samples as f32
-    let samples: Vec<f32> = match spec.sample_format {
-        SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
-        SampleFormat::Int => {
-            let max_val = (1 << (spec.bits_per_sample - 1)) as f32;
-            reader
-                .samples::<i32>()
-                .map(|s| s.map(|v| v as f32 / max_val))
-                .collect::<Result<_, _>>()?
+/// decoded pcm audio with interleaved f32 samples in [-1, 1]
+pub struct DecodedAudio {
+    pub samples: Vec<f32>,
+    pub channels: usize,
+    pub sample_rate: u32,
+}
+
+/// decodes any supported container/codec into interleaved f32 samples.
+/// the file extension is only used as a probe hint, the actual format is sniffed
+pub fn decode_audio_file(path: &Path) -> Result<DecodedAudio> {
+    let mut stream = AudioStream::open(path)?;
+
+    let mut samples: Vec<f32> = Vec::new();
+    while let Some(packet) = stream.next_samples()? {
+        samples.extend_from_slice(&packet);
+    }
+
+    if samples.is_empty() {
+        return Err(anyhow!("no audio decoded from {}", path.display()));
+    }
+
+    Ok(DecodedAudio {
+        samples,
+        channels: stream.channels(),
+        sample_rate: stream.sample_rate(),
+    })
+}
+
+/// decodes an audio file packet by packet, so long recordings never have to
+/// sit in memory whole
+pub struct AudioStream {
+    source: Source,
+    /// samples decoded while looking for the stream layout
+    pending: Vec<f32>,
+    channels: usize,
+    sample_rate: u32,
+}
+
+enum Source {
+    Symphonia {
+        format: Box<dyn FormatReader>,
+        decoder: Box<dyn Decoder>,
+        track_id: u32,
+        sample_buf: Option<SampleBuffer<f32>>,
+    },
+    Opus(OpusReader),
+}
+
+impl AudioStream {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+        let mut hint = Hint::new();
+        if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+            hint.with_extension(ext);
+        }
+
+        let probed = symphonia::default::get_probe()
+            .format(
+                &hint,
+                mss,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .with_context(|| format!("unrecognized audio format: {}", path.display()))?;
+
+        let format = probed.format;
+
+        // pick the first track that actually carries audio
+        let track = format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or_else(|| anyhow!("no audio track found in {}", path.display()))?;
+
+        // symphonia has no opus decoder, libopus takes over from here
+        if track.codec_params.codec == CODEC_TYPE_OPUS {
+            let reader = OpusReader::open(path)?;
+            return Ok(Self {
+                channels: reader.channels(),
+                sample_rate: OPUS_RATE,
+                pending: Vec::new(),
+                source: Source::Opus(reader),
+            });
+        }
+
+        let track_id = track.id;
+        let sample_rate = track.codec_params.sample_rate.unwrap_or(0);
+        let channels = track.codec_params.channels.map_or(0, |c| c.count());
+
+        let decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .context("unsupported audio codec")?;
+
+        let mut stream = Self {
+            source: Source::Symphonia {
+                format,
+                decoder,
+                track_id,
+                sample_buf: None,
+            },
+            pending: Vec::new(),
+            channels,
+            sample_rate,
+        };
+
+        // some containers only reveal the layout once the first packet is decoded
+        if stream.sample_rate == 0 || stream.channels == 0 {
+            stream.pending = stream.decode_next()?.unwrap_or_default();
+        }
+        if stream.sample_rate == 0 {
+            return Err(anyhow!("unknown sample rate"));
+        }
+        if stream.channels == 0 {
+            return Err(anyhow!("unknown channel count"));
+        }
+
+        Ok(stream)
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// interleaved samples of the next packet, None at the end of the stream
+    pub fn next_samples(&mut self) -> Result<Option<Vec<f32>>> {
+        if !self.pending.is_empty() {
+            return Ok(Some(std::mem::take(&mut self.pending)));
         }
-    };
+        self.decode_next()
+    }
+
+    fn decode_next(&mut self) -> Result<Option<Vec<f32>>> {
+        let (format, decoder, track_id, sample_buf) = match &mut self.source {
+            Source::Symphonia {
+                format,
+                decoder,
+                track_id,
+                sample_buf,
+            } => (format, decoder, *track_id, sample_buf),
+            Source::Opus(reader) => return reader.next_samples(),
+        };
+
+        loop {
+            let packet = match format.next_packet() {
+                Ok(packet) => packet,
+                // end of stream is reported as an unexpected eof
+                Err(SymphoniaError::IoError(e))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            if packet.track_id() != track_id {
+                continue;
+            }
 
-    let n_channels = spec.channels as usize;
-    let _n_samples = samples.len() / n_channels;
+            let decoded = match decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(SymphoniaError::DecodeError(e)) => {
+                    // a corrupt frame shouldn't sink the whole file
+                    eprintln!("skipping undecodable packet: {}", e);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let spec = *decoded.spec();
+            if self.sample_rate == 0 {
+                self.sample_rate = spec.rate;
+            }
+            if self.channels == 0 {
+                self.channels = spec.channels.count();
+            }
+
+            // (re)allocate the conversion buffer if this packet is bigger than the last
+            let needs_alloc = sample_buf
+                .as_ref()
+                .is_none_or(|buf| buf.capacity() < decoded.capacity() * spec.channels.count());
+            if needs_alloc {
+                *sample_buf = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+            }
+
+            let buf = sample_buf.as_mut().unwrap();
+            buf.copy_interleaved_ref(decoded);
+            return Ok(Some(buf.samples().to_vec()));
+        }
+    }
+}
+
+/// loads an audio file (wav, mp3, flac, ogg, opus, aiff, m4a) into a pytorch tensor
+/// with shape [channels, samples]. normalizes to f32 and ensures stereo output
+pub fn load_audio_to_tensor(path: &Path, device: Device) -> Result<(Tensor, u32)> {
+    let decoded = decode_audio_file(path)?;
+
+    let n_channels = decoded.channels;
+    let n_samples = decoded.samples.len() / n_channels;
 
     // create tensor [channels, samples]
-    let mut tensor = Tensor::from_slice(&samples)
+    let mut tensor = Tensor::from_slice(&decoded.samples[..n_samples * n_channels])
         .to_device(device)
-        .reshape(&[_n_samples as i64, n_channels as i64])
+        .reshape(&[n_samples as i64, n_channels as i64])
         .transpose(0, 1);
 
-    // ensure stereo - duplicate mono channel if needed
+    // ensure stereo - duplicate mono channel if needed, keep front left/right of surround
     if n_channels == 1 {
         tensor = tensor.repeat(&[2, 1]);
+    } else if n_channels > 2 {
+        tensor = tensor.narrow(0, 0, 2);
+    }
+
+    Ok((tensor.contiguous(), decoded.sample_rate))
+}
+
+/// zero crossings of the sinc kernel on each side, higher is sharper but slower
+const RESAMPLER_ZERO_CROSSINGS: usize = 32;
+/// passband edge as a fraction of the lower nyquist, leaves room for the transition band
+const RESAMPLER_ROLLOFF: f64 = 0.945;
+/// kaiser window shape, ~90db stopband attenuation
+const RESAMPLER_KAISER_BETA: f64 = 9.0;
+/// cap on precomputed filter phases, odd ratios interpolate between neighbours
+const RESAMPLER_MAX_PHASES: usize = 4096;
+
+/// band-limited polyphase resampler using a kaiser-windowed sinc kernel.
+/// the conversion ratio is reduced to up/down so every output sample maps to
+/// one of `up` filter phases, which are precomputed into a coefficient table
+pub struct Resampler {
+    up: usize,
+    down: usize,
+    half_taps: usize,
+    n_phases: usize,
+    table: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32) -> Self {
+        let g = gcd(from_rate as usize, to_rate as usize);
+        let up = to_rate as usize / g;
+        let down = from_rate as usize / g;
+
+        // when downsampling the cutoff moves to the output nyquist and the
+        // kernel has to widen to keep the same number of zero crossings
+        let cutoff = (up as f64 / down as f64).min(1.0) * RESAMPLER_ROLLOFF;
+        let half_taps = (RESAMPLER_ZERO_CROSSINGS as f64 / cutoff).ceil() as usize;
+        let n_phases = up.min(RESAMPLER_MAX_PHASES);
+
+        // one extra row so interpolation at the last phase has a right neighbour
+        let taps = 2 * half_taps;
+        let mut table = Vec::with_capacity((n_phases + 1) * taps);
+        for phase in 0..=n_phases {
+            let frac = phase as f64 / n_phases as f64;
+            for j in 0..taps {
+                table.push(kernel_tap(j, frac, half_taps, cutoff) as f32);
+            }
+        }
+
+        Self {
+            up,
+            down,
+            half_taps,
+            n_phases,
+            table,
+        }
+    }
+
+    /// number of output samples produced for `n_input` input samples
+    pub fn output_len(&self, n_input: usize) -> usize {
+        (n_input * self.up).div_ceil(self.down)
+    }
+
+    /// resamples a single channel
+    pub fn process(&self, input: &[f32]) -> Vec<f32> {
+        if self.up == self.down {
+            return input.to_vec();
+        }
+
+        let mut coeffs = vec![0.0f32; 2 * self.half_taps];
+        (0..self.output_len(input.len()))
+            .map(|n| self.output_sample(input, 0, n, &mut coeffs))
+            .collect()
+    }
+
+    /// output sample `n`, where `input` holds the signal from sample `offset` on
+    fn output_sample(&self, input: &[f32], offset: usize, n: usize, coeffs: &mut [f32]) -> f32 {
+        let taps = 2 * self.half_taps;
+        let pos = n * self.down;
+        let base = (pos / self.up) as isize;
+        let phase = pos % self.up;
+
+        if self.n_phases == self.up {
+            coeffs.copy_from_slice(&self.table[phase * taps..(phase + 1) * taps]);
+        } else {
+            // linear interpolation between the two nearest table phases
+            let f = phase as f64 * self.n_phases as f64 / self.up as f64;
+            let p0 = f as usize;
+            let w = (f - p0 as f64) as f32;
+            let row0 = &self.table[p0 * taps..(p0 + 1) * taps];
+            let row1 = &self.table[(p0 + 1) * taps..(p0 + 2) * taps];
+            for ((c, a), b) in coeffs.iter_mut().zip(row0).zip(row1) {
+                *c = a + (b - a) * w;
+            }
+        }
+
+        // first input sample under the kernel, relative to `input`
+        let first = base - self.half_taps as isize + 1 - offset as isize;
+
+        let mut acc = 0.0f32;
+        if first >= 0 && first as usize + taps <= input.len() {
+            let window = &input[first as usize..first as usize + taps];
+            for (x, c) in window.iter().zip(coeffs.iter()) {
+                acc += x * c;
+            }
+        } else {
+            // kernel hangs off either end, treat missing samples as silence
+            for (j, c) in coeffs.iter().enumerate() {
+                let idx = first + j as isize;
+                if idx >= 0 && (idx as usize) < input.len() {
+                    acc += input[idx as usize] * c;
+                }
+            }
+        }
+        acc
+    }
+}
+
+/// resamples one channel block by block, keeping only the input history the
+/// kernel still needs. the output matches `Resampler::process` on the whole signal
+pub struct StreamResampler {
+    resampler: Resampler,
+    /// input the kernel hasn't moved past yet, starting at sample `offset`
+    input: Vec<f32>,
+    offset: usize,
+    n_input: usize,
+    /// index of the next output sample
+    next_out: usize,
+    coeffs: Vec<f32>,
+}
+
+impl StreamResampler {
+    pub fn new(from_rate: u32, to_rate: u32) -> Self {
+        let resampler = Resampler::new(from_rate, to_rate);
+        let coeffs = vec![0.0f32; 2 * resampler.half_taps];
+
+        Self {
+            resampler,
+            input: Vec::new(),
+            offset: 0,
+            n_input: 0,
+            next_out: 0,
+            coeffs,
+        }
+    }
+
+    /// feeds the next block of input, returns every output sample it completes
+    pub fn push(&mut self, input: &[f32]) -> Vec<f32> {
+        self.input.extend_from_slice(input);
+        self.n_input += input.len();
+        self.drain(false)
+    }
+
+    /// the remaining output, samples past the end of the input count as silence
+    pub fn finish(&mut self) -> Vec<f32> {
+        self.drain(true)
+    }
+
+    fn drain(&mut self, at_end: bool) -> Vec<f32> {
+        let r = &self.resampler;
+
+        if r.up == r.down {
+            self.offset += self.input.len();
+            return std::mem::take(&mut self.input);
+        }
+
+        let n_out = r.output_len(self.n_input);
+        let mut output = Vec::new();
+
+        while self.next_out < n_out {
+            // last input sample under the kernel has to be there unless the input ended
+            let base = self.next_out * r.down / r.up;
+            if !at_end && base + r.half_taps + 1 > self.n_input {
+                break;
+            }
+            output.push(r.output_sample(&self.input, self.offset, self.next_out, &mut self.coeffs));
+            self.next_out += 1;
+        }
+
+        // drop what the kernel of the next output sample no longer reaches
+        let first_needed = (self.next_out * r.down / r.up + 1).saturating_sub(r.half_taps);
+        if first_needed > self.offset {
+            let consumed = (first_needed - self.offset).min(self.input.len());
+            self.input.drain(..consumed);
+            self.offset += consumed;
+        }
+
+        output
+    }
+}
+
+/// filter coefficient for tap `j` when the output sits `frac` input samples past the base
+fn kernel_tap(j: usize, frac: f64, half_taps: usize, cutoff: f64) -> f64 {
+    // distance from the output instant to this input sample, in input samples
+    let t = j as f64 - (half_taps as f64 - 1.0) - frac;
+    let x = t / half_taps as f64;
+    if x.abs() >= 1.0 {
+        return 0.0;
+    }
+
+    let window =
+        bessel_i0(RESAMPLER_KAISER_BETA * (1.0 - x * x).sqrt()) / bessel_i0(RESAMPLER_KAISER_BETA);
+    cutoff * sinc(cutoff * t) * window
+}
+
+/// resamples a [channels, samples] tensor, returning it on the same device
+pub fn resample_tensor(audio: &Tensor, from_rate: u32, to_rate: u32) -> Result<Tensor> {
+    if from_rate == to_rate {
+        return Ok(audio.shallow_clone());
     }
 
-    Ok((tensor, spec.sample_rate))
+    println!("resampling {} hz -> {} hz", from_rate, to_rate);
+
+    let device = audio.device();
+    let audio_cpu = audio.to_device(Device::Cpu).contiguous();
+    let n_channels = audio_cpu.size()[0];
+    let resampler = Resampler::new(from_rate, to_rate);
+
+    // channels are independent, resample them in parallel
+    let channels: Vec<Vec<f32>> = (0..n_channels)
+        .map(|c| Vec::<f32>::try_from(audio_cpu.select(0, c)))
+        .collect::<Result<_, _>>()?;
+
+    let resampled: Vec<Vec<f32>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = channels
+            .iter()
+            .map(|channel| scope.spawn(|| resampler.process(channel)))
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    });
+
+    let tensors: Vec<Tensor> = resampled.iter().map(|c| Tensor::from_slice(c)).collect();
+    Ok(Tensor::stack(&tensors, 0).to_device(device))
 }
 
-/// saves a pytorch tensor [channels, samples] to wav file
-pub fn save_tensor_to_wav(path: &str, tensor: &Tensor, sample_rate: u32) -> Result<()> {
-    let tensor_cpu = tensor.to_device(Device::Cpu);
-    let shape = tensor_cpu.size();
-    let n_channels = shape[0] as u16;
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-12 {
+        1.0
+    } else {
+        let px = std::f64::consts::PI * x;
+        px.sin() / px
+    }
+}
 
-    let spec = WavSpec {
-        channels: n_channels,
-        sample_rate,
-        bits_per_sample: 32,
-        sample_format: SampleFormat::Float,
-    };
+/// zeroth order modified bessel function of the first kind (power series)
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half_x = x / 2.0;
+    for k in 1..64 {
+        term *= half_x / k as f64;
+        let t2 = term * term;
+        sum += t2;
+        if t2 < sum * 1e-16 {
+            break;
+        }
+    }
+    sum
+}
 
-    let mut writer = WavWriter::create(path, spec)?;
+fn gcd(mut a: usize, mut b: usize) -> usize {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
 
-    // convert to interleaved format
-    let interleaved = tensor_cpu.transpose(0, 1).contiguous();
-    let data: Vec<f32> = interleaved.view(-1).try_into()?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::opus::OpusWriter;
+    use hound::{SampleFormat, WavSpec, WavWriter};
+
+    fn tone(n: usize, sample_rate: u32, level: f32) -> Vec<f32> {
+        (0..n)
+            .map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                (2.0 * std::f32::consts::PI * 440.0 * t).sin() * level
+            })
+            .collect()
+    }
 
-    for sample in data {
-        writer.write_sample(sample)?;
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
     }
 
-    writer.finalize()?;
-    Ok(())
+    #[test]
+    fn decodes_wav_exactly() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for channels in [1, 2] {
+            let path = dir.path().join(format!("{}.wav", channels));
+            let samples = tone(1000 * channels, 44100, 0.5);
+            let spec = WavSpec {
+                channels: channels as u16,
+                sample_rate: 44100,
+                bits_per_sample: 32,
+                sample_format: SampleFormat::Float,
+            };
+            let mut writer = WavWriter::create(&path, spec).unwrap();
+            for s in &samples {
+                writer.write_sample(*s).unwrap();
+            }
+            writer.finalize().unwrap();
+
+            let decoded = decode_audio_file(&path).unwrap();
+            assert_eq!(decoded.channels, channels);
+            assert_eq!(decoded.sample_rate, 44100);
+            assert_eq!(decoded.samples, samples);
+        }
+    }
+
+    #[test]
+    fn decodes_opus_to_its_exact_length() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for (sample_rate, n) in [(48000, 48000 + 123), (44100, 44100 + 77)] {
+            let path = dir.path().join(format!("{}.opus", sample_rate));
+            let channel = tone(n, sample_rate, 0.5);
+            let mut writer = OpusWriter::create(&path, sample_rate).unwrap();
+            writer.write(&[channel.clone(), channel]).unwrap();
+            writer.finish().unwrap();
+
+            let decoded = decode_audio_file(&path).unwrap();
+            assert_eq!(decoded.channels, 2);
+            assert_eq!(decoded.sample_rate, OPUS_RATE);
+
+            // pre-skip and padding are gone, what's left is the input at 48 khz
+            let expected = (n as u64 * OPUS_RATE as u64).div_ceil(sample_rate as u64) as usize;
+            assert_eq!(decoded.samples.len(), expected * 2, "{} hz", sample_rate);
+
+            // and it lines up with the input rather than the encoder's lookahead
+            let left = decoded
+                .samples
+                .iter()
+                .step_by(2)
+                .copied()
+                .collect::<Vec<_>>();
+            let reference = tone(expected, OPUS_RATE, 0.5);
+            let middle = expected / 2..expected / 2 + 4800;
+            let error = left[middle.clone()]
+                .iter()
+                .zip(&reference[middle])
+                .map(|(a, b)| a - b)
+                .collect::<Vec<_>>();
+            assert!(
+                rms(&error) < 0.05,
+                "{} hz: error {}",
+                sample_rate,
+                rms(&error)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_what_isnt_audio() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.opus");
+        std::fs::write(&path, b"not audio at all").unwrap();
+
+        assert!(AudioStream::open(&path).is_err());
+        assert!(AudioStream::open(&dir.path().join("missing.wav")).is_err());
+    }
+
+    #[test]
+    fn streaming_resampler_matches_the_whole_signal() {
+        let input = tone(10_000, 44100, 0.5);
+        let whole = Resampler::new(44100, 48000).process(&input);
+
+        let mut stream = StreamResampler::new(44100, 48000);
+        let mut streamed = Vec::new();
+        for block in input.chunks(777) {
+            streamed.extend(stream.push(block));
+        }
+        streamed.extend(stream.finish());
+
+        assert_eq!(streamed.len(), whole.len());
+        for (a, b) in streamed.iter().zip(&whole) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
 }
@@ -0,0 +1,4 @@
+// headless entry point for batch processing without the window
+fn main() -> std::process::ExitCode {
+    lala_lib::run_cli()
+}
@@ -0,0 +1,83 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// shared flag a running job polls between chunks. cloning shares the flag
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// bails out of the current job with `Cancelled` once cancel was requested
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(Cancelled.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// error a job returns when it stopped because of a cancel request
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "processing was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// tokens of the jobs the worker is running, by file id. managed as tauri state
+/// so `cancel_processing` can reach into the worker thread
+#[derive(Clone, Default)]
+pub struct ActiveJobs(Arc<Mutex<HashMap<String, CancellationToken>>>);
+
+impl ActiveJobs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// registers a fresh token for a job the worker is about to run
+    pub fn start(&self, file_id: &str) -> CancellationToken {
+        let token = CancellationToken::new();
+        self.jobs().insert(file_id.to_string(), token.clone());
+        token
+    }
+
+    pub fn finish(&self, file_id: &str) {
+        self.jobs().remove(file_id);
+    }
+
+    /// signals the running job for this file, returns false if none is running
+    pub fn cancel(&self, file_id: &str) -> bool {
+        match self.jobs().get(file_id) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // every update is a single insert or remove, a panicking holder can't
+    // leave the map half written
+    fn jobs(&self) -> MutexGuard<'_, HashMap<String, CancellationToken>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -0,0 +1,447 @@
+use crate::audio_io::SUPPORTED_AUDIO_EXTENSIONS;
+use crate::cancellation::ActiveJobs;
+use crate::commands::{export_asset, import_file, is_audio_file, queue_target_stage, remove_file};
+use crate::db::{get_all_files, get_assets_by_file, init_db, DbPool};
+use crate::encoding::OutputFormat;
+use crate::midi::SUPPORTED_MIDI_EXTENSIONS;
+use crate::model_cache::ModelCache;
+use crate::models::{Asset, AssetType, ProcessingStatus};
+use crate::progress::ProgressStage;
+use crate::residual::{find_residual, ResidualMethod};
+use crate::separator::WindowType;
+use crate::settings::{load_settings, save_settings, Settings};
+use crate::worker::{new_worker_id, process_next_job, ProcessingProgress, ProgressSink};
+use anyhow::{anyhow, bail, Result};
+use std::cell::RefCell;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+
+const USAGE: &str = "\
+usage: lala-cli [options] <file or directory>...
+
+runs audio or midi files through the pipeline up to a stage and copies the
+results to the output folder, one subfolder per input
+
+options:
+  -s, --stage <stage>   stems, midi, musicxml or pdf (default: stems)
+  -o, --output <dir>    where results are written (default: ./lala-output)
+      --separator <id>  separation backend, e.g. htdemucs_6s (default: auto)
+      --shifts <n>      extra time-shifted passes per track, slower but cleaner (default: 0)
+      --segment <sec>   separation chunk length, capped at the model's (default: model's)
+      --overlap <frac>  share of each chunk overlapping the next, 0 to 0.9 (default: 0.25)
+      --window <type>   chunk crossfade: cosine, linear or triangular (default: cosine)
+      --batch <n>       chunks per forward pass, up to 16, faster on a gpu (default: 1)
+      --device <dev>    auto, cpu or cuda:N (default: auto)
+      --threads <n>     cpu threads per op (default: one per core)
+      --interop-threads <n>
+                        ops run in parallel on the cpu (default: libtorch's)
+      --foreground      run at normal priority instead of in the background
+      --wiener <n>      post-filter stems with n wiener em iterations, up to 10,
+                        0 for plain ratio masks (default: off)
+      --residual <id>   also write instrumental (all but vocals) or accompaniment
+                        (all but piano), can be repeated
+      --residual-sum    build residuals from the other stems instead of subtracting
+                        from the mix
+  -f, --format <fmt>    stem files: wav_float, wav24, wav16, flac24, flac16 or opus
+                        (default: wav24)
+  -h, --help            print this message
+";
+
+struct Args {
+    inputs: Vec<PathBuf>,
+    stage: String,
+    output: PathBuf,
+    settings: Settings,
+}
+
+/// entry point of the headless binary, runs every input to the requested stage
+/// with the same worker the app uses
+pub fn run_cli() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(&args) {
+        Ok(0) => ExitCode::SUCCESS,
+        Ok(failed) => {
+            eprintln!("{} file(s) failed", failed);
+            ExitCode::FAILURE
+        }
+        Err(e) => {
+            eprintln!("error: {:?}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// returns None when help was requested
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Args>> {
+    let mut inputs = Vec::new();
+    let mut stage = "stems".to_string();
+    let mut output = PathBuf::from("lala-output");
+    let mut settings = Settings::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-s" | "--stage" => {
+                stage = args
+                    .next()
+                    .ok_or_else(|| anyhow!("{} needs a value", arg))?;
+            }
+            "-o" | "--output" => {
+                output = args
+                    .next()
+                    .map(PathBuf::from)
+                    .ok_or_else(|| anyhow!("{} needs a value", arg))?;
+            }
+            "--separator" => {
+                settings.separator = args
+                    .next()
+                    .ok_or_else(|| anyhow!("{} needs a value", arg))?;
+            }
+            "--shifts" => settings.shifts = parse_number(&arg, args.next())?,
+            "--segment" => settings.segment_seconds = Some(parse_number(&arg, args.next())?),
+            "--overlap" => settings.overlap = parse_number(&arg, args.next())?,
+            "--batch" => settings.batch_size = parse_number(&arg, args.next())?,
+            "--device" => {
+                settings.device = args
+                    .next()
+                    .ok_or_else(|| anyhow!("{} needs a value", arg))?;
+            }
+            "--threads" => settings.intra_op_threads = Some(parse_number(&arg, args.next())?),
+            "--interop-threads" => {
+                settings.inter_op_threads = Some(parse_number(&arg, args.next())?)
+            }
+            "--foreground" => settings.background_priority = false,
+            "--wiener" => settings.wiener_iterations = Some(parse_number(&arg, args.next())?),
+            "--residual" => {
+                let id = args
+                    .next()
+                    .ok_or_else(|| anyhow!("{} needs a value", arg))?;
+                find_residual(&id)?;
+                settings.residuals.push(id);
+            }
+            "--residual-sum" => settings.residual_method = ResidualMethod::Sum,
+            "-f" | "--format" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| anyhow!("{} needs a value", arg))?;
+                settings.export_format = OutputFormat::parse(&value)?;
+            }
+            "--window" => {
+                settings.window = match args.next().as_deref() {
+                    Some(value @ ("cosine" | "linear" | "triangular")) => {
+                        WindowType::from_string(value)
+                    }
+                    _ => bail!("{} needs cosine, linear or triangular", arg),
+                };
+            }
+            flag if flag.starts_with('-') => bail!("unknown option: {}", flag),
+            path => inputs.push(PathBuf::from(path)),
+        }
+    }
+
+    if !matches!(stage.as_str(), "stems" | "midi" | "musicxml" | "pdf") {
+        bail!("invalid stage: {}", stage);
+    }
+    if inputs.is_empty() {
+        bail!("no input files given");
+    }
+
+    Ok(Some(Args {
+        inputs,
+        stage,
+        output,
+        settings,
+    }))
+}
+
+fn parse_number<T: FromStr>(flag: &str, value: Option<String>) -> Result<T> {
+    value
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| anyhow!("{} needs a number", flag))
+}
+
+/// processes the inputs one by one, returns how many failed
+fn run(args: &Args) -> Result<usize> {
+    let files = collect_inputs(&args.inputs)?;
+    if files.is_empty() {
+        bail!("no supported audio or midi files found");
+    }
+
+    // the library lives next to the results and only holds the file in flight
+    let work_dir = args.output.join(".lala");
+    fs::create_dir_all(&work_dir)?;
+    let pool = init_db(&work_dir.join("lala.db"))?;
+    save_settings(&pool, &args.settings)?;
+
+    // a run that died midway leaves its copies behind
+    for file in get_all_files(&pool)? {
+        remove_file(&pool, &work_dir, &file.id)?;
+    }
+
+    let worker_id = new_worker_id();
+    let jobs = ActiveJobs::new();
+    let mut models = ModelCache::new();
+    let mut failed = 0;
+
+    for (idx, path) in files.iter().enumerate() {
+        println!("[{}/{}] {}", idx + 1, files.len(), path.display());
+
+        if let Err(e) = process_file(args, &pool, &work_dir, &worker_id, &mut models, &jobs, path) {
+            eprintln!("  failed: {}", e);
+            failed += 1;
+        }
+    }
+
+    println!(
+        "done, {} of {} file(s) written to {}",
+        files.len() - failed,
+        files.len(),
+        args.output.display()
+    );
+    Ok(failed)
+}
+
+fn process_file(
+    args: &Args,
+    pool: &DbPool,
+    work_dir: &Path,
+    worker_id: &str,
+    models: &mut ModelCache,
+    jobs: &ActiveJobs,
+    path: &Path,
+) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("invalid file name"))?;
+
+    let file_id = import_file(pool, work_dir, path, file_name)?;
+    let result = queue_target_stage(pool, work_dir, &file_id, &args.stage).and_then(|_| {
+        let printer = ConsolePrinter::default();
+        while process_next_job(&printer, pool, worker_id, models, jobs)? {}
+
+        export_results(pool, &file_id, &args.stage, &args.output, path)
+    });
+
+    remove_file(pool, work_dir, &file_id)?;
+    result
+}
+
+/// copies everything the pipeline produced for the file into its own folder
+fn export_results(
+    pool: &DbPool,
+    file_id: &str,
+    stage: &str,
+    output: &Path,
+    source: &Path,
+) -> Result<()> {
+    let assets = get_assets_by_file(pool, file_id)?;
+
+    if let Some(failed) = assets
+        .iter()
+        .find(|a| matches!(a.status, ProcessingStatus::Failed))
+    {
+        bail!(
+            "{} failed: {}",
+            failed.asset_type.to_string(),
+            failed.error_message.as_deref().unwrap_or("unknown error")
+        );
+    }
+
+    let reached = assets
+        .iter()
+        .any(|a| reaches_stage(a, stage) && matches!(a.status, ProcessingStatus::Completed));
+    if !reached {
+        bail!("pipeline stopped before reaching {}", stage);
+    }
+
+    let name = source
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("untitled");
+    let dest_dir = output.join(name);
+    fs::create_dir_all(&dest_dir)?;
+    let format = load_settings(pool)?.export_format;
+
+    // the uploaded file itself is the only asset without a parent
+    for asset in assets
+        .iter()
+        .filter(|a| a.parent_asset_id.is_some() && matches!(a.status, ProcessingStatus::Completed))
+    {
+        let src = Path::new(&asset.file_path);
+        let mut dest = dest_dir.join(src.file_name().unwrap_or_default());
+        if is_audio_file(src) {
+            dest.set_extension(format.extension());
+        }
+
+        let clipping = export_asset(src, &dest, format)?;
+        println!("  wrote {}", dest.display());
+        if let Some(clipping) = clipping.filter(|c| c.is_clipped()) {
+            println!("  warning: clipped, {}", clipping.describe());
+        }
+    }
+
+    Ok(())
+}
+
+fn reaches_stage(asset: &Asset, stage: &str) -> bool {
+    match stage {
+        "stems" => asset.asset_type.is_stem(),
+        "midi" => matches!(asset.asset_type, AssetType::Midi),
+        "musicxml" => matches!(asset.asset_type, AssetType::MusicXml),
+        "pdf" => matches!(asset.asset_type, AssetType::Pdf),
+        _ => false,
+    }
+}
+
+/// expands directories into the supported files directly inside them
+fn collect_inputs(inputs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+
+    for input in inputs {
+        if input.is_dir() {
+            let mut entries = fs::read_dir(input)?
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.is_file() && is_supported(p))
+                .collect::<Vec<_>>();
+            entries.sort();
+            files.extend(entries);
+        } else if input.is_file() {
+            files.push(input.clone());
+        } else {
+            bail!("no such file or directory: {}", input.display());
+        }
+    }
+
+    Ok(files)
+}
+
+fn is_supported(path: &Path) -> bool {
+    let Some(extension) = path.extension().and_then(|e| e.to_str()) else {
+        return false;
+    };
+    let dotted = format!(".{}", extension.to_lowercase());
+    SUPPORTED_AUDIO_EXTENSIONS.contains(&dotted.as_str())
+        || SUPPORTED_MIDI_EXTENSIONS.contains(&dotted.as_str())
+}
+
+/// prints a line per stage change and every 10%, readable in a log file
+#[derive(Default)]
+struct ConsolePrinter {
+    last: RefCell<Option<(String, Option<ProgressStage>, u32)>>,
+}
+
+impl ProgressSink for ConsolePrinter {
+    fn send(&self, progress: ProcessingProgress) {
+        let percent = (progress.progress * 100.0).round() as u32;
+        let key = (progress.asset_type.clone(), progress.stage, percent / 10);
+
+        // lifecycle events carry no stage and are always shown
+        let mut last = self.last.borrow_mut();
+        if progress.stage.is_some() && last.as_ref() == Some(&key) {
+            return;
+        }
+        *last = Some(key);
+
+        println!(
+            "  {} {:>3}% {}",
+            progress.asset_type, percent, progress.description
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Args>> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn error(args: &[&str]) -> String {
+        match parse(args) {
+            Ok(_) => panic!("{:?} parsed", args),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_flags() {
+        let args = parse(&[
+            "-s",
+            "midi",
+            "-o",
+            "out",
+            "--shifts",
+            "2",
+            "--window",
+            "linear",
+            "--residual",
+            "instrumental",
+            "--residual-sum",
+            "-f",
+            "flac16",
+            "--foreground",
+            "a.wav",
+            "songs",
+        ])
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(
+            args.inputs,
+            [PathBuf::from("a.wav"), PathBuf::from("songs")]
+        );
+        assert_eq!(args.stage, "midi");
+        assert_eq!(args.output, PathBuf::from("out"));
+        assert_eq!(args.settings.shifts, 2);
+        assert_eq!(args.settings.window, WindowType::Linear);
+        assert_eq!(args.settings.residuals, ["instrumental"]);
+        assert_eq!(args.settings.residual_method, ResidualMethod::Sum);
+        assert_eq!(args.settings.export_format, OutputFormat::Flac16);
+        assert!(!args.settings.background_priority);
+
+        let defaults = parse(&["a.wav"]).unwrap().unwrap();
+        assert_eq!(defaults.stage, "stems");
+        assert_eq!(defaults.output, PathBuf::from("lala-output"));
+
+        assert!(parse(&["a.wav", "--help"]).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_flags_and_values() {
+        assert_eq!(error(&["--bogus", "a.wav"]), "unknown option: --bogus");
+        assert_eq!(error(&["a.wav", "--shifts"]), "--shifts needs a number");
+        assert_eq!(
+            error(&["--shifts", "many", "a.wav"]),
+            "--shifts needs a number"
+        );
+        assert_eq!(error(&["a.wav", "-o"]), "-o needs a value");
+        assert_eq!(error(&["-s", "mp3", "a.wav"]), "invalid stage: mp3");
+        assert_eq!(error(&["--foreground"]), "no input files given");
+        assert!(error(&["--residual", "karaoke", "a.wav"]).contains("karaoke"));
+    }
+
+    #[test]
+    fn rejects_bad_formats() {
+        assert!(error(&["-f", "mp3", "a.wav"]).starts_with("invalid format: mp3"));
+        assert_eq!(error(&["a.wav", "--format"]), "--format needs a value");
+        assert_eq!(
+            error(&["--window", "hann", "a.wav"]),
+            "--window needs cosine, linear or triangular"
+        );
+    }
+}
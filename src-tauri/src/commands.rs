@@ -1,14 +1,26 @@
+use crate::audio_io::SUPPORTED_AUDIO_EXTENSIONS;
+use crate::cancellation::ActiveJobs;
 use crate::db::{
-    cancel_file_processing, create_asset, create_file, delete_file_and_assets, get_all_files,
-    get_assets_by_file, DbPool,
+    cancel_file_processing, create_file, delete_file_and_assets, delete_orphaned_assets,
+    get_all_files, get_assets_by_file, get_file_ids, DbPool, QueueStep,
 };
+use crate::encoding::{transcode, Clipping, OutputFormat};
+use crate::midi::{read_midi, SUPPORTED_MIDI_EXTENSIONS};
 use crate::models::{Asset, AssetType, FileRecord, ProcessingStatus};
-use anyhow::Result;
+use crate::musicxml::MUSICXML_EXTENSION;
+use crate::settings::load_settings;
+use crate::worker::find_piano_source;
+use anyhow::{anyhow, bail, Result};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tauri::command;
 use uuid::Uuid;
 
+/// a directory touched more recently than this may be an upload `import_file`
+/// is still copying, its record is written only once the copy is done
+const STRAY_GRACE_PERIOD: Duration = Duration::from_secs(10 * 60);
+
 #[command]
 pub async fn upload_file(
     pool: tauri::State<'_, DbPool>,
@@ -16,28 +28,64 @@ pub async fn upload_file(
     source_path: String,
     original_filename: String,
 ) -> Result<String, String> {
+    import_file(
+        &pool,
+        &app_data_dir,
+        Path::new(&source_path),
+        &original_filename,
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// copies an audio or midi file into the library and registers it, returns the file id
+pub fn import_file(
+    pool: &DbPool,
+    app_data_dir: &Path,
+    source_path: &Path,
+    original_filename: &str,
+) -> Result<String> {
+    // keep the real container so the decoder can sniff it later
+    let extension = Path::new(original_filename)
+        .extension()
+        .or_else(|| source_path.extension())
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .ok_or_else(|| anyhow!("file has no extension"))?;
+
+    let dotted = format!(".{}", extension);
+    let is_midi = SUPPORTED_MIDI_EXTENSIONS.contains(&dotted.as_str());
+    if !is_midi && !SUPPORTED_AUDIO_EXTENSIONS.contains(&dotted.as_str()) {
+        bail!("unsupported file type: {}", dotted);
+    }
+
+    // midi uploads enter the pipeline at the transcription output, make sure it parses
+    if is_midi {
+        read_midi(source_path)?;
+    }
+
     let file_id = Uuid::new_v4().to_string();
     let file_dir = app_data_dir.join("processing-files").join(&file_id);
 
-    fs::create_dir_all(&file_dir).map_err(|e| format!("failed to create directory: {:?}", e))?;
+    fs::create_dir_all(&file_dir).map_err(|e| anyhow!("failed to create directory: {:?}", e))?;
 
-    let dest_path = file_dir.join("original.wav");
-    fs::copy(&source_path, &dest_path).map_err(|e| format!("failed to copy file: {:?}", e))?;
+    let dest_path = file_dir.join(format!("original.{}", extension));
+    fs::copy(source_path, &dest_path).map_err(|e| anyhow!("failed to copy file: {:?}", e))?;
 
-    create_file(&pool, &file_id, &original_filename).map_err(|e| e.to_string())?;
-
-    // create original asset as completed (not queued - user must explicitly start processing)
+    // the original asset is created completed (not queued - user must explicitly start processing)
+    let asset_type = if is_midi {
+        AssetType::Midi
+    } else {
+        AssetType::Original
+    };
     let asset_id = Uuid::new_v4().to_string();
-    create_asset(
-        &pool,
-        &asset_id,
+    create_file(
+        pool,
         &file_id,
-        None,
-        AssetType::Original,
+        original_filename,
+        &asset_id,
+        asset_type,
         dest_path.to_str().unwrap(),
-        ProcessingStatus::Completed,
-    )
-    .map_err(|e| e.to_string())?;
+    )?;
 
     Ok(file_id)
 }
@@ -55,10 +103,44 @@ pub async fn list_assets(
     get_assets_by_file(&pool, &file_id).map_err(|e| e.to_string())
 }
 
+/// saves an asset to `destination`. audio is encoded in `format`, or the export
+/// format from settings when none is given, and the clipping it found is returned
 #[command]
-pub async fn download_asset(asset_path: String, destination: String) -> Result<(), String> {
-    fs::copy(&asset_path, &destination).map_err(|e| format!("failed to copy file: {:?}", e))?;
-    Ok(())
+pub async fn download_asset(
+    pool: tauri::State<'_, DbPool>,
+    asset_path: String,
+    destination: String,
+    format: Option<String>,
+) -> Result<Option<Clipping>, String> {
+    let format = match format {
+        Some(format) => OutputFormat::parse(&format),
+        None => load_settings(&pool).map(|settings| settings.export_format),
+    }
+    .map_err(|e| e.to_string())?;
+
+    export_asset(Path::new(&asset_path), Path::new(&destination), format).map_err(|e| e.to_string())
+}
+
+/// copies an asset out of the library, re-encoding audio in `format`. returns
+/// what clipped for audio, None for anything else
+pub fn export_asset(
+    source: &Path,
+    destination: &Path,
+    format: OutputFormat,
+) -> Result<Option<Clipping>> {
+    if is_audio_file(source) {
+        return transcode(source, destination, format).map(Some);
+    }
+
+    fs::copy(source, destination).map_err(|e| anyhow!("failed to copy file: {:?}", e))?;
+    Ok(None)
+}
+
+pub fn is_audio_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| format!(".{}", e.to_lowercase()))
+        .is_some_and(|dotted| SUPPORTED_AUDIO_EXTENSIONS.contains(&dotted.as_str()))
 }
 
 #[command]
@@ -67,12 +149,111 @@ pub async fn delete_file(
     app_data_dir: tauri::State<'_, PathBuf>,
     file_id: String,
 ) -> Result<(), String> {
-    delete_file_and_assets(&pool, &file_id).map_err(|e| e.to_string())?;
+    remove_file(&pool, &app_data_dir, &file_id).map_err(|e| e.to_string())
+}
 
-    let file_dir = app_data_dir.join("processing-files").join(&file_id);
+#[command]
+pub async fn repair_library(
+    pool: tauri::State<'_, DbPool>,
+    app_data_dir: tauri::State<'_, PathBuf>,
+) -> Result<usize, String> {
+    check_integrity(&pool, &app_data_dir, true).map_err(|e| e.to_string())
+}
+
+/// check for what an interrupted delete or an older version can leave behind:
+/// assets whose file or parent is gone, and directories under `processing-files/`
+/// no file points at. orphaned assets are unreachable and removed. stray
+/// directories can still hold user data (an upload whose record failed, a db
+/// restored from backup), so they're only reported unless `repair` is set, and
+/// then moved to `quarantine/` rather than deleted. directories changed within
+/// `STRAY_GRACE_PERIOD` are left alone, an import may still be filling them.
+/// returns how many were found, or moved when repairing
+pub fn check_integrity(pool: &DbPool, app_data_dir: &Path, repair: bool) -> Result<usize> {
+    let orphaned = delete_orphaned_assets(pool)?;
+    if orphaned > 0 {
+        println!("removed {} orphaned assets", orphaned);
+    }
+
+    let files_dir = app_data_dir.join("processing-files");
+    if !files_dir.exists() {
+        return Ok(0);
+    }
+
+    let file_ids = get_file_ids(pool)?;
+    let mut stray = Vec::new();
+    for entry in fs::read_dir(&files_dir)? {
+        let path = entry?.path();
+        let known = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| file_ids.iter().any(|id| id == name));
+
+        if path.is_dir() && !known && !recently_modified(&path) {
+            stray.push(path);
+        }
+    }
+
+    if stray.is_empty() {
+        return Ok(0);
+    }
+
+    if !repair {
+        eprintln!(
+            "{} directories under {} have no file record:",
+            stray.len(),
+            files_dir.display()
+        );
+        for path in &stray {
+            eprintln!("  {}", path.display());
+        }
+        return Ok(stray.len());
+    }
+
+    let quarantine_dir = app_data_dir.join("quarantine");
+    fs::create_dir_all(&quarantine_dir)?;
+    let mut moved = 0;
+    for path in &stray {
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+        let target = quarantine_dir.join(name);
+        println!(
+            "moving stray directory {} to {}",
+            path.display(),
+            target.display()
+        );
+        match fs::rename(path, &target) {
+            Ok(()) => moved += 1,
+            Err(e) => eprintln!("failed to move {}: {}", path.display(), e),
+        }
+    }
+
+    Ok(moved)
+}
+
+/// whether the directory or anything directly in it changed within the grace period
+fn recently_modified(dir: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let entries = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| modified(&entry.path()));
+
+    modified(dir)
+        .into_iter()
+        .chain(entries)
+        .any(|time| time.elapsed().map_or(true, |age| age < STRAY_GRACE_PERIOD))
+}
+
+/// deletes a file's records and everything on disk that belongs to it
+pub fn remove_file(pool: &DbPool, app_data_dir: &Path, file_id: &str) -> Result<()> {
+    delete_file_and_assets(pool, file_id)?;
+
+    let file_dir = app_data_dir.join("processing-files").join(file_id);
     if file_dir.exists() {
         fs::remove_dir_all(&file_dir)
-            .map_err(|e| format!("failed to delete directory: {:?}", e))?;
+            .map_err(|e| anyhow!("failed to delete directory: {:?}", e))?;
     }
 
     Ok(())
@@ -85,7 +266,18 @@ pub async fn process_to_stage(
     file_id: String,
     target_stage: String,
 ) -> Result<(), String> {
-    let assets = get_assets_by_file(&pool, &file_id).map_err(|e| e.to_string())?;
+    queue_target_stage(&pool, &app_data_dir, &file_id, &target_stage).map_err(|e| e.to_string())
+}
+
+/// sets the file's target stage and queues the first job towards it, the worker
+/// queues the rest as each stage completes
+pub fn queue_target_stage(
+    pool: &DbPool,
+    app_data_dir: &Path,
+    file_id: &str,
+    target_stage: &str,
+) -> Result<()> {
+    let assets = get_assets_by_file(pool, file_id)?;
 
     // check if anything is already processing
     let has_processing = assets
@@ -93,46 +285,50 @@ pub async fn process_to_stage(
         .any(|a| matches!(a.status, ProcessingStatus::Processing));
 
     if has_processing {
-        return Err("file already has processing in progress".to_string());
+        bail!("file already has processing in progress");
     }
 
     // validate target stage
-    if !matches!(target_stage.as_str(), "stems" | "midi" | "pdf") {
-        return Err("invalid target stage".to_string());
+    if !matches!(target_stage, "stems" | "midi" | "musicxml" | "pdf") {
+        bail!("invalid target stage");
     }
 
-    // set the target stage on the file
-    crate::db::set_target_stage(&pool, &file_id, Some(&target_stage)).map_err(|e| e.to_string())?;
-
     // determine what needs to happen first
     let has_original = assets.iter().any(|a| {
         matches!(a.asset_type, AssetType::Original)
             && matches!(a.status, ProcessingStatus::Completed)
     });
 
-    let has_stems = assets.iter().any(|a| {
-        matches!(a.asset_type, AssetType::StemPiano)
-            && matches!(a.status, ProcessingStatus::Completed)
-    });
+    let has_stems = assets
+        .iter()
+        .any(|a| a.asset_type.is_stem() && matches!(a.status, ProcessingStatus::Completed));
 
     let has_midi = assets.iter().any(|a| {
         matches!(a.asset_type, AssetType::Midi) && matches!(a.status, ProcessingStatus::Completed)
     });
 
-    if !has_original {
-        return Err("no original file found".to_string());
+    let has_musicxml = assets.iter().any(|a| {
+        matches!(a.asset_type, AssetType::MusicXml)
+            && matches!(a.status, ProcessingStatus::Completed)
+    });
+
+    if !has_original && !has_midi {
+        bail!("no original file found");
+    }
+
+    if !has_original && target_stage == "stems" {
+        bail!("midi uploads have no audio to separate");
     }
 
     // queue the first step that needs to happen
-    if !has_stems {
+    let step = if !has_stems && !has_midi {
         // need to separate stems first
         let original = assets
             .iter()
             .find(|a| matches!(a.asset_type, AssetType::Original))
-            .ok_or("original asset not found")?;
+            .ok_or_else(|| anyhow!("original asset not found"))?;
 
-        crate::db::update_asset_status(&pool, &original.id, ProcessingStatus::Queued, None)
-            .map_err(|e| e.to_string())?;
+        QueueStep::Requeue(original.id.clone())
     } else if target_stage != "stems" && !has_midi {
         // stems exist, but need midi
         let existing_midi = assets
@@ -145,33 +341,60 @@ pub async fn process_to_stage(
                 midi.status,
                 ProcessingStatus::Failed | ProcessingStatus::Cancelled
             ) {
-                crate::db::update_asset_status(&pool, &midi.id, ProcessingStatus::Queued, None)
-                    .map_err(|e| e.to_string())?;
+                QueueStep::Requeue(midi.id.clone())
+            } else {
+                QueueStep::Nothing
             }
         } else {
             // create and queue midi asset
             let midi_id = Uuid::new_v4().to_string();
-            let file_dir = app_data_dir.join("processing-files").join(&file_id);
+            let file_dir = app_data_dir.join("processing-files").join(file_id);
             let midi_path = file_dir.join("stem_piano.midi");
 
-            let piano_stem = assets
+            let piano_stem =
+                find_piano_source(&assets).ok_or_else(|| anyhow!("piano stem not found"))?;
+
+            QueueStep::Create {
+                id: midi_id,
+                parent_asset_id: piano_stem.id.clone(),
+                asset_type: AssetType::Midi,
+                file_path: midi_path.to_str().unwrap().to_string(),
+            }
+        }
+    } else if matches!(target_stage, "musicxml" | "pdf") && has_midi && !has_musicxml {
+        // midi exists, export musicxml (on its own or on the way to pdf)
+        let existing_musicxml = assets
+            .iter()
+            .find(|a| matches!(a.asset_type, AssetType::MusicXml));
+
+        if let Some(musicxml) = existing_musicxml {
+            if matches!(
+                musicxml.status,
+                ProcessingStatus::Failed | ProcessingStatus::Cancelled
+            ) {
+                QueueStep::Requeue(musicxml.id.clone())
+            } else {
+                QueueStep::Nothing
+            }
+        } else {
+            let musicxml_id = Uuid::new_v4().to_string();
+            let file_dir = app_data_dir.join("processing-files").join(file_id);
+            let musicxml_path = file_dir.join(format!("stem_piano.{}", MUSICXML_EXTENSION));
+
+            let midi_asset = assets
                 .iter()
-                .find(|a| matches!(a.asset_type, AssetType::StemPiano))
-                .ok_or("piano stem not found")?;
-
-            create_asset(
-                &pool,
-                &midi_id,
-                &file_id,
-                Some(&piano_stem.id),
-                AssetType::Midi,
-                midi_path.to_str().unwrap(),
-                ProcessingStatus::Queued,
-            )
-            .map_err(|e| e.to_string())?;
+                .find(|a| matches!(a.asset_type, AssetType::Midi))
+                .ok_or_else(|| anyhow!("midi asset not found"))?;
+
+            QueueStep::Create {
+                id: musicxml_id,
+                parent_asset_id: midi_asset.id.clone(),
+                asset_type: AssetType::MusicXml,
+                file_path: musicxml_path.to_str().unwrap().to_string(),
+            }
         }
-    } else if target_stage == "pdf" && has_midi {
-        // midi exists, queue pdf
+    } else if target_stage == "pdf" && has_musicxml {
+        // musicxml exists, queue pdf
         let existing_pdf = assets
             .iter()
             .find(|a| matches!(a.asset_type, AssetType::Pdf));
@@ -181,43 +404,50 @@ pub async fn process_to_stage(
                 pdf.status,
                 ProcessingStatus::Failed | ProcessingStatus::Cancelled
             ) {
-                crate::db::update_asset_status(&pool, &pdf.id, ProcessingStatus::Queued, None)
-                    .map_err(|e| e.to_string())?;
+                QueueStep::Requeue(pdf.id.clone())
+            } else {
+                QueueStep::Nothing
             }
         } else {
             let pdf_id = Uuid::new_v4().to_string();
-            let file_dir = app_data_dir.join("processing-files").join(&file_id);
+            let file_dir = app_data_dir.join("processing-files").join(file_id);
             let pdf_path = file_dir.join("stem_piano.pdf");
 
-            let midi_asset = assets
+            let musicxml_asset = assets
                 .iter()
-                .find(|a| matches!(a.asset_type, AssetType::Midi))
-                .ok_or("midi asset not found")?;
-
-            create_asset(
-                &pool,
-                &pdf_id,
-                &file_id,
-                Some(&midi_asset.id),
-                AssetType::Pdf,
-                pdf_path.to_str().unwrap(),
-                ProcessingStatus::Queued,
-            )
-            .map_err(|e| e.to_string())?;
+                .find(|a| matches!(a.asset_type, AssetType::MusicXml))
+                .ok_or_else(|| anyhow!("musicxml asset not found"))?;
+
+            QueueStep::Create {
+                id: pdf_id,
+                parent_asset_id: musicxml_asset.id.clone(),
+                asset_type: AssetType::Pdf,
+                file_path: pdf_path.to_str().unwrap().to_string(),
+            }
         }
-    }
+    } else {
+        QueueStep::Nothing
+    };
 
-    Ok(())
+    crate::db::queue_stage(pool, file_id, target_stage, &step)
 }
 
 #[command]
 pub async fn cancel_processing(
     pool: tauri::State<'_, DbPool>,
+    jobs: tauri::State<'_, ActiveJobs>,
     file_id: String,
 ) -> Result<(), String> {
     // clear target stage
     crate::db::set_target_stage(&pool, &file_id, None).map_err(|e| e.to_string())?;
 
     // cancel any queued/processing assets
-    cancel_file_processing(&pool, &file_id).map_err(|e| e.to_string())
+    cancel_file_processing(&pool, &file_id).map_err(|e| e.to_string())?;
+
+    // stop the running job, the worker cleans up and confirms the cancelled status
+    if jobs.cancel(&file_id) {
+        println!("signalled running job for file {} to stop", file_id);
+    }
+
+    Ok(())
 }
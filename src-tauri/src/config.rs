@@ -1,9 +1,11 @@
+use crate::audio_io::SUPPORTED_AUDIO_EXTENSIONS;
+use crate::midi::SUPPORTED_MIDI_EXTENSIONS;
 use serde::Serialize;
 
 #[derive(Serialize, Clone)]
 pub struct FileUploadConfig {
     pub max_file_size_mb: u32,
-    pub permitted_file_extensions: [&'static str; 3],
+    pub permitted_file_extensions: Vec<&'static str>,
     pub max_upload_time_sec: u16,
 }
 
@@ -17,7 +19,8 @@ pub fn get_app_config() -> AppConfig {
     AppConfig {
         file_upload: FileUploadConfig {
             max_file_size_mb: 500,
-            permitted_file_extensions: [".wav", ".mp3", ".flac"], // support more formats
+            permitted_file_extensions: [SUPPORTED_AUDIO_EXTENSIONS, SUPPORTED_MIDI_EXTENSIONS]
+                .concat(),
             max_upload_time_sec: 300,
         },
     }
@@ -1,58 +1,154 @@
+use crate::encoding::Clipping;
+use crate::migrations::migrate;
 use crate::models::*;
-use anyhow::{Context, Result};
-use rusqlite::{params, Connection};
+use anyhow::{anyhow, bail, Context, Result};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Row};
+use std::ops::Deref;
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
 
-pub type DbPool = Arc<Mutex<Connection>>;
+pub type DbPool = Arc<Database>;
 
-pub fn init_db(db_path: &Path) -> Result<DbPool> {
-    let conn = Connection::open(db_path).context("failed to open database")?;
+/// read-only connections kept open next to the writer
+const READER_COUNT: usize = 4;
 
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS files (
-            id TEXT PRIMARY KEY,
-            original_filename TEXT NOT NULL,
-            target_stage TEXT,
-            created_at INTEGER NOT NULL
-        )",
-        [],
-    )?;
+/// how long a statement waits for a lock held by another connection before failing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
 
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS assets (
-            id TEXT PRIMARY KEY,
-            file_id TEXT NOT NULL,
-            parent_asset_id TEXT,
-            asset_type TEXT NOT NULL,
-            file_path TEXT NOT NULL,
-            status TEXT NOT NULL,
-            error_message TEXT,
-            created_at INTEGER NOT NULL,
-            FOREIGN KEY(file_id) REFERENCES files(id),
-            FOREIGN KEY(parent_asset_id) REFERENCES assets(id)
-        )",
-        [],
-    )?;
+/// sqlite connections shared between the worker and the ui. sqlite takes one
+/// writer at a time, so writes go through a single connection; reads check out
+/// one of several read-only connections and, in wal mode, run alongside a write
+/// instead of queueing behind it
+pub struct Database {
+    writer: Mutex<Connection>,
+    readers: Mutex<Vec<Connection>>,
+    reader_returned: Condvar,
+}
 
-    // index for faster queries
-    conn.execute(
-        "CREATE INDEX IF NOT EXISTS idx_assets_status ON assets(status)",
-        [],
-    )?;
+impl Database {
+    /// the writer connection, for anything that modifies the database
+    pub fn write(&self) -> Result<MutexGuard<'_, Connection>> {
+        self.writer.lock().map_err(|_| {
+            // the panicking holder's transaction rolled back as it unwound, so the
+            // connection itself is fine. fail this call and let the next one through
+            self.writer.clear_poison();
+            anyhow!("a database write panicked, try again")
+        })
+    }
+
+    /// a read-only connection, waits while all of them are in use
+    pub fn read(&self) -> Result<ReadConnection<'_>> {
+        // the pool is only a vec of idle connections, a panic can't leave it half
+        // updated, so a poisoned lock is still safe to use
+        let mut readers = self.readers.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            if let Some(conn) = readers.pop() {
+                return Ok(ReadConnection {
+                    database: self,
+                    conn: Some(conn),
+                });
+            }
+            readers = self
+                .reader_returned
+                .wait(readers)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// a checked out reader, goes back to the pool when dropped
+pub struct ReadConnection<'a> {
+    database: &'a Database,
+    /// only None while being returned
+    conn: Option<Connection>,
+}
+
+impl Deref for ReadConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().expect("reader used after return")
+    }
+}
 
-    Ok(Arc::new(Mutex::new(conn)))
+impl Drop for ReadConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            let mut readers = self
+                .database
+                .readers
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            readers.push(conn);
+            self.database.reader_returned.notify_one();
+        }
+    }
+}
+
+pub fn init_db(db_path: &Path) -> Result<DbPool> {
+    let mut writer = Connection::open(db_path).context("failed to open database")?;
+    writer.busy_timeout(BUSY_TIMEOUT)?;
+    // wal lets readers go on while a write is in progress. it's stored in the file,
+    // so this only converts the database once
+    writer.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+    // wal stays consistent without a sync per commit, the last commits may be
+    // lost on power failure but never corrupted
+    writer.pragma_update(None, "synchronous", "NORMAL")?;
+    migrate(&mut writer)?;
+    // migrate turns them off for the table rebuilds
+    writer.pragma_update(None, "foreign_keys", true)?;
+
+    // opened after migrating, so they see the current schema
+    let readers = (0..READER_COUNT)
+        .map(|_| {
+            let conn = Connection::open_with_flags(
+                db_path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )
+            .context("failed to open database")?;
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            Ok(conn)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Arc::new(Database {
+        writer: Mutex::new(writer),
+        readers: Mutex::new(readers),
+        reader_returned: Condvar::new(),
+    }))
 }
 
-pub fn create_file(pool: &DbPool, id: &str, original_filename: &str) -> Result<()> {
-    let conn = pool.lock().unwrap();
+/// registers a file along with the completed asset it was uploaded as, together
+/// so there's never a file without its source
+pub fn create_file(
+    pool: &DbPool,
+    id: &str,
+    original_filename: &str,
+    asset_id: &str,
+    asset_type: AssetType,
+    file_path: &str,
+) -> Result<()> {
+    let mut conn = pool.write()?;
+    let tx = conn.transaction()?;
     let now = chrono::Utc::now().timestamp();
 
-    conn.execute(
+    tx.execute(
         "INSERT INTO files (id, original_filename, target_stage, created_at) VALUES (?1, ?2, NULL, ?3)",
         params![id, original_filename, now],
     )?;
+    insert_asset(
+        &tx,
+        asset_id,
+        id,
+        None,
+        asset_type,
+        file_path,
+        ProcessingStatus::Completed,
+        None,
+    )?;
 
+    tx.commit()?;
     Ok(())
 }
 
@@ -65,12 +161,100 @@ pub fn create_asset(
     file_path: &str,
     status: ProcessingStatus,
 ) -> Result<()> {
-    let conn = pool.lock().unwrap();
+    let conn = pool.write()?;
+    insert_asset(
+        &conn,
+        id,
+        file_id,
+        parent_asset_id,
+        asset_type,
+        file_path,
+        status,
+        None,
+    )
+}
+
+/// a separated stem for `create_stem_assets`
+pub struct NewStem {
+    pub id: String,
+    pub asset_type: AssetType,
+    pub file_path: String,
+    pub clipping: Clipping,
+}
+
+/// registers the stems of a separation as completed assets, all or none of them.
+/// nothing is written unless `worker_id` still holds the separation
+pub fn create_stem_assets(
+    pool: &DbPool,
+    file_id: &str,
+    parent_asset_id: &str,
+    worker_id: &str,
+    stems: &[NewStem],
+) -> Result<LeaseState> {
+    let mut conn = pool.write()?;
+    let tx = conn.transaction()?;
+
+    let status: Option<String> = tx
+        .query_row(
+            "SELECT status FROM assets WHERE id = ?1 AND worker_id = ?2",
+            params![parent_asset_id, worker_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    match status.as_deref() {
+        Some("processing") => {}
+        Some(_) => return Ok(LeaseState::Cancelled),
+        None => return Ok(LeaseState::Lost),
+    }
+
+    for stem in stems {
+        insert_asset(
+            &tx,
+            &stem.id,
+            file_id,
+            Some(parent_asset_id),
+            stem.asset_type.clone(),
+            &stem.file_path,
+            ProcessingStatus::Completed,
+            Some(&stem.clipping),
+        )?;
+    }
+
+    tx.commit()?;
+    Ok(LeaseState::Held)
+}
+
+/// deletes the assets produced from `parent_asset_id`, and theirs by cascade.
+/// returns the file paths of the deleted children
+pub fn delete_child_assets(pool: &DbPool, parent_asset_id: &str) -> Result<Vec<String>> {
+    let conn = pool.write()?;
+
+    let mut stmt =
+        conn.prepare("DELETE FROM assets WHERE parent_asset_id = ?1 RETURNING file_path")?;
+    let paths = stmt
+        .query_map([parent_asset_id], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    Ok(paths)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn insert_asset(
+    conn: &Connection,
+    id: &str,
+    file_id: &str,
+    parent_asset_id: Option<&str>,
+    asset_type: AssetType,
+    file_path: &str,
+    status: ProcessingStatus,
+    clipping: Option<&Clipping>,
+) -> Result<()> {
     let now = chrono::Utc::now().timestamp();
 
     conn.execute(
-        "INSERT INTO assets (id, file_id, parent_asset_id, asset_type, file_path, status, error_message, created_at)
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, NULL, ?7)",
+        "INSERT INTO assets (id, file_id, parent_asset_id, asset_type, file_path, status, error_message, created_at,
+                             clipped_samples, total_samples, peak)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, NULL, ?7, ?8, ?9, ?10)",
         params![
             id,
             file_id,
@@ -78,20 +262,51 @@ pub fn create_asset(
             asset_type.to_string(),
             file_path,
             status.to_string(),
-            now
+            now,
+            clipping.map(|c| c.clipped_samples as i64),
+            clipping.map(|c| c.total_samples as i64),
+            clipping.map(|c| c.peak as f64),
         ],
     )?;
 
     Ok(())
 }
 
+/// an asset as selected with the columns in table order
+fn asset_from_row(row: &Row) -> rusqlite::Result<Asset> {
+    let clipping = match (
+        row.get::<_, Option<i64>>(8)?,
+        row.get::<_, Option<i64>>(9)?,
+        row.get::<_, Option<f64>>(10)?,
+    ) {
+        (Some(clipped_samples), Some(total_samples), Some(peak)) => Some(Clipping {
+            clipped_samples: clipped_samples as u64,
+            total_samples: total_samples as u64,
+            peak: peak as f32,
+        }),
+        _ => None,
+    };
+
+    Ok(Asset {
+        id: row.get(0)?,
+        file_id: row.get(1)?,
+        parent_asset_id: row.get(2)?,
+        asset_type: AssetType::from_string(&row.get::<_, String>(3)?),
+        file_path: row.get(4)?,
+        status: ProcessingStatus::from_string(&row.get::<_, String>(5)?),
+        error_message: row.get(6)?,
+        created_at: row.get(7)?,
+        clipping,
+    })
+}
+
 pub fn update_asset_status(
     pool: &DbPool,
     asset_id: &str,
     status: ProcessingStatus,
     error_message: Option<&str>,
 ) -> Result<()> {
-    let conn = pool.lock().unwrap();
+    let conn = pool.write()?;
 
     conn.execute(
         "UPDATE assets SET status = ?1, error_message = ?2 WHERE id = ?3",
@@ -101,60 +316,119 @@ pub fn update_asset_status(
     Ok(())
 }
 
-pub fn get_next_queued_asset(pool: &DbPool) -> Result<Option<Asset>> {
-    let conn = pool.lock().unwrap();
+/// atomically takes the oldest queued asset for `worker_id` and marks it processing
+/// under a lease that runs out after `lease`. a processing asset whose lease ran
+/// out, or that predates leases, belonged to a worker that died and is taken over
+/// the same way. None if there's nothing to do
+pub fn claim_next_asset(pool: &DbPool, worker_id: &str, lease: Duration) -> Result<Option<Asset>> {
+    let conn = pool.write()?;
+    let now = chrono::Utc::now().timestamp();
 
+    // one statement, so two workers can never claim the same asset
     let mut stmt = conn.prepare(
-        "SELECT id, file_id, parent_asset_id, asset_type, file_path, status, error_message, created_at
-         FROM assets WHERE status = 'queued' ORDER BY created_at ASC LIMIT 1",
+        "UPDATE assets SET status = 'processing', worker_id = ?1, lease_expires_at = ?2
+         WHERE id = (
+             SELECT id FROM assets
+             WHERE status = 'queued'
+                OR (status = 'processing' AND (lease_expires_at IS NULL OR lease_expires_at < ?3))
+             ORDER BY created_at ASC LIMIT 1
+         )
+         RETURNING id, file_id, parent_asset_id, asset_type, file_path, status, error_message, created_at,
+                   clipped_samples, total_samples, peak",
     )?;
 
-    let mut rows = stmt.query([])?;
+    let mut rows = stmt.query(params![worker_id, now + lease.as_secs() as i64, now])?;
 
     if let Some(row) = rows.next()? {
-        Ok(Some(Asset {
-            id: row.get(0)?,
-            file_id: row.get(1)?,
-            parent_asset_id: row.get(2)?,
-            asset_type: AssetType::from_string(&row.get::<_, String>(3)?),
-            file_path: row.get(4)?,
-            status: ProcessingStatus::from_string(&row.get::<_, String>(5)?),
-            error_message: row.get(6)?,
-            created_at: row.get(7)?,
-        }))
+        Ok(Some(asset_from_row(row)?))
     } else {
         Ok(None)
     }
 }
 
+/// extends the lease on an asset `worker_id` is processing. false if the lease is
+/// gone, because the job was cancelled or another worker took it over
+pub fn renew_lease(
+    pool: &DbPool,
+    asset_id: &str,
+    worker_id: &str,
+    lease: Duration,
+) -> Result<bool> {
+    let conn = pool.write()?;
+    let expires_at = chrono::Utc::now().timestamp() + lease.as_secs() as i64;
+
+    let count = conn.execute(
+        "UPDATE assets SET lease_expires_at = ?1
+         WHERE id = ?2 AND worker_id = ?3 AND status = 'processing'",
+        params![expires_at, asset_id, worker_id],
+    )?;
+
+    Ok(count > 0)
+}
+
+/// whether a worker still held a claimed asset when it went to write a result
+#[derive(Debug, PartialEq)]
+pub enum LeaseState {
+    /// still held, the write went through
+    Held,
+    /// cancelled while the worker ran, it should clean up after itself
+    Cancelled,
+    /// taken over by another worker, or deleted. whatever is on disk under the
+    /// asset's paths belongs to the new owner, if any
+    Lost,
+}
+
+/// records how a claimed job ended and drops the lease, if `worker_id` still
+/// holds the asset and it's still processing. a cancelled asset only loses the lease
+pub fn release_asset(
+    pool: &DbPool,
+    asset_id: &str,
+    worker_id: &str,
+    status: ProcessingStatus,
+    error_message: Option<&str>,
+) -> Result<LeaseState> {
+    let conn = pool.write()?;
+
+    let count = conn.execute(
+        "UPDATE assets SET status = ?1, error_message = ?2, worker_id = NULL, lease_expires_at = NULL
+         WHERE id = ?3 AND worker_id = ?4 AND status = 'processing'",
+        params![status.to_string(), error_message, asset_id, worker_id],
+    )?;
+    if count > 0 {
+        return Ok(LeaseState::Held);
+    }
+
+    // cancel_file_processing keeps the worker id, so a cancel is told apart from a takeover
+    let count = conn.execute(
+        "UPDATE assets SET worker_id = NULL, lease_expires_at = NULL
+         WHERE id = ?1 AND worker_id = ?2 AND status = 'cancelled'",
+        params![asset_id, worker_id],
+    )?;
+    if count > 0 {
+        return Ok(LeaseState::Cancelled);
+    }
+
+    Ok(LeaseState::Lost)
+}
+
 pub fn get_assets_by_file(pool: &DbPool, file_id: &str) -> Result<Vec<Asset>> {
-    let conn = pool.lock().unwrap();
+    let conn = pool.read()?;
 
     let mut stmt = conn.prepare(
-        "SELECT id, file_id, parent_asset_id, asset_type, file_path, status, error_message, created_at
+        "SELECT id, file_id, parent_asset_id, asset_type, file_path, status, error_message, created_at,
+                clipped_samples, total_samples, peak
          FROM assets WHERE file_id = ?1 ORDER BY created_at ASC",
     )?;
 
     let assets = stmt
-        .query_map([file_id], |row| {
-            Ok(Asset {
-                id: row.get(0)?,
-                file_id: row.get(1)?,
-                parent_asset_id: row.get(2)?,
-                asset_type: AssetType::from_string(&row.get::<_, String>(3)?),
-                file_path: row.get(4)?,
-                status: ProcessingStatus::from_string(&row.get::<_, String>(5)?),
-                error_message: row.get(6)?,
-                created_at: row.get(7)?,
-            })
-        })?
+        .query_map([file_id], asset_from_row)?
         .collect::<Result<Vec<_>, _>>()?;
 
     Ok(assets)
 }
 
 // pub fn get_asset_by_id(pool: &DbPool, asset_id: &str) -> Result<Option<Asset>> {
-//     let conn = pool.lock().unwrap();
+//     let conn = pool.read()?;
 //
 //     let mut stmt = conn.prepare(
 //         "SELECT id, file_id, parent_asset_id, asset_type, file_path, status, error_message, created_at
@@ -180,7 +454,7 @@ pub fn get_assets_by_file(pool: &DbPool, file_id: &str) -> Result<Vec<Asset>> {
 // }
 
 pub fn get_all_files(pool: &DbPool) -> Result<Vec<FileRecord>> {
-    let conn = pool.lock().unwrap();
+    let conn = pool.read()?;
 
     let mut stmt = conn
         .prepare("SELECT id, original_filename, target_stage, created_at FROM files ORDER BY created_at DESC")?;
@@ -199,47 +473,64 @@ pub fn get_all_files(pool: &DbPool) -> Result<Vec<FileRecord>> {
     Ok(files)
 }
 
-// startup recovery: reset any processing jobs to queued
-pub fn reset_interrupted_jobs(pool: &DbPool) -> Result<usize> {
-    let conn = pool.lock().unwrap();
-
-    let count = conn.execute(
-        "UPDATE assets SET status = 'queued' WHERE status = 'processing'",
-        [],
-    )?;
-
-    Ok(count)
-}
-
 pub fn delete_file_and_assets(pool: &DbPool, file_id: &str) -> Result<()> {
-    let conn = pool.lock().unwrap();
+    let conn = pool.write()?;
 
-    conn.execute("DELETE FROM assets WHERE file_id = ?1", [file_id])?;
+    // the assets go with it, they cascade
     conn.execute("DELETE FROM files WHERE id = ?1", [file_id])?;
 
     Ok(())
 }
 
 pub fn cancel_file_processing(pool: &DbPool, file_id: &str) -> Result<()> {
-    let conn = pool.lock().unwrap();
+    let mut conn = pool.write()?;
+    let tx = conn.transaction()?;
 
     // delete queued assets
-    conn.execute(
+    tx.execute(
         "DELETE FROM assets WHERE file_id = ?1 AND status = 'queued'",
         [file_id],
     )?;
 
     // mark processing assets as cancelled
-    conn.execute(
+    tx.execute(
         "UPDATE assets SET status = 'cancelled' WHERE file_id = ?1 AND status = 'processing'",
         [file_id],
     )?;
 
+    tx.commit()?;
     Ok(())
 }
 
+/// deletes assets whose file or parent is gone, left by databases from before
+/// foreign keys were enforced. their descendants cascade. returns how many went
+pub fn delete_orphaned_assets(pool: &DbPool) -> Result<usize> {
+    let conn = pool.write()?;
+
+    let count = conn.execute(
+        "DELETE FROM assets
+         WHERE file_id NOT IN (SELECT id FROM files)
+            OR (parent_asset_id IS NOT NULL AND parent_asset_id NOT IN (SELECT id FROM assets))",
+        [],
+    )?;
+
+    Ok(count)
+}
+
+/// ids of every file, including ones without assets
+pub fn get_file_ids(pool: &DbPool) -> Result<Vec<String>> {
+    let conn = pool.read()?;
+
+    let mut stmt = conn.prepare("SELECT id FROM files")?;
+    let ids = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    Ok(ids)
+}
+
 pub fn set_target_stage(pool: &DbPool, file_id: &str, target_stage: Option<&str>) -> Result<()> {
-    let conn = pool.lock().unwrap();
+    let conn = pool.write()?;
 
     conn.execute(
         "UPDATE files SET target_stage = ?1 WHERE id = ?2",
@@ -249,8 +540,80 @@ pub fn set_target_stage(pool: &DbPool, file_id: &str, target_stage: Option<&str>
     Ok(())
 }
 
+/// the job `queue_stage` starts a file's processing with
+pub enum QueueStep {
+    /// re-queue an asset that's already there
+    Requeue(String),
+    /// a new queued asset
+    Create {
+        id: String,
+        parent_asset_id: String,
+        asset_type: AssetType,
+        file_path: String,
+    },
+    /// the next job is already queued
+    Nothing,
+}
+
+/// sets the file's target stage and queues its next job in one transaction, so a
+/// failure can't leave a target behind without the job working toward it. fails
+/// if a worker took one of the file's assets since the caller looked
+pub fn queue_stage(
+    pool: &DbPool,
+    file_id: &str,
+    target_stage: &str,
+    step: &QueueStep,
+) -> Result<()> {
+    let mut conn = pool.write()?;
+    let tx = conn.transaction()?;
+
+    let processing: i64 = tx.query_row(
+        "SELECT COUNT(*) FROM assets WHERE file_id = ?1 AND status = ?2",
+        params![file_id, ProcessingStatus::Processing.to_string()],
+        |row| row.get(0),
+    )?;
+    if processing > 0 {
+        bail!("file already has processing in progress");
+    }
+
+    tx.execute(
+        "UPDATE files SET target_stage = ?1 WHERE id = ?2",
+        params![target_stage, file_id],
+    )?;
+
+    match step {
+        QueueStep::Requeue(asset_id) => {
+            tx.execute(
+                "UPDATE assets SET status = ?1, error_message = NULL WHERE id = ?2",
+                params![ProcessingStatus::Queued.to_string(), asset_id],
+            )?;
+        }
+        QueueStep::Create {
+            id,
+            parent_asset_id,
+            asset_type,
+            file_path,
+        } => {
+            insert_asset(
+                &tx,
+                id,
+                file_id,
+                Some(parent_asset_id),
+                asset_type.clone(),
+                file_path,
+                ProcessingStatus::Queued,
+                None,
+            )?;
+        }
+        QueueStep::Nothing => {}
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
 pub fn get_file_target_stage(pool: &DbPool, file_id: &str) -> Result<Option<String>> {
-    let conn = pool.lock().unwrap();
+    let conn = pool.read()?;
 
     let mut stmt = conn.prepare("SELECT target_stage FROM files WHERE id = ?1")?;
     let mut rows = stmt.query([file_id])?;
@@ -261,3 +624,191 @@ pub fn get_file_target_stage(pool: &DbPool, file_id: &str) -> Result<Option<Stri
         Ok(None)
     }
 }
+
+pub fn get_file_original_filename(pool: &DbPool, file_id: &str) -> Result<Option<String>> {
+    let conn = pool.read()?;
+
+    let mut stmt = conn.prepare("SELECT original_filename FROM files WHERE id = ?1")?;
+    let mut rows = stmt.query([file_id])?;
+
+    if let Some(row) = rows.next()? {
+        Ok(Some(row.get(0)?))
+    } else {
+        Ok(None)
+    }
+}
+
+pub fn get_setting(pool: &DbPool, key: &str) -> Result<Option<String>> {
+    let conn = pool.read()?;
+
+    let mut stmt = conn.prepare("SELECT value FROM settings WHERE key = ?1")?;
+    let mut rows = stmt.query([key])?;
+
+    if let Some(row) = rows.next()? {
+        Ok(Some(row.get(0)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// writes every pair in one transaction so a failed save leaves the old settings whole
+pub fn set_settings(pool: &DbPool, values: &[(&str, String)]) -> Result<()> {
+    let mut conn = pool.write()?;
+    let tx = conn.transaction()?;
+
+    for (key, value) in values {
+        tx.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            params![key, value],
+        )?;
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEASE: Duration = Duration::from_secs(60);
+
+    /// a database with one file whose original is claimed by worker "a"
+    fn claimed_separation(dir: &Path) -> DbPool {
+        let pool = init_db(&dir.join("lala.db")).unwrap();
+        create_file(
+            &pool,
+            "song",
+            "song.mp3",
+            "original",
+            AssetType::Original,
+            "song/original.mp3",
+        )
+        .unwrap();
+        update_asset_status(&pool, "original", ProcessingStatus::Queued, None).unwrap();
+        let claimed = claim_next_asset(&pool, "a", LEASE).unwrap().unwrap();
+        assert_eq!(claimed.id, "original");
+        pool
+    }
+
+    fn stem(id: &str) -> NewStem {
+        NewStem {
+            id: id.to_string(),
+            asset_type: AssetType::StemPiano,
+            file_path: format!("song/{}.wav", id),
+            clipping: Clipping::default(),
+        }
+    }
+
+    fn status_and_worker(pool: &DbPool, asset_id: &str) -> (String, Option<String>) {
+        pool.read()
+            .unwrap()
+            .query_row(
+                "SELECT status, worker_id FROM assets WHERE id = ?1",
+                [asset_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap()
+    }
+
+    fn expire_lease(pool: &DbPool, asset_id: &str) {
+        pool.write()
+            .unwrap()
+            .execute(
+                "UPDATE assets SET lease_expires_at = 0 WHERE id = ?1",
+                [asset_id],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn release_tells_a_cancel_from_a_takeover() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = claimed_separation(dir.path());
+
+        cancel_file_processing(&pool, "song").unwrap();
+        let state =
+            release_asset(&pool, "original", "a", ProcessingStatus::Completed, None).unwrap();
+        assert_eq!(state, LeaseState::Cancelled);
+        assert_eq!(
+            status_and_worker(&pool, "original"),
+            ("cancelled".to_string(), None)
+        );
+
+        update_asset_status(&pool, "original", ProcessingStatus::Queued, None).unwrap();
+        claim_next_asset(&pool, "a", LEASE).unwrap().unwrap();
+        expire_lease(&pool, "original");
+        claim_next_asset(&pool, "b", LEASE).unwrap().unwrap();
+
+        let state =
+            release_asset(&pool, "original", "a", ProcessingStatus::Completed, None).unwrap();
+        assert_eq!(state, LeaseState::Lost);
+        assert_eq!(
+            status_and_worker(&pool, "original"),
+            ("processing".to_string(), Some("b".to_string()))
+        );
+
+        let state =
+            release_asset(&pool, "original", "b", ProcessingStatus::Completed, None).unwrap();
+        assert_eq!(state, LeaseState::Held);
+        assert_eq!(
+            status_and_worker(&pool, "original"),
+            ("completed".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn stems_are_only_registered_under_the_lease() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = claimed_separation(dir.path());
+        let stems = |pool: &DbPool| get_assets_by_file(pool, "song").unwrap().len() - 1;
+
+        let state = create_stem_assets(&pool, "song", "original", "b", &[stem("x")]).unwrap();
+        assert_eq!(state, LeaseState::Lost);
+        assert_eq!(stems(&pool), 0);
+
+        let state = create_stem_assets(&pool, "song", "original", "a", &[stem("piano")]).unwrap();
+        assert_eq!(state, LeaseState::Held);
+        assert_eq!(stems(&pool), 1);
+
+        cancel_file_processing(&pool, "song").unwrap();
+        let state = create_stem_assets(&pool, "song", "original", "a", &[stem("y")]).unwrap();
+        assert_eq!(state, LeaseState::Cancelled);
+        assert_eq!(stems(&pool), 1);
+
+        assert_eq!(
+            delete_child_assets(&pool, "original").unwrap(),
+            vec!["song/piano.wav".to_string()]
+        );
+        assert_eq!(stems(&pool), 0);
+    }
+
+    #[test]
+    fn stages_are_only_queued_while_idle() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = claimed_separation(dir.path());
+        let midi = QueueStep::Create {
+            id: "midi".to_string(),
+            parent_asset_id: "original".to_string(),
+            asset_type: AssetType::Midi,
+            file_path: "song/stem_piano.midi".to_string(),
+        };
+
+        // neither write lands while the original is processing
+        assert!(queue_stage(&pool, "song", "midi", &midi).is_err());
+        assert_eq!(get_file_target_stage(&pool, "song").unwrap(), None);
+        assert_eq!(get_assets_by_file(&pool, "song").unwrap().len(), 1);
+
+        release_asset(&pool, "original", "a", ProcessingStatus::Completed, None).unwrap();
+        queue_stage(&pool, "song", "midi", &midi).unwrap();
+        assert_eq!(
+            get_file_target_stage(&pool, "song").unwrap(),
+            Some("midi".to_string())
+        );
+        assert_eq!(
+            status_and_worker(&pool, "midi"),
+            ("queued".to_string(), None)
+        );
+    }
+}
@@ -1,105 +1,120 @@
+use crate::cancellation::CancellationToken;
+use crate::separator::{SeparationOptions, Separator, WindowType, MAX_OVERLAP};
 use anyhow::{anyhow, Result};
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::path::Path;
 use tch::{CModule, Device, Kind, Tensor};
 
-const SAMPLE_RATE: i64 = 44100;
-const SEGMENT_LENGTH: i64 = 441000; // 10 seconds at 44.1khz
-const OVERLAP_RATIO: f64 = 0.25;
+/// describes a torchscript separation model and the contract it was exported with
+#[derive(Debug, Clone)]
+pub struct ModelSpec {
+    pub id: &'static str,
+    pub path: &'static str,
+    pub sample_rate: u32,
+    /// samples per forward pass, the model was traced with this input length.
+    /// shorter chunks are padded up to it, longer ones aren't possible
+    pub segment_length: i64,
+    /// output stem names in the order the model emits them
+    pub stems: &'static [&'static str],
+}
+
+/// every separation model the app knows how to run, see script.py for exports
+pub const MODEL_REGISTRY: &[ModelSpec] = &[
+    ModelSpec {
+        id: "htdemucs_6s",
+        path: "models/htdemucs_6s.pt",
+        sample_rate: 44100,
+        segment_length: 343980, // 7.8 seconds, the transformer's training length
+        stems: &["drums", "bass", "other", "vocals", "guitar", "piano"],
+    },
+    ModelSpec {
+        id: "hdemucs",
+        path: "models/hdemucs.pt",
+        sample_rate: 44100,
+        segment_length: 441000, // 10 seconds
+        stems: &["drums", "bass", "other", "vocals"],
+    },
+];
+
+/// first registered model whose weights are present on disk, in registry order
+pub fn default_model() -> Result<&'static ModelSpec> {
+    MODEL_REGISTRY
+        .iter()
+        .find(|spec| Path::new(spec.path).exists())
+        .ok_or_else(|| {
+            anyhow!(
+                "no separation model found. expected one of: {}",
+                MODEL_REGISTRY
+                    .iter()
+                    .map(|spec| spec.path)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+}
 
 pub struct DemucsModel {
     model: CModule,
     device: Device,
+    spec: ModelSpec,
     stems: Vec<String>,
+    /// largest batch that has fit on the device so far. a batch that ran out of
+    /// memory lowers it for every later block and shift, until the model is reloaded
+    max_batch_size: Cell<usize>,
 }
 
 impl DemucsModel {
-    pub fn new(model_path: &Path) -> Result<Self> {
+    pub fn new(spec: &ModelSpec, device: Device) -> Result<Self> {
+        let model_path = Path::new(spec.path);
         if !model_path.exists() {
             return Err(anyhow!(
-                "model file not found at {}. please ensure demucs.pt is available.",
-                model_path.display()
+                "model file not found at {}. please ensure {}.pt is available.",
+                model_path.display(),
+                spec.id
             ));
         }
 
-        // use gpu if available, otherwise cpu
-        let device = if tch::Cuda::is_available() {
-            println!("using cuda device for inference");
-            Device::Cuda(0)
-        } else {
-            println!("using cpu device for inference");
-            Device::Cpu
-        };
-
+        println!("loading separation model {} on {:?}", spec.id, device);
         let model = CModule::load_on_device(model_path, device)?;
 
-        // demucs 4-stem model outputs
-        let stems = vec![
-            "drums".to_string(),
-            "bass".to_string(),
-            "other".to_string(),
-            "vocals".to_string(),
-        ];
+        let stems = spec.stems.iter().map(|s| s.to_string()).collect();
 
         Ok(Self {
             model,
             device,
+            spec: spec.clone(),
             stems,
+            max_batch_size: Cell::new(usize::MAX),
         })
     }
 
-    /// separates audio into stems using demucs model
-    /// input: tensor [2, samples] (stereo audio)
-    /// output: hashmap of stem tensors [2, samples]
-    pub fn separate<F>(&self, audio: &Tensor, mut progress_cb: F) -> Result<HashMap<String, Tensor>>
-    where
-        F: FnMut(u32, u32),
-    {
-        let audio_shape = audio.size();
-        let n_channels = audio_shape[0];
-        let n_samples = audio_shape[1];
-
-        println!(
-            "separating audio: {} channels, {} samples ({:.1}s)",
-            n_channels,
-            n_samples,
-            n_samples as f64 / SAMPLE_RATE as f64
-        );
-
-        if n_samples <= SEGMENT_LENGTH {
-            // process short audio in one go
-            let res = self.separate_segment(audio)?;
-            progress_cb(90, 1);
-            Ok(res)
-        } else {
-            // process long audio with overlap-add
-            self.separate_with_overlap(audio, progress_cb)
-        }
-    }
-
+    /// runs one forward pass. `audio` is already normalized with the track statistics
     fn separate_segment(&self, audio: &Tensor) -> Result<HashMap<String, Tensor>> {
-        let audio_shape = audio.size();
-        let n_channels = audio_shape[0];
-        let n_samples = audio_shape[1];
-
-        // pad audio to model's expected length
-        let mut padded = audio.shallow_clone();
-        if n_samples < SEGMENT_LENGTH {
-            let padding = Tensor::zeros(
-                &[n_channels, SEGMENT_LENGTH - n_samples],
-                (Kind::Float, self.device),
-            );
-            padded = Tensor::cat(&[padded, padding], 1);
-        } else if n_samples > SEGMENT_LENGTH {
-            padded = padded.narrow(1, 0, SEGMENT_LENGTH);
-        }
+        let mut results = self.separate_batch(std::slice::from_ref(audio))?;
+        Ok(results.remove(0))
+    }
 
-        // normalize audio for model input - use unbiased=false for std
-        let audio_std = padded.std(false);
-        let normalized = &padded / (&audio_std + 1e-8);
+    /// runs several chunks through the model in one [batch, 2, segment] forward pass.
+    /// chunks shorter than the model segment are zero padded and trimmed back
+    fn separate_batch(&self, chunks: &[Tensor]) -> Result<Vec<HashMap<String, Tensor>>> {
+        let segment_length = self.spec.segment_length;
+
+        // pad each chunk to model's expected length
+        let padded = chunks
+            .iter()
+            .map(|chunk| {
+                let n_samples = chunk.size()[1];
+                if n_samples < segment_length {
+                    chunk.constant_pad_nd([0, segment_length - n_samples])
+                } else {
+                    chunk.narrow(1, 0, segment_length)
+                }
+            })
+            .collect::<Vec<_>>();
 
-        // add batch dimension: [1, 2, SEGMENT_LENGTH]
-        let input = normalized.unsqueeze(0);
+        // [batch, 2, segment_length]
+        let input = Tensor::stack(&padded, 0);
 
         // Debug print
         println!("Model input shape: {:?}", input.size());
@@ -110,44 +125,60 @@ impl DemucsModel {
         // Debug print
         println!("Model output shape: {:?}", output.size());
 
-        // output shape: [1, 4, 2, SEGMENT_LENGTH] (batch, stems, channels, time)
-        let separated = output.squeeze_dim(0); // [4, 2, SEGMENT_LENGTH]
-
-        // denormalize and trim to original length
-        let denormalized = &separated * &audio_std;
-        let trimmed = if n_samples < SEGMENT_LENGTH {
-            denormalized.narrow(2, 0, n_samples)
-        } else {
-            denormalized
-        };
+        // output shape: [batch, n_stems, 2, segment_length] (batch, stems, channels, time)
+        let results = chunks
+            .iter()
+            .enumerate()
+            .map(|(b, chunk)| {
+                // trim to original length
+                let n_samples = chunk.size()[1].min(segment_length);
+                let separated = output.select(0, b as i64).narrow(2, 0, n_samples);
+
+                // convert to hashmap
+                let mut result = HashMap::new();
+                for (i, stem_name) in self.stems.iter().enumerate() {
+                    // use select instead of i() for tensor indexing
+                    let stem_audio = separated.select(0, i as i64).to_device(self.device);
+                    result.insert(stem_name.clone(), stem_audio);
+                }
+                result
+            })
+            .collect();
 
-        // convert to hashmap
-        let mut result = HashMap::new();
-        for (i, stem_name) in self.stems.iter().enumerate() {
-            // use select instead of i() for tensor indexing
-            let stem_audio = trimmed.select(0, i as i64).to_device(self.device);
-            result.insert(stem_name.clone(), stem_audio);
-        }
+        Ok(results)
+    }
 
-        Ok(result)
+    /// chunk length for `options`, capped at the segment the model was exported with
+    fn chunk_length(&self, options: &SeparationOptions) -> i64 {
+        let max = self.spec.segment_length;
+        options.segment_seconds.map_or(max, |seconds| {
+            ((seconds * self.spec.sample_rate as f64).round() as i64).clamp(1, max)
+        })
     }
 
     fn separate_with_overlap<F>(
         &self,
         audio: &Tensor,
+        chunk_length: i64,
+        options: &SeparationOptions,
+        cancel: &CancellationToken,
         mut progress_cb: F,
     ) -> Result<HashMap<String, Tensor>>
     where
         F: FnMut(u32, u32),
     {
         let n_samples = audio.size()[1];
-        let hop_size = (SEGMENT_LENGTH as f64 * (1.0 - OVERLAP_RATIO)) as i64;
-        let n_chunks = ((n_samples - SEGMENT_LENGTH) as f64 / hop_size as f64).ceil() as usize + 1;
+        let overlap = options.overlap.clamp(0.0, MAX_OVERLAP);
+        let hop_size = ((chunk_length as f64 * (1.0 - overlap)) as i64).max(1);
+        let overlap_length = chunk_length - hop_size;
+        let n_chunks = ((n_samples - chunk_length) as f64 / hop_size as f64).ceil() as usize + 1;
 
         println!(
-            "processing {} chunks with {:.0}% overlap",
+            "processing {} chunks of {} samples with {:.0}% overlap, {:?} window",
             n_chunks,
-            OVERLAP_RATIO * 100.0
+            chunk_length,
+            overlap * 100.0,
+            options.window
         );
 
         // initialize output tensors
@@ -161,74 +192,83 @@ impl DemucsModel {
             );
         }
 
-        // create blending window for smooth transitions
-        let window = self.create_blend_window(SEGMENT_LENGTH);
-        let window_tensor = Tensor::from_slice(&window).to_device(self.device);
-
-        // process each chunk
-        for chunk_idx in 0..n_chunks {
-            let progress = 10 + ((chunk_idx as f32 / n_chunks as f32) * 80.0).round() as u32;
-            progress_cb(progress, n_chunks as u32);
+        let chunk_range = |chunk_idx: usize| {
             let start = chunk_idx as i64 * hop_size;
-            let end = (start + SEGMENT_LENGTH).min(n_samples);
-            let actual_size = end - start;
+            let end = (start + chunk_length).min(n_samples);
+            (start, end)
+        };
+
+        // chunks go through the model `batch_size` at a time. a batch that fails,
+        // usually because memory ran out, is retried at half the size from then on
+        let mut batch_size = options.batch_size.clamp(1, self.max_batch_size.get());
+        let mut chunk_idx = 0;
+
+        while chunk_idx < n_chunks {
+            cancel.check()?;
+
+            let batch_end = (chunk_idx + batch_size).min(n_chunks);
+            let inputs = (chunk_idx..batch_end)
+                .map(|idx| {
+                    let (start, end) = chunk_range(idx);
+                    audio.narrow(1, start, end - start)
+                })
+                .collect::<Vec<_>>();
 
-            progress_cb(
-                (chunk_idx + 1).try_into().unwrap(),
-                n_chunks.try_into().unwrap(),
-            );
             println!(
-                "chunk {}/{}: samples {} to {} (size: {})",
+                "chunks {}-{}/{} (batch of {})",
                 chunk_idx + 1,
+                batch_end,
                 n_chunks,
-                start,
-                end,
-                actual_size
+                inputs.len()
             );
 
-            // extract chunk with padding if needed
-            let chunk = if actual_size == SEGMENT_LENGTH {
-                audio.narrow(1, start, SEGMENT_LENGTH)
-            } else {
-                // pad short final chunk
-                let partial = audio.narrow(1, start, actual_size);
-                let padding = Tensor::zeros(
-                    &[2, SEGMENT_LENGTH - actual_size],
-                    (Kind::Float, self.device),
-                );
-                Tensor::cat(&[partial, padding], 1)
+            let batch_results = match self.separate_batch(&inputs) {
+                Ok(results) => results,
+                Err(e) if batch_size > 1 && is_out_of_memory(&e) => {
+                    batch_size /= 2;
+                    self.max_batch_size.set(batch_size);
+                    println!(
+                        "batch failed ({}), retrying with batch size {}",
+                        e, batch_size
+                    );
+                    continue;
+                }
+                Err(e) => return Err(e),
             };
 
-            // Debug print
-            println!("Chunk shape before segment separation: {:?}", chunk.size());
-
-            // separate this chunk
-            let chunk_results = self.separate_segment(&chunk)?;
-
-            // apply windowing and overlap-add
-            let current_window = if actual_size == SEGMENT_LENGTH {
-                window_tensor.shallow_clone()
-            } else {
-                // no windowing for final partial chunk
-                Tensor::ones(&[actual_size], (Kind::Float, self.device))
-            };
+            for chunk_results in batch_results {
+                let (start, end) = chunk_range(chunk_idx);
+                let actual_size = end - start;
+
+                // fade only where a neighbouring chunk overlaps, not at the ends of the
+                // track. the shorter tail chunk gets the same fade-in as every other chunk
+                let window = blend_window(
+                    actual_size,
+                    overlap_length,
+                    start > 0,
+                    end < n_samples,
+                    options,
+                );
+                let window_tensor = Tensor::from_slice(&window)
+                    .to_device(self.device)
+                    .unsqueeze(0);
+
+                for (stem_name, stem_chunk) in chunk_results {
+                    if let Some(output_stem) = separated_stems.get_mut(&stem_name) {
+                        // get mutable slice and add windowed chunk
+                        let mut output_slice = output_stem.narrow(1, start, actual_size);
+                        output_slice += &stem_chunk * &window_tensor;
+                    }
+                }
 
-            for (stem_name, stem_chunk) in chunk_results {
-                if let Some(output_stem) = separated_stems.get_mut(&stem_name) {
-                    // trim chunk to actual size and apply window
-                    let trimmed_chunk = stem_chunk.narrow(1, 0, actual_size);
-                    let windowed_chunk = &trimmed_chunk * current_window.unsqueeze(0);
+                // update weight sum for normalization
+                let mut weight_slice = weight_sum.narrow(1, start, actual_size);
+                let weight_update = window_tensor.expand_as(&weight_slice);
+                weight_slice += &weight_update;
 
-                    // get mutable slice and add windowed chunk
-                    let mut output_slice = output_stem.narrow(1, start, actual_size);
-                    output_slice += &windowed_chunk;
-                }
+                chunk_idx += 1;
+                progress_cb(chunk_idx as u32, n_chunks as u32);
             }
-
-            // update weight sum for normalization
-            let mut weight_slice = weight_sum.narrow(1, start, actual_size);
-            let weight_update = current_window.unsqueeze(0).expand_as(&weight_slice);
-            weight_slice += &weight_update;
         }
 
         // normalize by weight sum to complete overlap-add
@@ -239,24 +279,255 @@ impl DemucsModel {
 
         Ok(separated_stems)
     }
+}
 
-    fn create_blend_window(&self, length: i64) -> Vec<f32> {
-        let fade_length = (length as f64 * OVERLAP_RATIO / 2.0) as usize;
-        let mut window = vec![1.0; length as usize];
+/// whether a forward pass failed for lack of gpu or host memory, the one
+/// error a smaller batch can fix
+fn is_out_of_memory(error: &anyhow::Error) -> bool {
+    const ALLOCATION_FAILURES: &[&str] = &[
+        "out of memory",
+        "can't allocate memory",
+        "not enough memory",
+        "failed to allocate",
+    ];
+
+    let message = format!("{:#}", error).to_lowercase();
+    ALLOCATION_FAILURES.iter().any(|m| message.contains(m))
+}
 
-        // cosine fade-in
-        for i in 0..fade_length {
-            let t = i as f32 / fade_length as f32;
-            window[i] = 0.5 * (1.0 - (std::f32::consts::PI * t).cos());
+/// crossfade weights for one chunk of `length` samples. ramps run over the
+/// overlap with the previous and next chunk, and never reach zero so every
+/// sample keeps some weight in the overlap-add
+fn blend_window(
+    length: i64,
+    overlap: i64,
+    fade_in: bool,
+    fade_out: bool,
+    options: &SeparationOptions,
+) -> Vec<f32> {
+    let length = length as usize;
+    let fade_length = match options.window {
+        WindowType::Triangular => length / 2,
+        WindowType::Cosine | WindowType::Linear => (overlap as usize).min(length / 2),
+    };
+
+    let ramp = |i: usize| -> f64 {
+        let t = (i + 1) as f64 / (fade_length + 1) as f64;
+        match options.window {
+            WindowType::Cosine => 0.5 * (1.0 - (std::f64::consts::PI * t).cos()),
+            WindowType::Linear | WindowType::Triangular => t,
         }
+    };
 
-        // cosine fade-out
-        for i in 0..fade_length {
-            let t = i as f32 / fade_length as f32;
-            let idx = length as usize - 1 - i;
-            window[idx] = 0.5 * (1.0 - (std::f32::consts::PI * t).cos());
+    (0..length)
+        .map(|i| {
+            let mut weight: f64 = 1.0;
+            if fade_in && i < fade_length {
+                weight = ramp(i);
+            }
+            let from_end = length - 1 - i;
+            if fade_out && from_end < fade_length {
+                weight = weight.min(ramp(from_end));
+            }
+            weight.powf(options.transition_power) as f32
+        })
+        .collect()
+}
+
+impl Separator for DemucsModel {
+    fn stems(&self) -> &[String] {
+        &self.stems
+    }
+
+    /// sample rate the model was trained on, input must be resampled to this
+    fn sample_rate(&self) -> u32 {
+        self.spec.sample_rate
+    }
+
+    fn device(&self) -> Device {
+        self.device
+    }
+
+    fn segment_length(&self) -> usize {
+        self.spec.segment_length as usize
+    }
+
+    /// separates audio into stems using demucs model
+    /// input: tensor [2, samples] (stereo audio)
+    /// output: hashmap of stem tensors [2, samples]
+    fn separate(
+        &self,
+        audio: &Tensor,
+        options: &SeparationOptions,
+        cancel: &CancellationToken,
+        progress_cb: &mut dyn FnMut(u32, u32),
+    ) -> Result<HashMap<String, Tensor>> {
+        let audio_shape = audio.size();
+        let n_channels = audio_shape[0];
+        let n_samples = audio_shape[1];
+        let chunk_length = self.chunk_length(options);
+
+        println!(
+            "separating audio: {} channels, {} samples ({:.1}s)",
+            n_channels,
+            n_samples,
+            n_samples as f64 / self.spec.sample_rate as f64
+        );
+
+        // normalize by the statistics of the whole track's mono mix, like upstream
+        // demucs, so every chunk sees the same scale however loud it is itself
+        let (mean, std) = options.track_stats.unwrap_or_else(|| {
+            let mono = (audio.select(0, 0) + audio.select(0, 1)) * 0.5;
+            (
+                mono.mean(Kind::Float).double_value(&[]),
+                mono.std(false).double_value(&[]),
+            )
+        });
+        let normalized = (audio - mean) / (std + 1e-8);
+
+        let stems = if n_samples <= chunk_length {
+            // process short audio in one go
+            cancel.check()?;
+            let res = self.separate_segment(&normalized)?;
+            progress_cb(1, 1);
+            res
+        } else {
+            // process long audio with overlap-add
+            self.separate_with_overlap(&normalized, chunk_length, options, cancel, progress_cb)?
+        };
+
+        // back to the track's level
+        Ok(stems
+            .into_iter()
+            .map(|(name, stem)| (name, stem * std + mean))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    /// 0.1 s chunks, so a second of audio crosses plenty of boundaries
+    const SEGMENT_LENGTH: i64 = 4410;
+
+    const STEMS: &[&str] = &["low", "high"];
+
+    /// a model that isn't scale invariant: it saturates, and splits the result
+    /// evenly between the stems. with per-chunk normalization a quiet chunk
+    /// saturates differently than a loud one and the level steps at the boundary
+    fn saturating_model() -> DemucsModel {
+        let spec = ModelSpec {
+            id: "saturating",
+            path: "",
+            sample_rate: SAMPLE_RATE,
+            segment_length: SEGMENT_LENGTH,
+            stems: STEMS,
+        };
+        let example = Tensor::zeros([1, 2, SEGMENT_LENGTH], (Kind::Float, Device::Cpu));
+        let model = CModule::create_by_tracing(
+            "saturating",
+            "forward",
+            &[example],
+            &mut |x: &[Tensor]| {
+                let share = x[0].tanh() / STEMS.len() as f64;
+                vec![share.unsqueeze(1).repeat([1, STEMS.len() as i64, 1, 1])]
+            },
+        )
+        .unwrap();
+
+        DemucsModel {
+            model,
+            device: Device::Cpu,
+            stems: STEMS.iter().map(|s| s.to_string()).collect(),
+            spec,
+            max_batch_size: Cell::new(usize::MAX),
+        }
+    }
+
+    /// a sine that jumps from quiet to loud halfway through
+    fn quiet_then_loud(seconds: f64) -> Tensor {
+        let n = (seconds * SAMPLE_RATE as f64) as i64;
+        let t = Tensor::arange(n, (Kind::Float, Device::Cpu)) / SAMPLE_RATE as f64;
+        let gain = t.lt(seconds / 2.0).to_kind(Kind::Float) * (0.01 - 0.5) + 0.5;
+        let sine = (t * (2.0 * std::f64::consts::PI * 220.0)).sin() * gain;
+        Tensor::stack(&[&sine, &(&sine * 0.8)], 0)
+    }
+
+    /// what the model gives when the whole track is normalized at once
+    fn whole_track_stem(audio: &Tensor, mean: f64, std: f64) -> Tensor {
+        ((audio - mean) / (std + 1e-8)).tanh() / STEMS.len() as f64 * std + mean
+    }
+
+    fn mono_stats(audio: &Tensor) -> (f64, f64) {
+        let mono = (audio.select(0, 0) + audio.select(0, 1)) * 0.5;
+        (
+            mono.mean(Kind::Float).double_value(&[]),
+            mono.std(false).double_value(&[]),
+        )
+    }
+
+    #[test]
+    fn chunk_boundaries_keep_the_level() {
+        let model = saturating_model();
+        let audio = quiet_then_loud(1.0);
+        let (mean, std) = mono_stats(&audio);
+
+        let stems = model
+            .separate(
+                &audio,
+                &SeparationOptions::default(),
+                &CancellationToken::new(),
+                &mut |_, _| {},
+            )
+            .unwrap();
+
+        // every chunk saw the same normalization, so the crossfades blend equal
+        // values and the result is the whole-track answer, boundaries included
+        let expected = whole_track_stem(&audio, mean, std);
+        for name in STEMS {
+            let stem = &stems[*name];
+            let error = (stem - &expected).abs().max().double_value(&[]);
+            assert!(error < 1e-4, "{} stem is off by {} somewhere", name, error);
         }
+    }
+
+    #[test]
+    fn blocks_use_the_track_level() {
+        let model = saturating_model();
+        let audio = quiet_then_loud(1.0);
+        let (mean, std) = mono_stats(&audio);
+        let options = SeparationOptions {
+            track_stats: Some((mean, std)),
+            ..SeparationOptions::default()
+        };
+
+        // a streaming block from the loud half, normalized like the whole track
+        let half = audio.size()[1] / 2;
+        let block = audio.narrow(1, half, half);
+        let stems = model
+            .separate(&block, &options, &CancellationToken::new(), &mut |_, _| {})
+            .unwrap();
+
+        let expected = whole_track_stem(&block, mean, std);
+        let error = (&stems[STEMS[0]] - &expected).abs().max().double_value(&[]);
+        assert!(error < 1e-4, "block is off by {}", error);
+    }
+
+    #[test]
+    fn only_allocation_failures_shrink_the_batch() {
+        let cuda = anyhow::anyhow!(
+            "CUDA out of memory. Tried to allocate 2.00 GiB (GPU 0; 7.79 GiB total capacity)"
+        );
+        let cpu = anyhow::anyhow!(
+            "[enforce fail at alloc_cpu.cpp:83] err == 0. DefaultCPUAllocator: can't allocate memory"
+        );
+        assert!(is_out_of_memory(&cuda));
+        assert!(is_out_of_memory(&cpu.context("forward failed")));
 
-        window
+        let shape = anyhow::anyhow!("The size of tensor a (4) must match the size of tensor b (2)");
+        assert!(!is_out_of_memory(&shape));
     }
 }
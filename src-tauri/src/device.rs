@@ -0,0 +1,187 @@
+use anyhow::{anyhow, bail, Result};
+use std::cell::Cell;
+use std::sync::OnceLock;
+use tch::{Cuda, Device};
+
+/// settings id that picks the first gpu if there is one, otherwise the cpu
+pub const AUTO_DEVICE: &str = "auto";
+
+/// where inference runs. stored in settings as "auto", "cpu" or "cuda:N"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DevicePolicy {
+    Auto,
+    Cpu,
+    Cuda(usize),
+}
+
+impl DevicePolicy {
+    pub fn to_string(&self) -> String {
+        match self {
+            DevicePolicy::Auto => AUTO_DEVICE.to_string(),
+            DevicePolicy::Cpu => "cpu".to_string(),
+            DevicePolicy::Cuda(index) => format!("cuda:{}", index),
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            AUTO_DEVICE => Ok(DevicePolicy::Auto),
+            "cpu" => Ok(DevicePolicy::Cpu),
+            _ => s
+                .strip_prefix("cuda:")
+                .and_then(|index| index.parse().ok())
+                .map(DevicePolicy::Cuda)
+                .ok_or_else(|| anyhow!("invalid device: {}, expected auto, cpu or cuda:N", s)),
+        }
+    }
+
+    /// the device to load models on. a gpu that isn't there is an error rather
+    /// than a silent fallback, so a typo doesn't turn into hour-long cpu runs
+    pub fn resolve(&self) -> Result<Device> {
+        match self {
+            DevicePolicy::Auto if Cuda::is_available() => Ok(Device::Cuda(0)),
+            DevicePolicy::Auto | DevicePolicy::Cpu => Ok(Device::Cpu),
+            DevicePolicy::Cuda(index) => {
+                let count = Cuda::device_count() as usize;
+                if *index >= count {
+                    bail!(
+                        "cuda:{} is not available, this machine has {} gpu(s)",
+                        index,
+                        count
+                    );
+                }
+                Ok(Device::Cuda(*index))
+            }
+        }
+    }
+}
+
+/// device ids that can be picked on this machine
+#[tauri::command]
+pub fn list_devices() -> Vec<String> {
+    let gpus = (0..Cuda::device_count().max(0) as usize).map(DevicePolicy::Cuda);
+
+    [DevicePolicy::Auto, DevicePolicy::Cpu]
+        .into_iter()
+        .chain(gpus)
+        .map(|policy| policy.to_string())
+        .collect()
+}
+
+/// libtorch's own intra-op thread count, one per core
+static DEFAULT_THREADS: OnceLock<i32> = OnceLock::new();
+
+/// the inter-op thread count libtorch was started with in this process
+static INTEROP_THREADS: OnceLock<Option<usize>> = OnceLock::new();
+
+/// caps the cpu threads libtorch uses, None leaves libtorch's default of one per core.
+/// the inter-op pool can only be sized before the first parallel op, a change to it
+/// applies after a restart
+pub fn configure_threads(intra_op: Option<usize>, inter_op: Option<usize>) {
+    let default = *DEFAULT_THREADS.get_or_init(tch::get_num_threads);
+    let intra_op = intra_op.map_or(default, |n| n as i32);
+    if tch::get_num_threads() != intra_op {
+        println!("using {} intra-op threads", intra_op);
+        tch::set_num_threads(intra_op);
+    }
+
+    let started_with = *INTEROP_THREADS.get_or_init(|| {
+        if let Some(n) = inter_op {
+            println!("using {} inter-op threads", n);
+            tch::set_num_interop_threads(n as i32);
+        }
+        inter_op
+    });
+    if started_with != inter_op {
+        println!("inter-op thread count changes apply after a restart");
+    }
+}
+
+thread_local! {
+    /// whether this thread currently runs at background priority
+    static BACKGROUND: Cell<bool> = const { Cell::new(false) };
+}
+
+/// whether libtorch's threads were spawned at background priority
+static POOL_BACKGROUND: OnceLock<bool> = OnceLock::new();
+
+/// runs the calling thread below normal priority so inference doesn't starve the
+/// ui. call it on the worker thread before its first tch call: libtorch spawns its
+/// thread pools on the first op and they inherit the priority the worker has then.
+/// on linux the nice value is per thread, so a later change only reaches the worker
+/// thread itself and applies to the pools after a restart
+pub fn set_background_priority(enabled: bool) {
+    if BACKGROUND.get() != enabled {
+        match set_thread_priority(enabled) {
+            Ok(()) => {
+                println!(
+                    "worker running at {} priority",
+                    if enabled { "background" } else { "normal" }
+                );
+                BACKGROUND.set(enabled);
+            }
+            // raising the priority back usually needs privileges, keep going as is.
+            // the worker thread is new after a restart and starts at normal priority
+            Err(e) => eprintln!(
+                "failed to change worker priority, it applies after a restart: {}",
+                e
+            ),
+        }
+    }
+
+    let pools = *POOL_BACKGROUND.get_or_init(|| BACKGROUND.get());
+    if pools != enabled {
+        println!(
+            "libtorch threads keep their priority, the change applies to them after a restart"
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_thread_priority(background: bool) -> std::io::Result<()> {
+    // on linux the nice value is per thread, pid 0 is the calling thread
+    let nice = if background { 10 } else { 0 };
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+fn set_thread_priority(background: bool) -> std::io::Result<()> {
+    let class = if background {
+        libc::qos_class_t::QOS_CLASS_UTILITY
+    } else {
+        libc::qos_class_t::QOS_CLASS_DEFAULT
+    };
+    let result = unsafe { libc::pthread_set_qos_class_self_np(class, 0) };
+    if result != 0 {
+        return Err(std::io::Error::from_raw_os_error(result));
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn set_thread_priority(background: bool) -> std::io::Result<()> {
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentThread, SetThreadPriority, THREAD_PRIORITY_BELOW_NORMAL, THREAD_PRIORITY_NORMAL,
+    };
+
+    let priority = if background {
+        THREAD_PRIORITY_BELOW_NORMAL
+    } else {
+        THREAD_PRIORITY_NORMAL
+    };
+    if unsafe { SetThreadPriority(GetCurrentThread(), priority) } == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn set_thread_priority(_background: bool) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "thread priorities are not supported on this platform",
+    ))
+}
@@ -0,0 +1,295 @@
+use crate::audio_io::AudioStream;
+use crate::flac::FlacWriter;
+use crate::opus::OpusWriter;
+use crate::streaming::{to_stereo, Stereo};
+use anyhow::{anyhow, Context, Result};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+/// file formats stems can be written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// 32-bit float wav, keeps everything including peaks over full scale
+    WavFloat,
+    Wav24,
+    Wav16,
+    Flac24,
+    Flac16,
+    /// ogg opus, lossy. only for exports, stems the pipeline reads on from stay lossless
+    Opus,
+}
+
+impl OutputFormat {
+    pub fn to_string(&self) -> String {
+        match self {
+            OutputFormat::WavFloat => "wav_float".to_string(),
+            OutputFormat::Wav24 => "wav24".to_string(),
+            OutputFormat::Wav16 => "wav16".to_string(),
+            OutputFormat::Flac24 => "flac24".to_string(),
+            OutputFormat::Flac16 => "flac16".to_string(),
+            OutputFormat::Opus => "opus".to_string(),
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "wav_float" => Ok(OutputFormat::WavFloat),
+            "wav24" => Ok(OutputFormat::Wav24),
+            "wav16" => Ok(OutputFormat::Wav16),
+            "flac24" => Ok(OutputFormat::Flac24),
+            "flac16" => Ok(OutputFormat::Flac16),
+            "opus" => Ok(OutputFormat::Opus),
+            _ => Err(anyhow!(
+                "invalid format: {}, expected wav_float, wav24, wav16, flac24, flac16 or opus",
+                s
+            )),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::WavFloat | OutputFormat::Wav24 | OutputFormat::Wav16 => "wav",
+            OutputFormat::Flac24 | OutputFormat::Flac16 => "flac",
+            OutputFormat::Opus => "opus",
+        }
+    }
+
+    pub fn is_lossless(&self) -> bool {
+        !matches!(self, OutputFormat::Opus)
+    }
+}
+
+/// samples at or beyond full scale seen while encoding. integer formats clip
+/// them, float wav keeps them but they'll clip wherever the stem goes next
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Clipping {
+    pub clipped_samples: u64,
+    pub total_samples: u64,
+    /// largest absolute sample value, 1.0 is full scale
+    pub peak: f32,
+}
+
+impl Clipping {
+    fn observe(&mut self, sample: f32) {
+        let magnitude = sample.abs();
+        self.peak = self.peak.max(magnitude);
+        self.total_samples += 1;
+        if magnitude > 1.0 {
+            self.clipped_samples += 1;
+        }
+    }
+
+    pub fn is_clipped(&self) -> bool {
+        self.clipped_samples > 0
+    }
+
+    /// e.g. "1234 samples (0.05%) over full scale, peak +1.3 dbfs"
+    pub fn describe(&self) -> String {
+        format!(
+            "{} samples ({:.2}%) over full scale, peak {:+.1} dbfs",
+            self.clipped_samples,
+            100.0 * self.clipped_samples as f64 / self.total_samples.max(1) as f64,
+            20.0 * (self.peak.max(f32::MIN_POSITIVE) as f64).log10()
+        )
+    }
+}
+
+/// rounds float samples to `bits` bit integers with tpdf dither: the sum of two
+/// uniform values, ±1 lsb with a triangular distribution, added before rounding.
+/// this turns the quantization error into steady noise instead of distortion
+/// that follows the signal, which matters for quiet fades in 16 bit
+struct Quantizer {
+    scale: f32,
+    min: i32,
+    max: i32,
+    state: u64,
+}
+
+impl Quantizer {
+    fn new(bits: u32) -> Self {
+        let scale = (1i64 << (bits - 1)) as f32;
+        Self {
+            scale,
+            min: -(1 << (bits - 1)),
+            max: (1 << (bits - 1)) - 1,
+            // fixed seed, the same stem always encodes to the same bytes
+            state: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    fn quantize(&mut self, sample: f32) -> i32 {
+        let dither = self.uniform() - self.uniform();
+        let value = (sample * self.scale + dither).round();
+        (value as i64).clamp(self.min as i64, self.max as i64) as i32
+    }
+
+    /// uniform in [0, 1)
+    fn uniform(&mut self) -> f32 {
+        // xorshift64
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+/// writes stereo audio to a file block by block
+pub trait AudioEncoder {
+    fn write(&mut self, block: &Stereo) -> Result<()>;
+
+    /// completes the file, returns what clipped on the way
+    fn finish(self: Box<Self>) -> Result<Clipping>;
+}
+
+/// an encoder for `format` writing to `path`. input is `sample_rate`, formats
+/// with fixed rates convert on the way
+pub fn create_encoder(
+    path: &Path,
+    format: OutputFormat,
+    sample_rate: u32,
+) -> Result<Box<dyn AudioEncoder>> {
+    Ok(match format {
+        OutputFormat::WavFloat => Box::new(WavEncoder::create(path, sample_rate, None)?),
+        OutputFormat::Wav24 => Box::new(WavEncoder::create(path, sample_rate, Some(24))?),
+        OutputFormat::Wav16 => Box::new(WavEncoder::create(path, sample_rate, Some(16))?),
+        OutputFormat::Flac24 => Box::new(FlacEncoder::create(path, sample_rate, 24)?),
+        OutputFormat::Flac16 => Box::new(FlacEncoder::create(path, sample_rate, 16)?),
+        OutputFormat::Opus => Box::new(OpusEncoder {
+            writer: OpusWriter::create(path, sample_rate)?,
+            clipping: Clipping::default(),
+        }),
+    })
+}
+
+struct WavEncoder {
+    writer: WavWriter<BufWriter<File>>,
+    /// None writes float samples
+    quantizer: Option<Quantizer>,
+    clipping: Clipping,
+}
+
+impl WavEncoder {
+    fn create(path: &Path, sample_rate: u32, bits: Option<u32>) -> Result<Self> {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample: bits.unwrap_or(32) as u16,
+            sample_format: if bits.is_some() {
+                SampleFormat::Int
+            } else {
+                SampleFormat::Float
+            },
+        };
+        let writer = WavWriter::create(path, spec)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+
+        Ok(Self {
+            writer,
+            quantizer: bits.map(Quantizer::new),
+            clipping: Clipping::default(),
+        })
+    }
+}
+
+impl AudioEncoder for WavEncoder {
+    fn write(&mut self, block: &Stereo) -> Result<()> {
+        for (left, right) in block[0].iter().zip(&block[1]) {
+            for sample in [*left, *right] {
+                self.clipping.observe(sample);
+                match &mut self.quantizer {
+                    Some(quantizer) => self.writer.write_sample(quantizer.quantize(sample))?,
+                    None => self.writer.write_sample(sample)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<Clipping> {
+        self.writer.finalize()?;
+        Ok(self.clipping)
+    }
+}
+
+struct FlacEncoder {
+    writer: FlacWriter,
+    quantizer: Quantizer,
+    clipping: Clipping,
+}
+
+impl FlacEncoder {
+    fn create(path: &Path, sample_rate: u32, bits: u32) -> Result<Self> {
+        Ok(Self {
+            writer: FlacWriter::create(path, sample_rate, bits)?,
+            quantizer: Quantizer::new(bits),
+            clipping: Clipping::default(),
+        })
+    }
+}
+
+impl AudioEncoder for FlacEncoder {
+    fn write(&mut self, block: &Stereo) -> Result<()> {
+        let [left, right] = block.each_ref().map(|channel| {
+            channel
+                .iter()
+                .map(|sample| {
+                    self.clipping.observe(*sample);
+                    self.quantizer.quantize(*sample)
+                })
+                .collect::<Vec<_>>()
+        });
+        self.writer.write(&left, &right)
+    }
+
+    fn finish(self: Box<Self>) -> Result<Clipping> {
+        self.writer.finish()?;
+        Ok(self.clipping)
+    }
+}
+
+struct OpusEncoder {
+    writer: OpusWriter,
+    clipping: Clipping,
+}
+
+impl AudioEncoder for OpusEncoder {
+    fn write(&mut self, block: &Stereo) -> Result<()> {
+        for channel in block {
+            for sample in channel {
+                self.clipping.observe(*sample);
+            }
+        }
+        self.writer.write(block)
+    }
+
+    fn finish(self: Box<Self>) -> Result<Clipping> {
+        self.writer.finish()?;
+        Ok(self.clipping)
+    }
+}
+
+/// re-encodes an audio file, e.g. a stored stem for export
+pub fn transcode(source: &Path, destination: &Path, format: OutputFormat) -> Result<Clipping> {
+    let mut stream = AudioStream::open(source)?;
+    let mut encoder = create_encoder(destination, format, stream.sample_rate())?;
+
+    let written: Result<()> = (|| {
+        while let Some(samples) = stream.next_samples()? {
+            encoder.write(&to_stereo(&samples, stream.channels()))?;
+        }
+        Ok(())
+    })();
+
+    match written {
+        Ok(()) => encoder.finish(),
+        Err(e) => {
+            drop(encoder);
+            let _ = std::fs::remove_file(destination);
+            Err(e)
+        }
+    }
+}
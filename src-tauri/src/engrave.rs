@@ -0,0 +1,1279 @@
+use crate::cancellation::CancellationToken;
+use crate::notation::{Clef, Event, NoteType, Score};
+use anyhow::Result;
+use pdf_writer::{Content, Finish, Name, Pdf, Rect, Ref, Str, TextStr};
+use std::collections::HashMap;
+use std::path::Path;
+
+// a4 portrait, in points
+const PAGE_WIDTH: f32 = 595.28;
+const PAGE_HEIGHT: f32 = 841.89;
+const MARGIN_LEFT: f32 = 56.0;
+const MARGIN_RIGHT: f32 = 42.0;
+const MARGIN_TOP: f32 = 56.0;
+const MARGIN_BOTTOM: f32 = 56.0;
+const TITLE_HEIGHT: f32 = 64.0;
+
+/// distance between two staff lines, everything else is measured in this
+const SPACE: f32 = 6.5;
+const STAFF_HEIGHT: f32 = 4.0 * SPACE;
+/// from the bottom line of the treble staff to the top line of the bass staff
+const STAFF_GAP: f32 = 9.0 * SPACE;
+const SYSTEM_HEIGHT: f32 = 2.0 * STAFF_HEIGHT + STAFF_GAP;
+const SYSTEM_GAP: f32 = 8.0 * SPACE;
+
+const STAFF_LINE_WIDTH: f32 = 0.13 * SPACE;
+const STEM_WIDTH: f32 = 0.14 * SPACE;
+const STEM_LENGTH: f32 = 3.5 * SPACE;
+const BEAM_THICKNESS: f32 = 0.5 * SPACE;
+const BEAM_SPACING: f32 = 0.8 * SPACE;
+const NOTEHEAD_RX: f32 = 0.62 * SPACE;
+const NOTEHEAD_RY: f32 = 0.44 * SPACE;
+
+const FONT_REGULAR: Name = Name(b"F1");
+const FONT_BOLD: Name = Name(b"F2");
+
+/// a measure's natural horizontal layout before justification
+struct MeasureLayout {
+    /// x offset of each event position, relative to the measure start
+    columns: Vec<(u32, f32)>,
+    width: f32,
+}
+
+struct SystemLayout {
+    measures: Vec<usize>,
+    page: usize,
+    top: f32,
+    scale: f32,
+}
+
+/// resolved position of a note for tie drawing
+#[derive(Clone, Copy)]
+struct TieAnchor {
+    page: usize,
+    system: usize,
+    x: f32,
+    y: f32,
+    below: bool,
+    system_start: f32,
+    system_end: f32,
+}
+
+/// content stream wrapper that takes top-down page coordinates
+struct Painter {
+    content: Content,
+}
+
+impl Painter {
+    fn new() -> Self {
+        Self {
+            content: Content::new(),
+        }
+    }
+
+    fn y(y: f32) -> f32 {
+        PAGE_HEIGHT - y
+    }
+
+    fn line(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, width: f32) {
+        self.content
+            .set_line_width(width)
+            .move_to(x1, Self::y(y1))
+            .line_to(x2, Self::y(y2))
+            .stroke();
+    }
+
+    /// filled quadrilateral, used for beams and thick strokes with slope
+    fn parallelogram(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, thickness: f32) {
+        self.content
+            .move_to(x1, Self::y(y1))
+            .line_to(x2, Self::y(y2))
+            .line_to(x2, Self::y(y2 + thickness))
+            .line_to(x1, Self::y(y1 + thickness))
+            .close_path()
+            .fill_nonzero();
+    }
+
+    fn rect(&mut self, x: f32, y: f32, width: f32, height: f32) {
+        self.content
+            .rect(x, Self::y(y + height), width, height)
+            .fill_nonzero();
+    }
+
+    /// appends an ellipse path centred on the origin of the current transform
+    fn ellipse_path(&mut self, rx: f32, ry: f32) {
+        // bezier circle approximation constant
+        const K: f32 = 0.552_284_8;
+        self.content
+            .move_to(rx, 0.0)
+            .cubic_to(rx, ry * K, rx * K, ry, 0.0, ry)
+            .cubic_to(-rx * K, ry, -rx, ry * K, -rx, 0.0)
+            .cubic_to(-rx, -ry * K, -rx * K, -ry, 0.0, -ry)
+            .cubic_to(rx * K, -ry, rx, -ry * K, rx, 0.0)
+            .close_path();
+    }
+
+    fn dot(&mut self, x: f32, y: f32, radius: f32) {
+        self.content.save_state();
+        self.content.transform([1.0, 0.0, 0.0, 1.0, x, Self::y(y)]);
+        self.ellipse_path(radius, radius);
+        self.content.fill_nonzero();
+        self.content.restore_state();
+    }
+
+    fn notehead(&mut self, x: f32, y: f32, note_type: NoteType) {
+        self.content.save_state();
+        match note_type {
+            NoteType::Whole => {
+                self.content.transform([1.0, 0.0, 0.0, 1.0, x, Self::y(y)]);
+                self.ellipse_path(NOTEHEAD_RX * 1.3, NOTEHEAD_RY * 1.15);
+                // inner hole leans the other way, like an engraved whole note
+                let (s, c) = (-1.0f32).sin_cos();
+                self.content.transform([c, s, -s, c, 0.0, 0.0]);
+                self.ellipse_path(NOTEHEAD_RX * 0.62, NOTEHEAD_RY * 0.72);
+                self.content.fill_even_odd();
+            }
+            NoteType::Half => {
+                let (s, c) = 0.35f32.sin_cos();
+                self.content.transform([c, s, -s, c, x, Self::y(y)]);
+                self.ellipse_path(NOTEHEAD_RX * 1.05, NOTEHEAD_RY * 1.05);
+                self.ellipse_path(NOTEHEAD_RX * 0.85, NOTEHEAD_RY * 0.45);
+                self.content.fill_even_odd();
+            }
+            _ => {
+                let (s, c) = 0.35f32.sin_cos();
+                self.content.transform([c, s, -s, c, x, Self::y(y)]);
+                self.ellipse_path(NOTEHEAD_RX * 1.05, NOTEHEAD_RY * 1.05);
+                self.content.fill_nonzero();
+            }
+        }
+        self.content.restore_state();
+    }
+
+    /// smooth stroked curve through the given points (catmull-rom converted to bezier)
+    fn curve(&mut self, points: &[(f32, f32)], width: f32) {
+        if points.len() < 2 {
+            return;
+        }
+        self.content
+            .set_line_width(width)
+            .move_to(points[0].0, Self::y(points[0].1));
+        for i in 0..points.len() - 1 {
+            let p0 = points[i.saturating_sub(1)];
+            let p1 = points[i];
+            let p2 = points[i + 1];
+            let p3 = points[(i + 2).min(points.len() - 1)];
+            let c1 = (p1.0 + (p2.0 - p0.0) / 6.0, p1.1 + (p2.1 - p0.1) / 6.0);
+            let c2 = (p2.0 - (p3.0 - p1.0) / 6.0, p2.1 - (p3.1 - p1.1) / 6.0);
+            self.content.cubic_to(
+                c1.0,
+                Self::y(c1.1),
+                c2.0,
+                Self::y(c2.1),
+                p2.0,
+                Self::y(p2.1),
+            );
+        }
+        self.content.stroke();
+    }
+
+    /// filled crescent between two points bulging up or down
+    fn tie(&mut self, x1: f32, x2: f32, y: f32, below: bool) {
+        let dir = if below { 1.0 } else { -1.0 };
+        let height = (0.25 * (x2 - x1)).clamp(0.5 * SPACE, 1.1 * SPACE) * dir;
+        let thickness = 0.18 * SPACE * dir;
+        let dx = (x2 - x1) / 4.0;
+        self.content
+            .move_to(x1, Self::y(y))
+            .cubic_to(
+                x1 + dx,
+                Self::y(y + height),
+                x2 - dx,
+                Self::y(y + height),
+                x2,
+                Self::y(y),
+            )
+            .cubic_to(
+                x2 - dx,
+                Self::y(y + height - thickness),
+                x1 + dx,
+                Self::y(y + height - thickness),
+                x1,
+                Self::y(y),
+            )
+            .close_path()
+            .fill_nonzero();
+    }
+
+    fn text(&mut self, x: f32, y: f32, size: f32, font: Name, text: &str) {
+        // standard type1 fonts only cover latin-1, keep it to printable ascii
+        let bytes: Vec<u8> = text
+            .chars()
+            .map(|c| {
+                if c.is_ascii_graphic() || c == ' ' {
+                    c as u8
+                } else {
+                    b'?'
+                }
+            })
+            .collect();
+        self.content
+            .begin_text()
+            .set_font(font, size)
+            .next_line(x, Self::y(y))
+            .show(Str(&bytes))
+            .end_text();
+    }
+
+    /// approximate helvetica advance width, good enough for centring
+    fn text_width(text: &str, size: f32) -> f32 {
+        text.chars().count() as f32 * size * 0.55
+    }
+
+    fn sharp(&mut self, x: f32, y: f32) {
+        let s = SPACE;
+        self.line(
+            x - 0.22 * s,
+            y - 1.2 * s,
+            x - 0.22 * s,
+            y + 1.4 * s,
+            0.12 * s,
+        );
+        self.line(
+            x + 0.22 * s,
+            y - 1.4 * s,
+            x + 0.22 * s,
+            y + 1.2 * s,
+            0.12 * s,
+        );
+        self.parallelogram(x - 0.5 * s, y - 0.3 * s, x + 0.5 * s, y - 0.6 * s, 0.3 * s);
+        self.parallelogram(x - 0.5 * s, y + 0.5 * s, x + 0.5 * s, y + 0.2 * s, 0.3 * s);
+    }
+
+    fn flat(&mut self, x: f32, y: f32) {
+        let s = SPACE;
+        self.line(
+            x - 0.3 * s,
+            y - 1.9 * s,
+            x - 0.3 * s,
+            y + 0.55 * s,
+            0.12 * s,
+        );
+        self.curve(
+            &[
+                (x - 0.3 * s, y - 0.05 * s),
+                (x + 0.25 * s, y - 0.45 * s),
+                (x + 0.4 * s, y - 0.05 * s),
+                (x - 0.3 * s, y + 0.5 * s),
+            ],
+            0.2 * s,
+        );
+    }
+
+    fn natural(&mut self, x: f32, y: f32) {
+        let s = SPACE;
+        self.line(
+            x - 0.22 * s,
+            y - 1.4 * s,
+            x - 0.22 * s,
+            y + 0.55 * s,
+            0.12 * s,
+        );
+        self.line(
+            x + 0.22 * s,
+            y - 0.55 * s,
+            x + 0.22 * s,
+            y + 1.4 * s,
+            0.12 * s,
+        );
+        self.parallelogram(
+            x - 0.22 * s,
+            y - 0.35 * s,
+            x + 0.22 * s,
+            y - 0.55 * s,
+            0.3 * s,
+        );
+        self.parallelogram(
+            x - 0.22 * s,
+            y + 0.35 * s,
+            x + 0.22 * s,
+            y + 0.15 * s,
+            0.3 * s,
+        );
+    }
+
+    fn accidental(&mut self, x: f32, y: f32, alter: i8) {
+        match alter {
+            a if a > 0 => self.sharp(x, y),
+            a if a < 0 => self.flat(x, y),
+            _ => self.natural(x, y),
+        }
+    }
+
+    /// g clef curling around the second line (`g_line` is its y)
+    fn treble_clef(&mut self, x: f32, g_line: f32) {
+        let s = SPACE;
+        let p = |dx: f32, dy: f32| (x + dx * s, g_line - dy * s);
+        self.curve(
+            &[
+                p(0.15, -0.1),
+                p(-0.35, 0.3),
+                p(0.05, 0.85),
+                p(0.8, 0.45),
+                p(0.85, -0.45),
+                p(0.15, -1.0),
+                p(-0.8, -0.55),
+                p(-0.85, 0.6),
+                p(0.0, 1.7),
+                p(0.6, 2.8),
+                p(0.45, 3.9),
+                p(0.0, 3.4),
+                p(-0.1, 2.3),
+                p(0.15, -0.3),
+                p(0.3, -2.3),
+                p(-0.05, -2.9),
+                p(-0.5, -2.55),
+            ],
+            0.2 * s,
+        );
+        let (dx, dy) = p(-0.35, -2.4);
+        self.dot(dx, dy, 0.32 * s);
+    }
+
+    /// f clef with its dots either side of the fourth line (`f_line` is its y)
+    fn bass_clef(&mut self, x: f32, f_line: f32) {
+        let s = SPACE;
+        let p = |dx: f32, dy: f32| (x + dx * s, f_line - dy * s);
+        let (bx, by) = p(-0.55, 0.0);
+        self.dot(bx, by, 0.35 * s);
+        self.curve(
+            &[
+                p(-0.7, 0.2),
+                p(-0.25, 0.85),
+                p(0.55, 0.8),
+                p(0.95, 0.0),
+                p(0.6, -1.1),
+                p(-0.6, -2.1),
+            ],
+            0.26 * s,
+        );
+        let (dx, dy) = p(1.45, 0.5);
+        self.dot(dx, dy, 0.15 * s);
+        let (dx, dy) = p(1.45, -0.5);
+        self.dot(dx, dy, 0.15 * s);
+    }
+
+    fn whole_rest(&mut self, x: f32, line: f32) {
+        self.rect(x - 0.6 * SPACE, line, 1.2 * SPACE, 0.5 * SPACE);
+    }
+
+    fn half_rest(&mut self, x: f32, line: f32) {
+        self.rect(
+            x - 0.6 * SPACE,
+            line - 0.5 * SPACE,
+            1.2 * SPACE,
+            0.5 * SPACE,
+        );
+    }
+
+    fn quarter_rest(&mut self, x: f32, middle: f32) {
+        let s = SPACE;
+        let p = |dx: f32, dy: f32| (x + dx * s, middle + dy * s);
+        self.curve(&[p(-0.25, -1.5), p(0.3, -0.8), p(-0.2, -0.2)], 0.22 * s);
+        self.parallelogram(
+            p(-0.2, -0.2).0,
+            p(-0.2, -0.2).1,
+            p(0.3, 0.45).0,
+            p(0.3, 0.45).1,
+            0.25 * s,
+        );
+        self.curve(
+            &[p(0.3, 0.55), p(-0.3, 0.45), p(-0.2, 1.0), p(0.05, 1.4)],
+            0.2 * s,
+        );
+    }
+
+    /// eighth (1 flag) or sixteenth (2 flags) rest
+    fn flagged_rest(&mut self, x: f32, middle: f32, flags: u32) {
+        let s = SPACE;
+        let top = middle - 1.0 * s;
+        let bottom = top + (1.6 + flags as f32 * 0.9) * s;
+        self.line(x + 0.45 * s, top, x - 0.1 * s, bottom, 0.14 * s);
+        for i in 0..flags {
+            let fy = top + i as f32 * s;
+            let fx = x + 0.45 * s - i as f32 * 0.3 * s;
+            self.dot(fx - 0.65 * s, fy + 0.05 * s, 0.24 * s);
+            self.curve(
+                &[
+                    (fx - 0.65 * s, fy + 0.25 * s),
+                    (fx - 0.2 * s, fy + 0.3 * s),
+                    (fx, fy),
+                ],
+                0.12 * s,
+            );
+        }
+    }
+
+    /// flags hanging off an unbeamed stem end
+    fn flags(&mut self, x: f32, stem_end: f32, up: bool, count: u32) {
+        let s = SPACE;
+        let dir = if up { 1.0 } else { -1.0 };
+        for i in 0..count {
+            let y = stem_end + dir * i as f32 * 0.85 * s;
+            self.curve(
+                &[
+                    (x, y),
+                    (x + 0.35 * s, y + dir * 0.9 * s),
+                    (x + 0.95 * s, y + dir * 1.6 * s),
+                    (x + 0.7 * s, y + dir * 2.6 * s),
+                ],
+                0.22 * s,
+            );
+        }
+    }
+}
+
+/// vertical position of a staff step (0 = bottom line, 8 = top line)
+fn step_y(staff_top: f32, step: i32) -> f32 {
+    staff_top + STAFF_HEIGHT - step as f32 * SPACE / 2.0
+}
+
+/// horizontal room a column gets for a given duration in grid units
+fn duration_width(units: u32) -> f32 {
+    SPACE * (1.6 + 1.4 * (1.0 + units as f32).log2())
+}
+
+fn header_width(score: &Score, with_time_signature: bool) -> f32 {
+    let key = score.key_signature.fifths.unsigned_abs() as f32;
+    let mut width = 3.6 * SPACE + key * 1.1 * SPACE;
+    if key > 0.0 {
+        width += 0.6 * SPACE;
+    }
+    if with_time_signature {
+        width += 2.8 * SPACE;
+    }
+    width
+}
+
+fn layout_measure(score: &Score, measure_idx: usize) -> MeasureLayout {
+    let measure = &score.measures[measure_idx];
+
+    let mut positions: Vec<u32> = measure
+        .staves
+        .iter()
+        .flat_map(|staff| staff.events.iter())
+        .filter(|e| !e.measure_rest)
+        .map(|e| e.position)
+        .collect();
+    positions.sort_unstable();
+    positions.dedup();
+
+    if positions.is_empty() {
+        return MeasureLayout {
+            columns: Vec::new(),
+            width: 10.0 * SPACE,
+        };
+    }
+
+    let has_accidental = |position: u32| {
+        measure.staves.iter().any(|staff| {
+            staff
+                .events
+                .iter()
+                .any(|e| e.position == position && e.notes.iter().any(|n| n.accidental.is_some()))
+        })
+    };
+
+    let mut columns = Vec::with_capacity(positions.len());
+    let mut x = 1.2 * SPACE;
+    for (i, &position) in positions.iter().enumerate() {
+        if has_accidental(position) {
+            x += 1.3 * SPACE;
+        }
+        columns.push((position, x));
+        let next = positions
+            .get(i + 1)
+            .copied()
+            .unwrap_or(score.measure_length);
+        x += duration_width(next - position);
+    }
+
+    MeasureLayout {
+        columns,
+        width: x + 0.4 * SPACE,
+    }
+}
+
+/// breaks measures into justified systems and distributes systems over pages
+fn layout_systems(score: &Score, measures: &[MeasureLayout]) -> Vec<SystemLayout> {
+    let line_width = PAGE_WIDTH - MARGIN_LEFT - MARGIN_RIGHT;
+    let mut systems = Vec::new();
+    let mut current: Vec<usize> = Vec::new();
+    let mut current_width = 0.0;
+
+    let available = |first: bool| line_width - header_width(score, first);
+
+    for (idx, layout) in measures.iter().enumerate() {
+        let limit = available(systems.is_empty());
+        if !current.is_empty() && current_width + layout.width > limit {
+            systems.push((std::mem::take(&mut current), current_width, limit));
+            current_width = 0.0;
+        }
+        current.push(idx);
+        current_width += layout.width;
+    }
+    if !current.is_empty() {
+        let limit = available(systems.is_empty());
+        systems.push((current, current_width, limit));
+    }
+
+    let n_systems = systems.len();
+    let mut page = 0;
+    let mut top = MARGIN_TOP + TITLE_HEIGHT;
+
+    systems
+        .into_iter()
+        .enumerate()
+        .map(|(i, (measures, width, limit))| {
+            if top + SYSTEM_HEIGHT > PAGE_HEIGHT - MARGIN_BOTTOM {
+                page += 1;
+                top = MARGIN_TOP + 2.0 * SPACE;
+            }
+
+            // stretch to the margin, except a sparse last line
+            let mut scale = limit / width.max(1.0);
+            if i == n_systems - 1 && scale > 1.4 {
+                scale = 1.0;
+            }
+
+            let system = SystemLayout {
+                measures,
+                page,
+                top,
+                scale,
+            };
+            top += SYSTEM_HEIGHT + SYSTEM_GAP;
+            system
+        })
+        .collect()
+}
+
+/// renders a quantized score to a multi-page pdf with a grand staff per system.
+/// progress_cb receives (systems done, total systems)
+pub fn engrave_pdf<F>(
+    score: &Score,
+    title: &str,
+    output: &Path,
+    cancel: &CancellationToken,
+    mut progress_cb: F,
+) -> Result<()>
+where
+    F: FnMut(u32, u32),
+{
+    let measure_layouts: Vec<MeasureLayout> = (0..score.measures.len())
+        .map(|idx| layout_measure(score, idx))
+        .collect();
+    let systems = layout_systems(score, &measure_layouts);
+    let n_pages = systems.last().map_or(1, |s| s.page + 1);
+
+    println!(
+        "engraving {} measures in {} systems on {} pages",
+        score.measures.len(),
+        systems.len(),
+        n_pages
+    );
+
+    let mut painters: Vec<Painter> = (0..n_pages).map(|_| Painter::new()).collect();
+
+    // title block and page numbers
+    painters[0].text(
+        (PAGE_WIDTH - Painter::text_width(title, 18.0)) / 2.0,
+        MARGIN_TOP + 18.0,
+        18.0,
+        FONT_BOLD,
+        title,
+    );
+    for (page, painter) in painters.iter_mut().enumerate().skip(1) {
+        let label = format!("{}", page + 1);
+        painter.text(
+            (PAGE_WIDTH - Painter::text_width(&label, 9.0)) / 2.0,
+            PAGE_HEIGHT - MARGIN_BOTTOM / 2.0,
+            9.0,
+            FONT_REGULAR,
+            &label,
+        );
+    }
+
+    // tie anchors per staff in reading order, resolved after all systems are drawn
+    let mut tie_starts: Vec<HashMap<u8, TieAnchor>> = vec![HashMap::new(), HashMap::new()];
+    let mut ties: Vec<(TieAnchor, TieAnchor)> = Vec::new();
+
+    for (system_idx, system) in systems.iter().enumerate() {
+        cancel.check()?;
+
+        let painter = &mut painters[system.page];
+        let first_system = system_idx == 0;
+        let staff_tops = [system.top, system.top + STAFF_HEIGHT + STAFF_GAP];
+        let left = MARGIN_LEFT;
+        let content_width: f32 = system
+            .measures
+            .iter()
+            .map(|&m| measure_layouts[m].width * system.scale)
+            .sum();
+        let right = left + header_width(score, first_system) + content_width;
+
+        draw_system_frame(
+            painter,
+            score,
+            system,
+            first_system,
+            &staff_tops,
+            left,
+            right,
+        );
+
+        let mut measure_x = left + header_width(score, first_system);
+        for &measure_idx in &system.measures {
+            let layout = &measure_layouts[measure_idx];
+            let measure = &score.measures[measure_idx];
+            let width = layout.width * system.scale;
+
+            for (staff_idx, staff) in measure.staves.iter().enumerate() {
+                let staff_top = staff_tops[staff_idx];
+                let column_x = |position: u32| {
+                    layout
+                        .columns
+                        .iter()
+                        .find(|(p, _)| *p == position)
+                        .map_or(measure_x + width / 2.0, |(_, x)| {
+                            measure_x + x * system.scale
+                        })
+                };
+
+                let anchors = draw_staff_events(
+                    painter,
+                    &staff.events,
+                    staff.clef,
+                    staff_top,
+                    measure_x,
+                    width,
+                    &column_x,
+                );
+
+                for (event_idx, note_idx, x, y, below) in anchors {
+                    let note = &staff.events[event_idx].notes[note_idx];
+                    let anchor = TieAnchor {
+                        page: system.page,
+                        system: system_idx,
+                        x,
+                        y,
+                        below,
+                        system_start: left + header_width(score, first_system) - SPACE,
+                        system_end: right,
+                    };
+                    if note.tie_stop {
+                        if let Some(start) = tie_starts[staff_idx].remove(&note.midi) {
+                            ties.push((start, anchor));
+                        }
+                    }
+                    if note.tie_start {
+                        tie_starts[staff_idx].insert(note.midi, anchor);
+                    }
+                }
+            }
+
+            measure_x += width;
+
+            // barline through the grand staff, final barline at the end
+            if measure_idx == score.measures.len() - 1 {
+                painter.line(
+                    measure_x - 0.9 * SPACE,
+                    staff_tops[0],
+                    measure_x - 0.9 * SPACE,
+                    staff_tops[1] + STAFF_HEIGHT,
+                    0.16 * SPACE,
+                );
+                painter.rect(
+                    measure_x - 0.5 * SPACE,
+                    staff_tops[0],
+                    0.5 * SPACE,
+                    staff_tops[1] + STAFF_HEIGHT - staff_tops[0],
+                );
+            } else {
+                painter.line(
+                    measure_x,
+                    staff_tops[0],
+                    measure_x,
+                    staff_tops[1] + STAFF_HEIGHT,
+                    0.16 * SPACE,
+                );
+            }
+        }
+
+        progress_cb((system_idx + 1) as u32, systems.len() as u32);
+    }
+
+    for (start, end) in ties {
+        let gap = NOTEHEAD_RX + 0.2 * SPACE;
+        let offset = if start.below {
+            0.7 * SPACE
+        } else {
+            -0.7 * SPACE
+        };
+        if start.system == end.system {
+            painters[start.page].tie(start.x + gap, end.x - gap, start.y + offset, start.below);
+        } else {
+            // tie broken over a line: half ties to the edge and from the next header
+            painters[start.page].tie(
+                start.x + gap,
+                start.system_end,
+                start.y + offset,
+                start.below,
+            );
+            painters[end.page].tie(end.system_start, end.x - gap, end.y + offset, start.below);
+        }
+    }
+
+    write_pdf(painters, title, output)
+}
+
+/// staff lines, brace, clefs, key and time signature and the measure number
+fn draw_system_frame(
+    painter: &mut Painter,
+    score: &Score,
+    system: &SystemLayout,
+    first_system: bool,
+    staff_tops: &[f32; 2],
+    left: f32,
+    right: f32,
+) {
+    for &staff_top in staff_tops {
+        for line in 0..5 {
+            let y = staff_top + line as f32 * SPACE;
+            painter.line(left, y, right, y, STAFF_LINE_WIDTH);
+        }
+    }
+
+    let bottom = staff_tops[1] + STAFF_HEIGHT;
+    painter.line(left, staff_tops[0], left, bottom, 0.16 * SPACE);
+
+    // brace: two mirrored curves meeting at the middle of the gap
+    let middle = (staff_tops[0] + bottom) / 2.0;
+    let bx = left - 0.9 * SPACE;
+    painter.curve(
+        &[
+            (bx + 0.5 * SPACE, staff_tops[0]),
+            (bx - 0.2 * SPACE, staff_tops[0] + 4.0 * SPACE),
+            (bx + 0.2 * SPACE, middle - 3.0 * SPACE),
+            (bx - 0.6 * SPACE, middle),
+        ],
+        0.3 * SPACE,
+    );
+    painter.curve(
+        &[
+            (bx - 0.6 * SPACE, middle),
+            (bx + 0.2 * SPACE, middle + 3.0 * SPACE),
+            (bx - 0.2 * SPACE, bottom - 4.0 * SPACE),
+            (bx + 0.5 * SPACE, bottom),
+        ],
+        0.3 * SPACE,
+    );
+
+    let mut x = left + 1.6 * SPACE;
+    painter.treble_clef(x, step_y(staff_tops[0], 2));
+    painter.bass_clef(x, step_y(staff_tops[1], 6));
+    x += 2.6 * SPACE;
+
+    // key signature at the conventional heights, bass clef sits one step... two steps lower
+    const SHARP_STEPS: [i32; 7] = [8, 5, 9, 6, 3, 7, 4];
+    const FLAT_STEPS: [i32; 7] = [4, 7, 3, 6, 2, 5, 1];
+    let fifths = score.key_signature.fifths;
+    let count = fifths.unsigned_abs().min(7) as usize;
+    for i in 0..count {
+        let step = if fifths > 0 {
+            SHARP_STEPS[i]
+        } else {
+            FLAT_STEPS[i]
+        };
+        for (staff_idx, &staff_top) in staff_tops.iter().enumerate() {
+            let step = if staff_idx == 0 { step } else { step - 2 };
+            painter.accidental(x, step_y(staff_top, step), fifths.signum());
+        }
+        x += 1.1 * SPACE;
+    }
+    if count > 0 {
+        x += 0.6 * SPACE;
+    }
+
+    if first_system {
+        let numerator = score.time_signature.numerator.to_string();
+        let denominator = score.time_signature.denominator.to_string();
+        let size = 2.9 * SPACE;
+        for &staff_top in staff_tops {
+            painter.text(
+                x + 1.0 * SPACE - Painter::text_width(&numerator, size) / 2.0,
+                staff_top + 2.0 * SPACE - 0.05 * SPACE,
+                size,
+                FONT_BOLD,
+                &numerator,
+            );
+            painter.text(
+                x + 1.0 * SPACE - Painter::text_width(&denominator, size) / 2.0,
+                staff_top + 4.0 * SPACE - 0.05 * SPACE,
+                size,
+                FONT_BOLD,
+                &denominator,
+            );
+        }
+
+        // tempo as a quarter note = bpm above the first system
+        let tempo_y = staff_tops[0] - 2.5 * SPACE;
+        painter.notehead(left + SPACE, tempo_y, NoteType::Quarter);
+        painter.line(
+            left + SPACE + NOTEHEAD_RX,
+            tempo_y,
+            left + SPACE + NOTEHEAD_RX,
+            tempo_y - 2.8 * SPACE,
+            STEM_WIDTH,
+        );
+        painter.text(
+            left + 2.4 * SPACE,
+            tempo_y + 0.5 * SPACE,
+            9.0,
+            FONT_BOLD,
+            &format!("= {}", score.tempo_bpm.round()),
+        );
+    } else if let Some(&first) = system.measures.first() {
+        let number = score.measures[first].number.to_string();
+        painter.text(
+            left,
+            staff_tops[0] - 1.2 * SPACE,
+            8.0,
+            FONT_REGULAR,
+            &number,
+        );
+    }
+}
+
+/// geometry of a note or chord before drawing, beams need all of a group first
+struct ChordGeometry {
+    x: f32,
+    /// (note index, staff step), sorted low to high
+    steps: Vec<(usize, i32)>,
+    stem_up: bool,
+    stem_x: f32,
+    /// y where the stem leaves the notehead furthest from the stem end
+    stem_base: f32,
+    stem_end: f32,
+}
+
+/// draws one staff's events in a measure. returns (event, note, x, y, tie below)
+/// for every notehead so ties can be connected afterwards
+fn draw_staff_events(
+    painter: &mut Painter,
+    events: &[Event],
+    clef: Clef,
+    staff_top: f32,
+    measure_x: f32,
+    measure_width: f32,
+    column_x: &dyn Fn(u32) -> f32,
+) -> Vec<(usize, usize, f32, f32, bool)> {
+    let middle = step_y(staff_top, 4);
+    let mut anchors = Vec::new();
+
+    // first pass: stems for every chord, using the group direction for beams
+    let mut geometry: Vec<Option<ChordGeometry>> = events
+        .iter()
+        .map(|event| {
+            if event.is_rest() {
+                return None;
+            }
+            let mut steps: Vec<(usize, i32)> = event
+                .notes
+                .iter()
+                .enumerate()
+                .map(|(i, n)| (i, n.pitch.diatonic() - clef.bottom_line()))
+                .collect();
+            steps.sort_by_key(|&(_, step)| step);
+            let low = steps[0].1;
+            let high = steps[steps.len() - 1].1;
+            Some(chord_geometry(
+                column_x(event.position),
+                steps,
+                (high - 4) > (4 - low),
+                event,
+                staff_top,
+            ))
+        })
+        .collect();
+
+    let mut groups: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (idx, event) in events.iter().enumerate() {
+        if let Some(group) = event.beam_group {
+            groups.entry(group).or_default().push(idx);
+        }
+    }
+
+    for members in groups.values() {
+        // common direction: the side with more notes away from the middle line wins
+        let balance: i32 = members
+            .iter()
+            .filter_map(|&i| geometry[i].as_ref())
+            .map(|g| g.steps[0].1 + g.steps[g.steps.len() - 1].1 - 8)
+            .sum();
+        let stem_up = balance <= 0;
+        for &i in members {
+            let steps = geometry[i].as_ref().unwrap().steps.clone();
+            geometry[i] = Some(chord_geometry(
+                column_x(events[i].position),
+                steps,
+                !stem_up,
+                &events[i],
+                staff_top,
+            ));
+        }
+        fit_beam(&mut geometry, members, events);
+    }
+
+    for (idx, event) in events.iter().enumerate() {
+        let Some(geom) = &geometry[idx] else {
+            draw_rest(
+                painter,
+                event,
+                column_x(event.position),
+                measure_x,
+                measure_width,
+                middle,
+            );
+            continue;
+        };
+
+        let note_type = event.value.note_type;
+
+        // seconds in a chord push one head to the other side of the stem
+        let mut head_x: Vec<f32> = vec![geom.x; event.notes.len()];
+        let head_offset = 2.0 * NOTEHEAD_RX * if geom.stem_up { 1.0 } else { -1.0 };
+        let ordered: Vec<(usize, i32)> = if geom.stem_up {
+            geom.steps.clone()
+        } else {
+            geom.steps.iter().rev().copied().collect()
+        };
+        let mut previous: Option<(i32, bool)> = None;
+        for &(note_idx, step) in &ordered {
+            let shifted = matches!(previous, Some((prev, false)) if (prev - step).abs() == 1);
+            if shifted {
+                head_x[note_idx] += head_offset;
+            }
+            previous = Some((step, shifted));
+        }
+
+        // ledger lines above and below the staff
+        let low = geom.steps[0].1;
+        let high = geom.steps[geom.steps.len() - 1].1;
+        let ledger_half = NOTEHEAD_RX * 1.6;
+        let mut step = -2;
+        while step >= low {
+            let y = step_y(staff_top, step);
+            painter.line(
+                geom.x - ledger_half,
+                y,
+                geom.x + ledger_half,
+                y,
+                STAFF_LINE_WIDTH,
+            );
+            step -= 2;
+        }
+        let mut step = 10;
+        while step <= high {
+            let y = step_y(staff_top, step);
+            painter.line(
+                geom.x - ledger_half,
+                y,
+                geom.x + ledger_half,
+                y,
+                STAFF_LINE_WIDTH,
+            );
+            step += 2;
+        }
+
+        // accidentals, staggered leftwards when they would collide
+        let mut accidental_x = geom.x - NOTEHEAD_RX - 0.9 * SPACE;
+        let mut last_step: Option<i32> = None;
+        for &(note_idx, step) in geom.steps.iter().rev() {
+            if let Some(alter) = event.notes[note_idx].accidental {
+                if last_step.is_some_and(|last| last - step < 6) {
+                    accidental_x -= 1.1 * SPACE;
+                } else {
+                    accidental_x = geom.x - NOTEHEAD_RX - 0.9 * SPACE;
+                }
+                painter.accidental(accidental_x, step_y(staff_top, step), alter);
+                last_step = Some(step);
+            }
+        }
+
+        for (rank, &(note_idx, step)) in geom.steps.iter().enumerate() {
+            let y = step_y(staff_top, step);
+            painter.notehead(head_x[note_idx], y, note_type);
+
+            if event.value.dotted {
+                // dots sit in a space
+                let dot_step = if step % 2 == 0 { step + 1 } else { step };
+                painter.dot(
+                    geom.x + NOTEHEAD_RX + 0.7 * SPACE,
+                    step_y(staff_top, dot_step),
+                    0.2 * SPACE,
+                );
+            }
+
+            // single notes tie away from the stem, chords split into lower and upper halves
+            let below = if geom.steps.len() > 1 {
+                2 * rank < geom.steps.len()
+            } else {
+                geom.stem_up
+            };
+            anchors.push((idx, note_idx, head_x[note_idx], y, below));
+        }
+
+        if note_type != NoteType::Whole {
+            painter.line(
+                geom.stem_x,
+                geom.stem_base,
+                geom.stem_x,
+                geom.stem_end,
+                STEM_WIDTH,
+            );
+            if event.beam_group.is_none() && note_type.beams() > 0 {
+                painter.flags(geom.stem_x, geom.stem_end, geom.stem_up, note_type.beams());
+            }
+        }
+    }
+
+    for members in groups.values() {
+        draw_beams(painter, &geometry, members, events);
+    }
+
+    anchors
+}
+
+fn chord_geometry(
+    x: f32,
+    steps: Vec<(usize, i32)>,
+    stem_down: bool,
+    event: &Event,
+    staff_top: f32,
+) -> ChordGeometry {
+    let low = step_y(staff_top, steps[0].1);
+    let high = step_y(staff_top, steps[steps.len() - 1].1);
+    let middle = step_y(staff_top, 4);
+    let extra = if event.value.note_type.beams() > 1 {
+        0.5 * SPACE
+    } else {
+        0.0
+    };
+
+    if stem_down {
+        ChordGeometry {
+            x,
+            steps,
+            stem_up: false,
+            stem_x: x - NOTEHEAD_RX * 0.98,
+            stem_base: high + 0.15 * SPACE,
+            // stems reach at least the middle line
+            stem_end: (low + STEM_LENGTH + extra).max(middle),
+        }
+    } else {
+        ChordGeometry {
+            x,
+            steps,
+            stem_up: true,
+            stem_x: x + NOTEHEAD_RX * 0.98,
+            stem_base: low - 0.15 * SPACE,
+            stem_end: (high - STEM_LENGTH - extra).min(middle),
+        }
+    }
+}
+
+/// slopes the beam between the outer notes (at most one space) and moves it
+/// away from the heads until every stem keeps a minimum length
+fn fit_beam(geometry: &mut [Option<ChordGeometry>], members: &[usize], events: &[Event]) {
+    let first = geometry[members[0]].as_ref().unwrap();
+    let last = geometry[members[members.len() - 1]].as_ref().unwrap();
+    let stem_up = first.stem_up;
+    let (x1, x2) = (first.stem_x, last.stem_x);
+    let rise = (last.stem_end - first.stem_end).clamp(-SPACE, SPACE);
+    let slope = if x2 > x1 { rise / (x2 - x1) } else { 0.0 };
+
+    let max_beams = members
+        .iter()
+        .map(|&i| events[i].value.note_type.beams())
+        .max()
+        .unwrap_or(1);
+    let min_length = 2.8 * SPACE + (max_beams.saturating_sub(1)) as f32 * BEAM_SPACING;
+
+    let mut base = first.stem_end;
+    for &i in members {
+        let g = geometry[i].as_ref().unwrap();
+        let beam_y = base + slope * (g.stem_x - x1);
+        // the head nearest the beam bounds how short the stem may get
+        let nearest = if stem_up {
+            g.stem_base - (g.steps[g.steps.len() - 1].1 - g.steps[0].1) as f32 * SPACE / 2.0
+        } else {
+            g.stem_base + (g.steps[g.steps.len() - 1].1 - g.steps[0].1) as f32 * SPACE / 2.0
+        };
+        if stem_up && beam_y > nearest - min_length {
+            base -= beam_y - (nearest - min_length);
+        } else if !stem_up && beam_y < nearest + min_length {
+            base += (nearest + min_length) - beam_y;
+        }
+    }
+
+    for &i in members {
+        let g = geometry[i].as_mut().unwrap();
+        g.stem_end = base + slope * (g.stem_x - x1);
+    }
+}
+
+fn draw_beams(
+    painter: &mut Painter,
+    geometry: &[Option<ChordGeometry>],
+    members: &[usize],
+    events: &[Event],
+) {
+    let chords: Vec<&ChordGeometry> = members
+        .iter()
+        .map(|&i| geometry[i].as_ref().unwrap())
+        .collect();
+    let stem_up = chords[0].stem_up;
+    // beams grow from the stem end towards the heads
+    let dir = if stem_up { 1.0 } else { -1.0 };
+    let thickness = BEAM_THICKNESS * dir;
+    let beam_y = |g: &ChordGeometry, level: u32| g.stem_end + dir * level as f32 * BEAM_SPACING;
+    let half_stem = STEM_WIDTH / 2.0;
+
+    // primary beam across the whole group
+    let (first, last) = (chords[0], chords[chords.len() - 1]);
+    painter.parallelogram(
+        first.stem_x - half_stem,
+        beam_y(first, 0) - if stem_up { 0.0 } else { -thickness },
+        last.stem_x + half_stem,
+        beam_y(last, 0) - if stem_up { 0.0 } else { -thickness },
+        thickness.abs(),
+    );
+
+    // secondary beams between neighbouring sixteenths, stubs for lone ones
+    let beams: Vec<u32> = members
+        .iter()
+        .map(|&i| events[i].value.note_type.beams())
+        .collect();
+    let slope_y = |x: f32, level: u32| {
+        let t = if last.stem_x > first.stem_x {
+            (x - first.stem_x) / (last.stem_x - first.stem_x)
+        } else {
+            0.0
+        };
+        let y0 = beam_y(first, level);
+        let y1 = beam_y(last, level);
+        let y = y0 + (y1 - y0) * t;
+        if stem_up {
+            y
+        } else {
+            y - BEAM_THICKNESS
+        }
+    };
+
+    for i in 0..chords.len() {
+        if beams[i] < 2 {
+            continue;
+        }
+        let x = chords[i].stem_x;
+        if i + 1 < chords.len() && beams[i + 1] >= 2 {
+            let x_next = chords[i + 1].stem_x;
+            painter.parallelogram(
+                x - half_stem,
+                slope_y(x, 1),
+                x_next + half_stem,
+                slope_y(x_next, 1),
+                BEAM_THICKNESS,
+            );
+        } else if (i == 0 || beams[i - 1] < 2) && !(i + 1 < chords.len() && beams[i + 1] >= 2) {
+            // lone sixteenth: stub pointing into the group
+            let stub = 1.1 * SPACE;
+            let (x1, x2) = if i == 0 { (x, x + stub) } else { (x - stub, x) };
+            painter.parallelogram(x1, slope_y(x1, 1), x2, slope_y(x2, 1), BEAM_THICKNESS);
+        }
+    }
+}
+
+fn draw_rest(
+    painter: &mut Painter,
+    event: &Event,
+    x: f32,
+    measure_x: f32,
+    measure_width: f32,
+    middle: f32,
+) {
+    if event.measure_rest {
+        // hangs from the fourth line, centred in the bar
+        painter.whole_rest(measure_x + measure_width / 2.0, middle - SPACE);
+        return;
+    }
+
+    match event.value.note_type {
+        NoteType::Whole => painter.whole_rest(x, middle - SPACE),
+        NoteType::Half => painter.half_rest(x, middle),
+        NoteType::Quarter => painter.quarter_rest(x, middle),
+        NoteType::Eighth => painter.flagged_rest(x, middle, 1),
+        NoteType::Sixteenth => painter.flagged_rest(x, middle, 2),
+    }
+
+    if event.value.dotted {
+        painter.dot(x + 1.2 * SPACE, middle - 0.5 * SPACE, 0.2 * SPACE);
+    }
+}
+
+fn write_pdf(painters: Vec<Painter>, title: &str, output: &Path) -> Result<()> {
+    let catalog_id = Ref::new(1);
+    let page_tree_id = Ref::new(2);
+    let font_regular_id = Ref::new(3);
+    let font_bold_id = Ref::new(4);
+    let info_id = Ref::new(5);
+    let first_page_id = 6;
+
+    let page_ids: Vec<Ref> = (0..painters.len())
+        .map(|i| Ref::new(first_page_id + 2 * i as i32))
+        .collect();
+
+    let mut pdf = Pdf::new();
+    pdf.catalog(catalog_id).pages(page_tree_id);
+    pdf.pages(page_tree_id)
+        .kids(page_ids.iter().copied())
+        .count(page_ids.len() as i32);
+    pdf.type1_font(font_regular_id)
+        .base_font(Name(b"Helvetica"));
+    pdf.type1_font(font_bold_id)
+        .base_font(Name(b"Helvetica-Bold"));
+    pdf.document_info(info_id)
+        .title(TextStr(title))
+        .creator(TextStr("lala"));
+
+    for (page_id, painter) in page_ids.iter().zip(painters) {
+        let content_id = Ref::new(page_id.get() + 1);
+
+        let mut page = pdf.page(*page_id);
+        page.media_box(Rect::new(0.0, 0.0, PAGE_WIDTH, PAGE_HEIGHT));
+        page.parent(page_tree_id);
+        page.contents(content_id);
+        let mut resources = page.resources();
+        let mut fonts = resources.fonts();
+        fonts.pair(FONT_REGULAR, font_regular_id);
+        fonts.pair(FONT_BOLD, font_bold_id);
+        fonts.finish();
+        resources.finish();
+        page.finish();
+
+        pdf.stream(content_id, &painter.content.finish());
+    }
+
+    std::fs::write(output, pdf.finish())?;
+    Ok(())
+}
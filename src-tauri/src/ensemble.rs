@@ -0,0 +1,289 @@
+use crate::cancellation::CancellationToken;
+use crate::demucs_model::{DemucsModel, ModelSpec, MODEL_REGISTRY};
+use crate::separator::{SeparationOptions, Separator};
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use tch::{Device, Tensor};
+
+/// one model of an ensemble. stems not listed in `weights` count with 1.0,
+/// a weight of 0.0 keeps the model out of that stem's average
+#[derive(Debug)]
+pub struct EnsembleMember {
+    pub model: &'static str,
+    pub weights: &'static [(&'static str, f32)],
+}
+
+impl EnsembleMember {
+    fn weight(&self, stem: &str) -> f32 {
+        self.weights
+            .iter()
+            .find(|(name, _)| *name == stem)
+            .map_or(1.0, |(_, weight)| *weight)
+    }
+}
+
+/// several registered models whose stems are averaged, slower but cleaner
+#[derive(Debug)]
+pub struct EnsembleSpec {
+    pub id: &'static str,
+    pub members: &'static [EnsembleMember],
+}
+
+impl EnsembleSpec {
+    /// weights of every member, all must be present to run the ensemble
+    pub fn weights_paths(&self) -> impl Iterator<Item = &'static Path> + '_ {
+        self.members.iter().filter_map(|member| {
+            MODEL_REGISTRY
+                .iter()
+                .find(|spec| spec.id == member.model)
+                .map(|spec| Path::new(spec.path))
+        })
+    }
+
+    /// the registered model of every member, checked to run at one sample rate
+    fn member_specs(&self) -> Result<Vec<&'static ModelSpec>> {
+        let specs = self
+            .members
+            .iter()
+            .map(|member| {
+                MODEL_REGISTRY
+                    .iter()
+                    .find(|s| s.id == member.model)
+                    .ok_or_else(|| anyhow!("unknown ensemble member: {}", member.model))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let Some(first) = specs.first() else {
+            bail!("ensemble {} has no members", self.id);
+        };
+        if specs.iter().any(|s| s.sample_rate != first.sample_rate) {
+            bail!("ensemble {} mixes sample rates", self.id);
+        }
+
+        Ok(specs)
+    }
+
+    /// union of the stems members contribute to, in order of first appearance
+    fn stems(&self) -> Result<Vec<String>> {
+        let mut stems: Vec<String> = Vec::new();
+        for (spec, member) in self.member_specs()?.iter().zip(self.members) {
+            for stem in spec.stems {
+                if member.weight(stem) > 0.0 && !stems.iter().any(|s| s == stem) {
+                    stems.push(stem.to_string());
+                }
+            }
+        }
+        Ok(stems)
+    }
+}
+
+pub const ENSEMBLE_REGISTRY: &[EnsembleSpec] = &[EnsembleSpec {
+    id: "htdemucs_6s+hdemucs",
+    members: &[
+        EnsembleMember {
+            model: "htdemucs_6s",
+            weights: &[],
+        },
+        // hdemucs folds piano and guitar into "other", keep it out of that average
+        EnsembleMember {
+            model: "hdemucs",
+            weights: &[("other", 0.0)],
+        },
+    ],
+}];
+
+pub struct EnsembleSeparator {
+    spec: &'static EnsembleSpec,
+    models: Vec<DemucsModel>,
+    stems: Vec<String>,
+}
+
+impl EnsembleSeparator {
+    pub fn new(spec: &'static EnsembleSpec, device: Device) -> Result<Self> {
+        let stems = spec.stems()?;
+        let models = spec
+            .member_specs()?
+            .into_iter()
+            .map(|model_spec| DemucsModel::new(model_spec, device))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            spec,
+            models,
+            stems,
+        })
+    }
+}
+
+/// running weighted average of the members' stems, only the sums are held
+#[derive(Default)]
+struct StemAverage {
+    sums: HashMap<String, (Tensor, f32)>,
+}
+
+impl StemAverage {
+    fn add(&mut self, member: &EnsembleMember, stems: HashMap<String, Tensor>) {
+        for (name, stem) in stems {
+            let weight = member.weight(&name);
+            if weight <= 0.0 {
+                continue;
+            }
+
+            let weighted = stem * weight as f64;
+            match self.sums.get_mut(&name) {
+                Some((sum, total)) => {
+                    *sum += weighted;
+                    *total += weight;
+                }
+                None => {
+                    self.sums.insert(name, (weighted, weight));
+                }
+            }
+        }
+    }
+
+    fn finish(self) -> HashMap<String, Tensor> {
+        self.sums
+            .into_iter()
+            .map(|(name, (sum, total))| (name, sum / total as f64))
+            .collect()
+    }
+}
+
+impl Separator for EnsembleSeparator {
+    fn stems(&self) -> &[String] {
+        &self.stems
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.models[0].sample_rate()
+    }
+
+    fn device(&self) -> Device {
+        self.models[0].device()
+    }
+
+    /// the longest member's, so every member gets whole segments
+    fn segment_length(&self) -> usize {
+        self.models
+            .iter()
+            .map(|m| m.segment_length())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// weighted average of every member's stems. progress spans all members
+    fn separate(
+        &self,
+        audio: &Tensor,
+        options: &SeparationOptions,
+        cancel: &CancellationToken,
+        progress: &mut dyn FnMut(u32, u32),
+    ) -> Result<HashMap<String, Tensor>> {
+        let n_models = self.models.len() as u32;
+        let mut average = StemAverage::default();
+
+        for (idx, (model, member)) in self.models.iter().zip(self.spec.members).enumerate() {
+            println!(
+                "ensemble {}: running member {}/{} ({})",
+                self.spec.id,
+                idx + 1,
+                n_models,
+                member.model
+            );
+
+            let stems = model.separate(audio, options, cancel, &mut |done, total| {
+                progress(idx as u32 * total + done, n_models * total);
+            })?;
+            average.add(member, stems);
+        }
+
+        Ok(average.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::separator::all_backends;
+    use tch::Kind;
+
+    #[test]
+    fn registered_ensembles_are_runnable() {
+        for spec in ENSEMBLE_REGISTRY {
+            let members = spec.member_specs().unwrap();
+            assert_eq!(members.len(), spec.members.len());
+
+            // weights only name stems their model has
+            for (model, member) in members.iter().zip(spec.members) {
+                for (stem, _) in member.weights {
+                    assert!(model.stems.contains(stem), "{}: {}", spec.id, stem);
+                }
+            }
+            assert!(!spec.stems().unwrap().is_empty());
+        }
+
+        let ids = all_backends().map(|b| b.id()).collect::<Vec<_>>();
+        for (idx, id) in ids.iter().enumerate() {
+            assert!(!ids[idx + 1..].contains(id), "{} is registered twice", id);
+        }
+    }
+
+    #[test]
+    fn zero_weights_leave_stems_out() {
+        static SPEC: EnsembleSpec = EnsembleSpec {
+            id: "test",
+            members: &[EnsembleMember {
+                model: "hdemucs",
+                weights: &[("other", 0.0)],
+            }],
+        };
+        assert_eq!(SPEC.stems().unwrap(), ["drums", "bass", "vocals"]);
+
+        static UNKNOWN: EnsembleSpec = EnsembleSpec {
+            id: "unknown",
+            members: &[EnsembleMember {
+                model: "missing",
+                weights: &[],
+            }],
+        };
+        assert!(UNKNOWN.stems().is_err());
+    }
+
+    #[test]
+    fn stems_are_weighted_averages() {
+        let constant = |value: f64| Tensor::full([2, 4], value, (Kind::Float, Device::Cpu));
+        let plain = EnsembleMember {
+            model: "a",
+            weights: &[],
+        };
+        let weighted = EnsembleMember {
+            model: "b",
+            weights: &[("drums", 3.0), ("bass", 0.0)],
+        };
+
+        let mut average = StemAverage::default();
+        average.add(
+            &plain,
+            HashMap::from([
+                ("drums".to_string(), constant(1.0)),
+                ("bass".to_string(), constant(1.0)),
+            ]),
+        );
+        average.add(
+            &weighted,
+            HashMap::from([
+                ("drums".to_string(), constant(5.0)),
+                ("bass".to_string(), constant(100.0)),
+                ("vocals".to_string(), constant(2.0)),
+            ]),
+        );
+        let stems = average.finish();
+
+        // (1 * 1 + 5 * 3) / (1 + 3), the zero weight keeps 100 out of bass
+        assert!(stems["drums"].allclose(&constant(4.0), 1e-6, 1e-6, false));
+        assert!(stems["bass"].allclose(&constant(1.0), 1e-6, 1e-6, false));
+        assert!(stems["vocals"].allclose(&constant(2.0), 1e-6, 1e-6, false));
+    }
+}
@@ -0,0 +1,724 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// frames per flac frame, the reference encoder's default
+const BLOCK_SIZE: usize = 4096;
+
+/// highest order of the fixed predictors
+const MAX_FIXED_ORDER: usize = 4;
+
+/// highest rice partition order tried, more partitions adapt to louder and
+/// quieter stretches within a block
+const MAX_PARTITION_ORDER: u32 = 6;
+
+/// rice parameters are 4 bits, this value escapes to raw binary
+const RICE_ESCAPE: u32 = 15;
+
+/// stereo flac writer using the fixed predictors and rice coded residuals.
+/// simpler than lpc and still roughly halves the size of pcm wav. input is
+/// integer samples already quantized to `bits`
+pub struct FlacWriter {
+    writer: BufWriter<File>,
+    sample_rate: u32,
+    bits: u32,
+    buffered: [Vec<i32>; 2],
+    frame_number: u64,
+    total_frames: u64,
+    /// channel assignment of every frame, None picks the cheapest per frame
+    assignment: Option<ChannelAssignment>,
+}
+
+impl FlacWriter {
+    pub fn create(path: &Path, sample_rate: u32, bits: u32) -> Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+
+        let mut flac = Self {
+            writer: BufWriter::new(file),
+            sample_rate,
+            bits,
+            buffered: [
+                Vec::with_capacity(BLOCK_SIZE),
+                Vec::with_capacity(BLOCK_SIZE),
+            ],
+            frame_number: 0,
+            total_frames: 0,
+            assignment: None,
+        };
+
+        flac.writer.write_all(b"fLaC")?;
+        // streaminfo is rewritten with the final length once everything is encoded
+        let stream_info = flac.stream_info();
+        flac.writer.write_all(&stream_info)?;
+        Ok(flac)
+    }
+
+    pub fn write(&mut self, left: &[i32], right: &[i32]) -> Result<()> {
+        for (l, r) in left.iter().zip(right) {
+            self.buffered[0].push(*l);
+            self.buffered[1].push(*r);
+
+            if self.buffered[0].len() == BLOCK_SIZE {
+                self.encode_frame()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        if !self.buffered[0].is_empty() {
+            self.encode_frame()?;
+        }
+
+        let stream_info = self.stream_info();
+        let mut file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        // right after "fLaC"
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(&stream_info)?;
+        Ok(())
+    }
+
+    /// the streaminfo metadata block, marked as the last one
+    fn stream_info(&self) -> Vec<u8> {
+        let mut bits = BitWriter::new();
+        // last-metadata-block flag, type 0 (streaminfo), length
+        bits.write(1, 1);
+        bits.write(0, 7);
+        bits.write(34, 24);
+
+        bits.write(BLOCK_SIZE as u64, 16); // min block size, the last frame may be shorter
+        bits.write(BLOCK_SIZE as u64, 16); // max block size
+        bits.write(0, 24); // min frame size, unknown
+        bits.write(0, 24); // max frame size, unknown
+        bits.write(self.sample_rate as u64, 20);
+        bits.write(1, 3); // channels - 1
+        bits.write(self.bits as u64 - 1, 5);
+        bits.write(self.total_frames, 36);
+        // md5 of the audio, zero means not computed
+        bits.write(0, 64);
+        bits.write(0, 64);
+
+        bits.into_bytes()
+    }
+
+    fn encode_frame(&mut self) -> Result<()> {
+        let [left, right] = &self.buffered;
+        let n = left.len();
+
+        let (assignment, first, second) = choose_stereo(left, right, self.bits, self.assignment);
+
+        let mut bits = BitWriter::new();
+
+        // frame header: sync code, fixed block size strategy
+        bits.write(0b11_1111_1111_1110, 14);
+        bits.write(0, 1);
+        bits.write(0, 1);
+        bits.write(0b0111, 4); // block size - 1 follows as 16 bits
+        bits.write(0b0000, 4); // sample rate from streaminfo
+        bits.write(assignment.code(), 4);
+        bits.write(0b000, 3); // sample size from streaminfo
+        bits.write(0, 1);
+        write_utf8_number(&mut bits, self.frame_number);
+        bits.write(n as u64 - 1, 16);
+        let crc = crc8(bits.bytes());
+        bits.write(crc as u64, 8);
+
+        first.write(&mut bits);
+        second.write(&mut bits);
+
+        bits.align();
+        let crc = crc16(bits.bytes());
+        bits.write(crc as u64, 16);
+
+        self.writer.write_all(&bits.into_bytes())?;
+
+        self.frame_number += 1;
+        self.total_frames += n as u64;
+        self.buffered[0].clear();
+        self.buffered[1].clear();
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ChannelAssignment {
+    Independent,
+    LeftSide,
+    RightSide,
+    MidSide,
+}
+
+impl ChannelAssignment {
+    fn code(self) -> u64 {
+        match self {
+            ChannelAssignment::Independent => 0b0001,
+            ChannelAssignment::LeftSide => 0b1000,
+            ChannelAssignment::RightSide => 0b1001,
+            ChannelAssignment::MidSide => 0b1010,
+        }
+    }
+}
+
+/// the cheapest way to code the pair unless `forced`, the side channel needs one
+/// extra bit
+fn choose_stereo(
+    left: &[i32],
+    right: &[i32],
+    bits: u32,
+    forced: Option<ChannelAssignment>,
+) -> (ChannelAssignment, Subframe, Subframe) {
+    let side: Vec<i64> = left
+        .iter()
+        .zip(right)
+        .map(|(l, r)| *l as i64 - *r as i64)
+        .collect();
+    let mid: Vec<i64> = left
+        .iter()
+        .zip(right)
+        .map(|(l, r)| (*l as i64 + *r as i64) >> 1)
+        .collect();
+    let left: Vec<i64> = left.iter().map(|s| *s as i64).collect();
+    let right: Vec<i64> = right.iter().map(|s| *s as i64).collect();
+
+    let l = Subframe::best(left, bits);
+    let r = Subframe::best(right, bits);
+    let s = Subframe::best(side, bits + 1);
+    let m = Subframe::best(mid, bits);
+
+    let costs = [
+        l.cost + r.cost,
+        l.cost + s.cost,
+        r.cost + s.cost,
+        m.cost + s.cost,
+    ];
+    let cheapest = match (0..costs.len()).min_by_key(|&i| costs[i]) {
+        Some(1) => ChannelAssignment::LeftSide,
+        Some(2) => ChannelAssignment::RightSide,
+        Some(3) => ChannelAssignment::MidSide,
+        _ => ChannelAssignment::Independent,
+    };
+
+    let assignment = forced.unwrap_or(cheapest);
+    match assignment {
+        ChannelAssignment::Independent => (assignment, l, r),
+        ChannelAssignment::LeftSide => (assignment, l, s),
+        ChannelAssignment::RightSide => (assignment, s, r),
+        ChannelAssignment::MidSide => (assignment, m, s),
+    }
+}
+
+enum SubframeKind {
+    Constant,
+    Verbatim,
+    Fixed { order: usize, residual: Residual },
+}
+
+struct Subframe {
+    samples: Vec<i64>,
+    bits: u32,
+    kind: SubframeKind,
+    /// size in bits
+    cost: u64,
+}
+
+impl Subframe {
+    /// the smallest of a constant, verbatim or fixed predictor subframe
+    fn best(samples: Vec<i64>, bits: u32) -> Self {
+        let n = samples.len();
+
+        if samples.iter().all(|s| *s == samples[0]) {
+            return Self {
+                samples,
+                bits,
+                kind: SubframeKind::Constant,
+                cost: 8 + bits as u64,
+            };
+        }
+
+        // the order with the smallest total error is nearly always the one that
+        // codes smallest, only that one gets the full partition search
+        let (order, residual) = (0..=MAX_FIXED_ORDER.min(n - 1))
+            .map(|order| (order, fixed_residual(&samples, order)))
+            .min_by_key(|(_, residual)| residual.iter().map(|r| r.unsigned_abs()).sum::<u64>())
+            .unwrap();
+        let coded = Residual::best(residual, order, n);
+        let fixed_cost = 8 + order as u64 * bits as u64 + coded.cost;
+
+        let verbatim_cost = 8 + n as u64 * bits as u64;
+        let (kind, cost) = if fixed_cost < verbatim_cost {
+            (
+                SubframeKind::Fixed {
+                    order,
+                    residual: coded,
+                },
+                fixed_cost,
+            )
+        } else {
+            (SubframeKind::Verbatim, verbatim_cost)
+        };
+
+        Self {
+            samples,
+            bits,
+            kind,
+            cost,
+        }
+    }
+
+    fn write(&self, out: &mut BitWriter) {
+        // zero padding bit, 6 bit type, no wasted bits
+        out.write(0, 1);
+        match &self.kind {
+            SubframeKind::Constant => {
+                out.write(0b000000, 6);
+                out.write(0, 1);
+                out.write_signed(self.samples[0], self.bits);
+            }
+            SubframeKind::Verbatim => {
+                out.write(0b000001, 6);
+                out.write(0, 1);
+                for s in &self.samples {
+                    out.write_signed(*s, self.bits);
+                }
+            }
+            SubframeKind::Fixed { order, residual } => {
+                out.write(0b001000 | *order as u64, 6);
+                out.write(0, 1);
+                for s in &self.samples[..*order] {
+                    out.write_signed(*s, self.bits);
+                }
+                residual.write(out);
+            }
+        }
+    }
+}
+
+/// prediction error of the fixed polynomial predictor of `order`, for every
+/// sample after the warm-up ones
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    (order..samples.len())
+        .map(|i| {
+            let s = |k: usize| samples[i - k];
+            match order {
+                0 => s(0),
+                1 => s(0) - s(1),
+                2 => s(0) - 2 * s(1) + s(2),
+                3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+                _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+            }
+        })
+        .collect()
+}
+
+/// partition order, rice parameter and raw bit width per partition, and the
+/// size in bits of a residual coded that way
+type Partitioning = (u32, Vec<(u32, u32)>, u64);
+
+/// a partitioned rice coded residual
+struct Residual {
+    values: Vec<i64>,
+    /// warm-up samples the first partition is short by
+    warm_up: usize,
+    partition_order: u32,
+    /// rice parameter per partition, `RICE_ESCAPE` for raw binary
+    params: Vec<(u32, u32)>,
+    cost: u64,
+}
+
+impl Residual {
+    /// tries every valid partition order and keeps the smallest
+    fn best(residual: Vec<i64>, order: usize, block_size: usize) -> Self {
+        let zigzag: Vec<u64> = residual
+            .iter()
+            .map(|r| ((r << 1) ^ (r >> 63)) as u64)
+            .collect();
+
+        let mut best: Option<Partitioning> = None;
+
+        for partition_order in 0..=MAX_PARTITION_ORDER {
+            let partitions = 1usize << partition_order;
+            if !block_size.is_multiple_of(partitions) || block_size / partitions <= order {
+                break;
+            }
+
+            // 2 bits coding method, 4 bits partition order
+            let mut cost = 6;
+            let mut params = Vec::with_capacity(partitions);
+            let mut start = 0;
+            for p in 0..partitions {
+                let len = block_size / partitions - if p == 0 { order } else { 0 };
+                let (param, raw_bits, partition_cost) =
+                    partition_cost(&zigzag[start..start + len], &residual[start..start + len]);
+                params.push((param, raw_bits));
+                cost += partition_cost;
+                start += len;
+            }
+
+            if best
+                .as_ref()
+                .is_none_or(|(_, _, best_cost)| cost < *best_cost)
+            {
+                best = Some((partition_order, params, cost));
+            }
+        }
+
+        let (partition_order, params, cost) = best.expect("partition order 0 is always valid");
+        Self {
+            values: residual,
+            warm_up: order,
+            partition_order,
+            params,
+            cost,
+        }
+    }
+
+    fn write(&self, out: &mut BitWriter) {
+        out.write(0b00, 2); // rice coding with 4 bit parameters
+        out.write(self.partition_order as u64, 4);
+
+        let partitions = self.params.len();
+        let block_size = self.values.len() + self.warm_up;
+        let mut start = 0;
+        for (p, (param, raw_bits)) in self.params.iter().enumerate() {
+            let len = block_size / partitions - if p == 0 { self.warm_up } else { 0 };
+            let values = &self.values[start..start + len];
+            out.write(*param as u64, 4);
+
+            if *param == RICE_ESCAPE {
+                out.write(*raw_bits as u64, 5);
+                for v in values {
+                    out.write_signed(*v, *raw_bits);
+                }
+            } else {
+                for v in values {
+                    let u = ((v << 1) ^ (v >> 63)) as u64;
+                    out.write_unary(u >> param);
+                    out.write(u & ((1 << param) - 1), *param);
+                }
+            }
+            start += len;
+        }
+    }
+}
+
+/// best rice parameter for a partition, its raw bit width if escaped, and the cost in bits
+fn partition_cost(zigzag: &[u64], values: &[i64]) -> (u32, u32, u64) {
+    let n = zigzag.len() as u64;
+    let mean = zigzag.iter().sum::<u64>() / n.max(1);
+
+    // the optimum sits next to log2 of the mean, only its neighbours are worth trying
+    let estimate = (64 - mean.leading_zeros()).min(RICE_ESCAPE - 1);
+    let mut best = (0, 0, u64::MAX);
+    for param in estimate.saturating_sub(1)..=(estimate + 1).min(RICE_ESCAPE - 1) {
+        // each value costs its quotient in unary, a stop bit and `param` low bits
+        let quotients: u64 = zigzag.iter().map(|u| u >> param).sum();
+        let cost = 4 + quotients + n * (param as u64 + 1);
+        if cost < best.2 {
+            best = (param, 0, cost);
+        }
+    }
+
+    // raw two's complement values for partitions rice can't handle well
+    let raw_bits = values
+        .iter()
+        .map(|v| 65 - (if *v < 0 { !*v } else { *v }).leading_zeros())
+        .max()
+        .unwrap_or(1)
+        .max(1);
+    if raw_bits < 32 {
+        let cost = 4 + 5 + n * raw_bits as u64;
+        if cost < best.2 {
+            best = (RICE_ESCAPE, raw_bits, cost);
+        }
+    }
+
+    best
+}
+
+/// frame numbers are coded like utf-8 code points, up to 36 bits
+fn write_utf8_number(out: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        out.write(value, 8);
+        return;
+    }
+
+    let mut continuation = 1;
+    while value >= 1 << (6 * continuation + (6 - continuation)) {
+        continuation += 1;
+    }
+
+    let lead_bits = 6 - continuation;
+    let marker = (0xFF00u64 >> (continuation + 1)) & 0xFF;
+    out.write(marker | (value >> (6 * continuation)), 8);
+    for i in (0..continuation).rev() {
+        out.write(0x80 | ((value >> (6 * i)) & 0x3F), 8);
+    }
+    debug_assert!(value >> (6 * continuation) < 1 << lead_bits);
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// msb-first bit packing
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    n_bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            acc: 0,
+            n_bits: 0,
+        }
+    }
+
+    /// writes the low `bits` bits of `value`, at most 64
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits > 32 {
+            self.write(value >> 32, bits - 32);
+            self.write(value & 0xFFFF_FFFF, 32);
+            return;
+        }
+        if bits == 0 {
+            return;
+        }
+
+        let mask = (1u64 << bits) - 1;
+        self.acc = (self.acc << bits) | (value & mask);
+        self.n_bits += bits;
+        while self.n_bits >= 8 {
+            self.n_bits -= 8;
+            self.bytes.push((self.acc >> self.n_bits) as u8);
+        }
+        self.acc &= (1u64 << self.n_bits) - 1;
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    /// `value` zeros followed by a one
+    fn write_unary(&mut self, mut value: u64) {
+        while value >= 32 {
+            self.write(0, 32);
+            value -= 32;
+        }
+        self.write(1, value as u32 + 1);
+    }
+
+    /// pads with zeros to the next byte boundary
+    fn align(&mut self) {
+        if self.n_bits > 0 {
+            self.write(0, 8 - self.n_bits);
+        }
+    }
+
+    /// the complete bytes written so far
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::audio::{AudioBufferRef, Signal};
+    use symphonia::core::codecs::DecoderOptions;
+    use symphonia::core::formats::FormatOptions;
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::meta::MetadataOptions;
+    use symphonia::core::probe::Hint;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    const ASSIGNMENTS: [ChannelAssignment; 4] = [
+        ChannelAssignment::Independent,
+        ChannelAssignment::LeftSide,
+        ChannelAssignment::RightSide,
+        ChannelAssignment::MidSide,
+    ];
+
+    /// deterministic noise in [-amplitude, amplitude)
+    fn noise(n: usize, amplitude: i32, seed: u64) -> Vec<i32> {
+        let mut state = seed | 1;
+        (0..n)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state % (2 * amplitude as u64)) as i32 - amplitude
+            })
+            .collect()
+    }
+
+    /// correlated music-like channels at `bits`, with a few full-scale samples of
+    /// opposite sign so the side channel needs its extra bit
+    fn test_signal(n: usize, bits: u32) -> [Vec<i32>; 2] {
+        let peak = (1 << (bits - 1)) - 1;
+        let hiss = noise(n, peak / 100, 7);
+        let mut left = (0..n)
+            .map(|i| {
+                let t = i as f64 / SAMPLE_RATE as f64;
+                ((2.0 * std::f64::consts::PI * 330.0 * t).sin() * peak as f64 * 0.5) as i32
+                    + hiss[i]
+            })
+            .collect::<Vec<_>>();
+        let mut right = left
+            .iter()
+            .zip(noise(n, peak / 200, 11))
+            .map(|(l, n)| l * 3 / 4 + n)
+            .collect::<Vec<_>>();
+        for i in [10, n / 2, n - 1] {
+            left[i] = peak;
+            right[i] = -peak - 1;
+        }
+        [left, right]
+    }
+
+    fn encode(
+        path: &Path,
+        signal: &[Vec<i32>; 2],
+        bits: u32,
+        assignment: Option<ChannelAssignment>,
+    ) {
+        let mut writer = FlacWriter::create(path, SAMPLE_RATE, bits).unwrap();
+        writer.assignment = assignment;
+        // writes that don't line up with the frames
+        for (left, right) in signal[0].chunks(1000).zip(signal[1].chunks(1000)) {
+            writer.write(left, right).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    /// decodes with symphonia, which checks the frame crcs on the way
+    fn decode(path: &Path, bits: u32) -> [Vec<i32>; 2] {
+        let stream =
+            MediaSourceStream::new(Box::new(File::open(path).unwrap()), Default::default());
+        let mut hint = Hint::new();
+        hint.with_extension("flac");
+        let mut format = symphonia::default::get_probe()
+            .format(
+                &hint,
+                stream,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .unwrap()
+            .format;
+
+        let track = format.default_track().unwrap();
+        let n_frames = track.codec_params.n_frames;
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .unwrap();
+
+        let mut channels = [Vec::new(), Vec::new()];
+        while let Ok(packet) = format.next_packet() {
+            match decoder.decode(&packet).unwrap() {
+                AudioBufferRef::S32(buffer) => {
+                    for (ch, samples) in channels.iter_mut().enumerate() {
+                        // scaled up to 32 bits on decode
+                        samples.extend(buffer.chan(ch).iter().map(|s| s >> (32 - bits)));
+                    }
+                }
+                _ => panic!("flac should decode to 32 bit integers"),
+            }
+        }
+
+        assert_eq!(n_frames, Some(channels[0].len() as u64));
+        channels
+    }
+
+    #[test]
+    fn every_channel_assignment_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        // the last frame is shorter than a block
+        let n = BLOCK_SIZE * 2 + 1000;
+
+        for bits in [16, 24] {
+            let signal = test_signal(n, bits);
+            for assignment in ASSIGNMENTS {
+                let path = dir.path().join(format!("{}-{:?}.flac", bits, assignment));
+                encode(&path, &signal, bits, Some(assignment));
+                assert_eq!(
+                    decode(&path, bits),
+                    signal,
+                    "{} bit {:?} changed the samples",
+                    bits,
+                    assignment
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn short_final_blocks_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // a last frame too short for the higher predictors and most partition orders
+        for n in [1, 3, BLOCK_SIZE + 5, BLOCK_SIZE * 3 + 3] {
+            let signal = test_signal(n.max(16), 16).map(|channel| channel[..n].to_vec());
+            let path = dir.path().join(format!("{}.flac", n));
+            encode(&path, &signal, 16, None);
+            assert_eq!(decode(&path, 16), signal, "{} frames", n);
+        }
+    }
+
+    #[test]
+    fn escaped_partitions_round_trip() {
+        // white noise spread evenly over its range codes smaller as raw binary
+        // than rice
+        let n = BLOCK_SIZE * 2;
+        let signal = [noise(n, 1 << 10, 3), noise(n, 1 << 10, 5)];
+        let block = signal[0][..BLOCK_SIZE].iter().map(|s| *s as i64).collect();
+        match Subframe::best(block, 16).kind {
+            SubframeKind::Fixed { residual, .. } => assert!(residual
+                .params
+                .iter()
+                .any(|(param, _)| *param == RICE_ESCAPE)),
+            _ => panic!("noise should use a fixed predictor"),
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("noise.flac");
+        encode(&path, &signal, 16, None);
+        assert_eq!(decode(&path, 16), signal);
+    }
+}
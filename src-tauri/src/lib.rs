@@ -1,18 +1,43 @@
 mod audio_io;
+mod cancellation;
+mod cli;
 mod commands;
 mod config;
 mod db;
 mod demucs_model;
+mod device;
+mod encoding;
+mod engrave;
+mod ensemble;
+mod flac;
+mod midi;
+mod migrations;
+mod model_cache;
 mod models;
+mod musicxml;
+mod notation;
+mod opus;
 mod processing;
+mod progress;
+mod residual;
+mod separator;
+mod settings;
+mod streaming;
+mod transcription;
+mod wiener;
 mod worker;
 
+use cancellation::ActiveJobs;
+pub use cli::run_cli;
 use commands::{
-    cancel_processing, delete_file, download_asset, list_assets, list_files, process_to_stage,
-    upload_file,
+    cancel_processing, check_integrity, delete_file, download_asset, list_assets, list_files,
+    process_to_stage, repair_library, upload_file,
 };
 use config::get_app_config;
-use db::{init_db, reset_interrupted_jobs};
+use db::init_db;
+use device::list_devices;
+use separator::list_separators;
+use settings::{get_settings, update_settings};
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use tauri::{AppHandle, Manager};
@@ -36,6 +61,11 @@ pub fn run() {
             delete_file,
             process_to_stage,
             cancel_processing,
+            get_settings,
+            update_settings,
+            list_separators,
+            list_devices,
+            repair_library,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -53,19 +83,19 @@ fn setup_app(app: AppHandle) -> Result<(), Box<dyn std::error::Error>> {
     let db_path = app_data_dir.join("lala.db");
     let pool = init_db(&db_path)?;
 
-    // startup recovery: reset any jobs that were processing when app last closed
-    let reset_count = reset_interrupted_jobs(&pool)?;
-    if reset_count > 0 {
-        println!("reset {} interrupted jobs to queued", reset_count);
-    }
+    // jobs that were processing when the app last closed are picked up again by
+    // the worker once their lease runs out, see claim_next_asset
+    check_integrity(&pool, &app_data_dir, false)?;
 
     // manage state
+    let jobs = ActiveJobs::new();
     app.manage(pool.clone());
     app.manage(app_data_dir.clone());
+    app.manage(jobs.clone());
 
     // start background worker
     let shutdown = Arc::new(AtomicBool::new(false));
-    worker::start_worker(app.clone(), pool.clone(), shutdown.clone());
+    worker::start_worker(app.clone(), pool.clone(), jobs, shutdown.clone());
 
     Ok(())
 }
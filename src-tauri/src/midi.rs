@@ -0,0 +1,428 @@
+use anyhow::{anyhow, Context, Result};
+use midly::num::{u15, u24, u28, u4, u7};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// midi file extensions accepted as uploads, these skip separation and transcription
+pub const SUPPORTED_MIDI_EXTENSIONS: &[&str] = &[".mid", ".midi"];
+
+const SUSTAIN_PEDAL_CC: u8 = 64;
+const DEFAULT_BPM: f64 = 120.0;
+
+/// a single note, times in seconds
+#[derive(Debug, Clone)]
+pub struct NoteEvent {
+    pub onset: f64,
+    pub offset: f64,
+    pub pitch: u8,
+    pub velocity: u8,
+}
+
+/// sustain pedal press or release, time in seconds
+#[derive(Debug, Clone)]
+pub struct PedalEvent {
+    pub time: f64,
+    pub down: bool,
+}
+
+/// tempo change at a position measured in quarter-note beats from the start
+#[derive(Debug, Clone)]
+pub struct TempoChange {
+    pub beat: f64,
+    pub bpm: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TimeSignature {
+    pub numerator: u8,
+    pub denominator: u8,
+}
+
+/// key as a count of sharps (positive) or flats (negative)
+#[derive(Debug, Clone, Copy)]
+pub struct KeySignature {
+    pub fifths: i8,
+    pub minor: bool,
+}
+
+/// everything the pipeline keeps about a piece of piano music
+#[derive(Debug, Clone)]
+pub struct MidiScore {
+    pub notes: Vec<NoteEvent>,
+    pub pedals: Vec<PedalEvent>,
+    /// sorted by beat, the first entry is always at beat 0
+    pub tempo_map: Vec<TempoChange>,
+    pub time_signature: TimeSignature,
+    pub key_signature: KeySignature,
+}
+
+impl MidiScore {
+    /// wraps transcribed notes with a default 120 bpm, 4/4, c major header
+    pub fn from_notes(notes: Vec<NoteEvent>) -> Self {
+        Self {
+            notes,
+            pedals: Vec::new(),
+            tempo_map: vec![TempoChange {
+                beat: 0.0,
+                bpm: DEFAULT_BPM,
+            }],
+            time_signature: TimeSignature {
+                numerator: 4,
+                denominator: 4,
+            },
+            key_signature: KeySignature {
+                fifths: 0,
+                minor: false,
+            },
+        }
+    }
+
+    /// converts a time in seconds to quarter-note beats using the tempo map
+    pub fn seconds_to_beats(&self, seconds: f64) -> f64 {
+        let mut beat = 0.0;
+        let mut elapsed = 0.0;
+        let mut bpm = self.tempo_map.first().map_or(DEFAULT_BPM, |t| t.bpm);
+
+        for change in self.tempo_map.iter().skip(1) {
+            let segment_seconds = (change.beat - beat) * 60.0 / bpm;
+            if elapsed + segment_seconds > seconds {
+                break;
+            }
+            elapsed += segment_seconds;
+            beat = change.beat;
+            bpm = change.bpm;
+        }
+
+        beat + (seconds - elapsed) * bpm / 60.0
+    }
+
+    /// converts quarter-note beats to seconds using the tempo map
+    pub fn beats_to_seconds(&self, beats: f64) -> f64 {
+        let mut beat = 0.0;
+        let mut elapsed = 0.0;
+        let mut bpm = self.tempo_map.first().map_or(DEFAULT_BPM, |t| t.bpm);
+
+        for change in self.tempo_map.iter().skip(1) {
+            if change.beat > beats {
+                break;
+            }
+            elapsed += (change.beat - beat) * 60.0 / bpm;
+            beat = change.beat;
+            bpm = change.bpm;
+        }
+
+        elapsed + (beats - beat) * 60.0 / bpm
+    }
+}
+
+/// knobs for serializing a score
+#[derive(Debug, Clone)]
+pub struct MidiWriteOptions {
+    /// ticks per quarter note
+    pub ppq: u16,
+    /// notes at or above this pitch go to the right hand track (60 = middle c)
+    pub hand_split: u8,
+}
+
+impl Default for MidiWriteOptions {
+    fn default() -> Self {
+        Self {
+            ppq: 480,
+            hand_split: 60,
+        }
+    }
+}
+
+/// writes a format-1 midi file: a conductor track with the tempo map, time and
+/// key signature, then separate right and left hand tracks. sustain pedal is
+/// written as cc64 on the left hand track
+pub fn write_midi(path: &Path, score: &MidiScore, options: &MidiWriteOptions) -> Result<()> {
+    let ppq = options.ppq as f64;
+    let to_ticks = |seconds: f64| (score.seconds_to_beats(seconds) * ppq).round() as u32;
+
+    // conductor track
+    let mut conductor: Vec<(u32, TrackEventKind)> = vec![
+        (0, TrackEventKind::Meta(MetaMessage::TrackName(b"piano"))),
+        (
+            0,
+            TrackEventKind::Meta(MetaMessage::TimeSignature(
+                score.time_signature.numerator,
+                score.time_signature.denominator.trailing_zeros() as u8,
+                24, // midi clocks per metronome click
+                8,  // 32nd notes per quarter
+            )),
+        ),
+        (
+            0,
+            TrackEventKind::Meta(MetaMessage::KeySignature(
+                score.key_signature.fifths,
+                score.key_signature.minor,
+            )),
+        ),
+    ];
+    for change in &score.tempo_map {
+        let micros_per_beat = (60_000_000.0 / change.bpm).round() as u32;
+        conductor.push((
+            (change.beat * ppq).round() as u32,
+            TrackEventKind::Meta(MetaMessage::Tempo(u24::new(micros_per_beat))),
+        ));
+    }
+
+    // one track per hand, both on channel 0 so the pedal applies to everything
+    let mut right_hand: Vec<(u32, TrackEventKind)> = vec![(
+        0,
+        TrackEventKind::Meta(MetaMessage::TrackName(b"right hand")),
+    )];
+    let mut left_hand: Vec<(u32, TrackEventKind)> = vec![(
+        0,
+        TrackEventKind::Meta(MetaMessage::TrackName(b"left hand")),
+    )];
+
+    for note in &score.notes {
+        let on = to_ticks(note.onset);
+        let off = to_ticks(note.offset).max(on + 1);
+        let track = if note.pitch >= options.hand_split {
+            &mut right_hand
+        } else {
+            &mut left_hand
+        };
+
+        track.push((
+            on,
+            midi_event(MidiMessage::NoteOn {
+                key: u7::new(note.pitch),
+                vel: u7::new(note.velocity.clamp(1, 127)),
+            }),
+        ));
+        track.push((
+            off,
+            midi_event(MidiMessage::NoteOff {
+                key: u7::new(note.pitch),
+                vel: u7::new(0),
+            }),
+        ));
+    }
+
+    for pedal in &score.pedals {
+        left_hand.push((
+            to_ticks(pedal.time),
+            midi_event(MidiMessage::Controller {
+                controller: u7::new(SUSTAIN_PEDAL_CC),
+                value: u7::new(if pedal.down { 127 } else { 0 }),
+            }),
+        ));
+    }
+
+    let mut smf = Smf::new(Header::new(
+        Format::Parallel,
+        Timing::Metrical(u15::new(options.ppq)),
+    ));
+    smf.tracks.push(to_delta_track(conductor));
+    smf.tracks.push(to_delta_track(right_hand));
+    smf.tracks.push(to_delta_track(left_hand));
+
+    smf.save(path)
+        .with_context(|| format!("failed to write midi file {}", path.display()))?;
+
+    Ok(())
+}
+
+/// reads any standard midi file (format 0, 1 or 2) back into a score.
+/// notes from every track and channel are merged, the first time and key
+/// signature win, and times are converted to seconds through the tempo map
+pub fn read_midi(path: &Path) -> Result<MidiScore> {
+    let bytes =
+        std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let smf = Smf::parse(&bytes).map_err(|e| anyhow!("invalid midi file: {}", e))?;
+
+    let mut score = MidiScore::from_notes(Vec::new());
+
+    // absolute tick events across all tracks
+    let mut events: Vec<(u64, TrackEventKind)> = Vec::new();
+    for track in &smf.tracks {
+        // format 2 tracks are independent sequences, play them back to back
+        let mut tick: u64 = if smf.header.format == Format::Sequential {
+            events.iter().map(|(t, _)| *t).max().unwrap_or(0)
+        } else {
+            0
+        };
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            events.push((tick, event.kind));
+        }
+    }
+    events.sort_by_key(|(tick, _)| *tick);
+
+    // ticks -> seconds. metrical timing goes through the tempo map, timecode is absolute
+    let ticks_per_second_timecode = match smf.header.timing {
+        Timing::Metrical(_) => None,
+        Timing::Timecode(fps, subframes) => Some(fps.as_f32() as f64 * subframes as f64),
+    };
+    let ppq = match smf.header.timing {
+        Timing::Metrical(ppq) => ppq.as_int().max(1) as f64,
+        Timing::Timecode(..) => 1.0,
+    };
+
+    let mut tempo_map: Vec<TempoChange> = Vec::new();
+    let mut time_signature = None;
+    let mut key_signature = None;
+
+    for (tick, kind) in &events {
+        if let TrackEventKind::Meta(meta) = kind {
+            match meta {
+                MetaMessage::Tempo(micros) if ticks_per_second_timecode.is_none() => {
+                    let beat = *tick as f64 / ppq;
+                    let bpm = 60_000_000.0 / micros.as_int().max(1) as f64;
+                    // a later tempo at the same tick replaces the earlier one
+                    if tempo_map.last().is_some_and(|t| t.beat == beat) {
+                        tempo_map.pop();
+                    }
+                    tempo_map.push(TempoChange { beat, bpm });
+                }
+                MetaMessage::TimeSignature(numerator, denominator_pow, _, _) => {
+                    time_signature.get_or_insert(TimeSignature {
+                        numerator: *numerator,
+                        denominator: 1u8.checked_shl(*denominator_pow as u32).unwrap_or(4),
+                    });
+                }
+                MetaMessage::KeySignature(fifths, minor) => {
+                    key_signature.get_or_insert(KeySignature {
+                        fifths: *fifths,
+                        minor: *minor,
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if tempo_map.first().is_none_or(|t| t.beat > 0.0) {
+        tempo_map.insert(
+            0,
+            TempoChange {
+                beat: 0.0,
+                bpm: DEFAULT_BPM,
+            },
+        );
+    }
+    score.tempo_map = tempo_map;
+    if let Some(time_signature) = time_signature {
+        score.time_signature = time_signature;
+    }
+    if let Some(key_signature) = key_signature {
+        score.key_signature = key_signature;
+    }
+
+    let to_seconds = |tick: u64| match ticks_per_second_timecode {
+        Some(ticks_per_second) => tick as f64 / ticks_per_second,
+        None => score.beats_to_seconds(tick as f64 / ppq),
+    };
+
+    // pair note ons with offs per channel and key
+    let mut open_notes: HashMap<(u8, u8), Vec<(f64, u8)>> = HashMap::new();
+    let mut notes = Vec::new();
+    let mut pedals = Vec::new();
+
+    for (tick, kind) in &events {
+        let TrackEventKind::Midi { channel, message } = kind else {
+            continue;
+        };
+        let time = to_seconds(*tick);
+        let channel = channel.as_int();
+
+        match message {
+            MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                open_notes
+                    .entry((channel, key.as_int()))
+                    .or_default()
+                    .push((time, vel.as_int()));
+            }
+            MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                if let Some(stack) = open_notes.get_mut(&(channel, key.as_int())) {
+                    if !stack.is_empty() {
+                        // first in, first out for overlapping repeats of the same key
+                        let (onset, velocity) = stack.remove(0);
+                        notes.push(NoteEvent {
+                            onset,
+                            offset: time,
+                            pitch: key.as_int(),
+                            velocity,
+                        });
+                    }
+                }
+            }
+            MidiMessage::Controller { controller, value }
+                if controller.as_int() == SUSTAIN_PEDAL_CC =>
+            {
+                let down = value.as_int() >= 64;
+                // collapse repeated half-pedal values into press/release edges
+                if pedals.last().is_none_or(|p: &PedalEvent| p.down != down) {
+                    pedals.push(PedalEvent { time, down });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // close anything left hanging at the last event
+    let end_time = events.last().map_or(0.0, |(tick, _)| to_seconds(*tick));
+    for ((_, pitch), stack) in open_notes {
+        for (onset, velocity) in stack {
+            notes.push(NoteEvent {
+                onset,
+                offset: end_time.max(onset),
+                pitch,
+                velocity,
+            });
+        }
+    }
+
+    notes.sort_by(|a, b| a.onset.total_cmp(&b.onset).then(a.pitch.cmp(&b.pitch)));
+    score.notes = notes;
+    score.pedals = pedals;
+
+    Ok(score)
+}
+
+fn midi_event(message: MidiMessage) -> TrackEventKind<'static> {
+    TrackEventKind::Midi {
+        channel: u4::new(0),
+        message,
+    }
+}
+
+/// sorts absolute-tick events and converts them to a delta-timed track.
+/// note offs sort before note ons on the same tick so repeated keys retrigger
+fn to_delta_track(mut events: Vec<(u32, TrackEventKind)>) -> Vec<TrackEvent> {
+    let order = |kind: &TrackEventKind| match kind {
+        TrackEventKind::Meta(_) => 0,
+        TrackEventKind::Midi {
+            message: MidiMessage::NoteOff { .. },
+            ..
+        } => 1,
+        TrackEventKind::Midi {
+            message: MidiMessage::Controller { .. },
+            ..
+        } => 2,
+        _ => 3,
+    };
+    events.sort_by_key(|(tick, kind)| (*tick, order(kind)));
+
+    let mut track = Vec::with_capacity(events.len() + 1);
+    let mut last_tick = 0;
+    for (tick, kind) in events {
+        track.push(TrackEvent {
+            delta: u28::new(tick - last_tick),
+            kind,
+        });
+        last_tick = tick;
+    }
+
+    track.push(TrackEvent {
+        delta: u28::new(0),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+
+    track
+}
@@ -0,0 +1,274 @@
+use anyhow::{bail, Context, Result};
+use rusqlite::Connection;
+
+/// schema changes in the order they apply, migration n takes a database from
+/// `user_version` n - 1 to n. never edit one that has shipped, append a new one
+const MIGRATIONS: &[&str] = &[
+    // 1: the schema from before versioning. databases of that time are at
+    // version 0 with these tables already there, hence IF NOT EXISTS
+    "CREATE TABLE IF NOT EXISTS files (
+        id TEXT PRIMARY KEY,
+        original_filename TEXT NOT NULL,
+        target_stage TEXT,
+        created_at INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS assets (
+        id TEXT PRIMARY KEY,
+        file_id TEXT NOT NULL,
+        parent_asset_id TEXT,
+        asset_type TEXT NOT NULL,
+        file_path TEXT NOT NULL,
+        status TEXT NOT NULL,
+        error_message TEXT,
+        created_at INTEGER NOT NULL,
+        FOREIGN KEY(file_id) REFERENCES files(id),
+        FOREIGN KEY(parent_asset_id) REFERENCES assets(id)
+    );
+    CREATE TABLE IF NOT EXISTS settings (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_assets_status ON assets(status);",
+    // 2: deleting a file or asset takes everything derived from it along. sqlite
+    // can't change a foreign key in place, so the table is rebuilt
+    "CREATE TABLE assets_new (
+        id TEXT PRIMARY KEY,
+        file_id TEXT NOT NULL,
+        parent_asset_id TEXT,
+        asset_type TEXT NOT NULL,
+        file_path TEXT NOT NULL,
+        status TEXT NOT NULL,
+        error_message TEXT,
+        created_at INTEGER NOT NULL,
+        FOREIGN KEY(file_id) REFERENCES files(id) ON DELETE CASCADE,
+        FOREIGN KEY(parent_asset_id) REFERENCES assets(id) ON DELETE CASCADE
+    );
+    INSERT INTO assets_new
+        SELECT id, file_id, parent_asset_id, asset_type, file_path, status, error_message, created_at
+        FROM assets;
+    DROP TABLE assets;
+    ALTER TABLE assets_new RENAME TO assets;
+    CREATE INDEX idx_assets_status ON assets(status);
+    CREATE INDEX idx_assets_file ON assets(file_id);
+    CREATE INDEX idx_assets_parent ON assets(parent_asset_id);",
+    // 3: a claimed asset records which worker holds it and until when, so the
+    // jobs of a worker that died can be taken over once its lease runs out
+    "ALTER TABLE assets ADD COLUMN worker_id TEXT;
+    ALTER TABLE assets ADD COLUMN lease_expires_at INTEGER;",
+    // 4: what clipped when a stem was encoded, so the warning outlasts the job
+    "ALTER TABLE assets ADD COLUMN clipped_samples INTEGER;
+    ALTER TABLE assets ADD COLUMN total_samples INTEGER;
+    ALTER TABLE assets ADD COLUMN peak REAL;",
+];
+
+/// schema version this build writes
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// brings the database up to `SCHEMA_VERSION`. all pending migrations run in one
+/// transaction, so a failure leaves the database as it was. a database written by
+/// a newer build is refused rather than opened with a schema this one doesn't know.
+/// leaves foreign keys off, dropping a rebuilt table would cascade into its
+/// replacement otherwise. the caller turns them on once it's done
+pub fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+    if version > SCHEMA_VERSION {
+        bail!(
+            "the database is from a newer version of lala (schema {}, this version knows up to {}), \
+             update the app to open it",
+            version,
+            SCHEMA_VERSION
+        );
+    }
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    // bundled sqlite enforces them by default, and the pragma is a no-op inside a transaction
+    conn.pragma_update(None, "foreign_keys", false)?;
+    let tx = conn.transaction()?;
+    for (idx, sql) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        println!("migrating database to schema {}", idx + 1);
+        tx.execute_batch(sql)
+            .with_context(|| format!("database migration {} failed", idx + 1))?;
+    }
+    tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a database the way the first release left it: no version, no settings
+    /// table, foreign keys without cascades, and a file with a chain of assets
+    fn version_0_database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE files (
+                id TEXT PRIMARY KEY,
+                original_filename TEXT NOT NULL,
+                target_stage TEXT,
+                created_at INTEGER NOT NULL
+            );
+            CREATE TABLE assets (
+                id TEXT PRIMARY KEY,
+                file_id TEXT NOT NULL,
+                parent_asset_id TEXT,
+                asset_type TEXT NOT NULL,
+                file_path TEXT NOT NULL,
+                status TEXT NOT NULL,
+                error_message TEXT,
+                created_at INTEGER NOT NULL,
+                FOREIGN KEY(file_id) REFERENCES files(id),
+                FOREIGN KEY(parent_asset_id) REFERENCES assets(id)
+            );
+            CREATE INDEX idx_assets_status ON assets(status);
+
+            INSERT INTO files VALUES ('song', 'song.mp3', 'midi', 1);
+            INSERT INTO files VALUES ('other', 'other.mp3', NULL, 2);
+            INSERT INTO assets VALUES ('original', 'song', NULL, 'original', 'song/song.mp3', 'completed', NULL, 1);
+            INSERT INTO assets VALUES ('piano', 'song', 'original', 'stem_piano', 'song/stem_piano.wav', 'completed', NULL, 2);
+            INSERT INTO assets VALUES ('midi', 'song', 'piano', 'midi', 'song/piano.mid', 'failed', 'out of memory', 3);
+            INSERT INTO assets VALUES ('other_original', 'other', NULL, 'original', 'other/other.mp3', 'processing', NULL, 4);",
+        )
+        .unwrap();
+        conn
+    }
+
+    fn user_version(conn: &Connection) -> u32 {
+        conn.pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap()
+    }
+
+    fn count(conn: &Connection, sql: &str) -> i64 {
+        conn.query_row(sql, [], |row| row.get(0)).unwrap()
+    }
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        let mut stmt = conn
+            .prepare(&format!("SELECT name FROM pragma_table_info('{}')", table))
+            .unwrap();
+        stmt.query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn upgrades_version_0_to_current() {
+        let mut conn = version_0_database();
+        assert_eq!(user_version(&conn), 0);
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+
+        // every row made it through the rebuild of assets, unchanged
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM files"), 2);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM assets"), 4);
+        let midi: (String, String, String, Option<String>, i64) = conn
+            .query_row(
+                "SELECT parent_asset_id, asset_type, status, error_message, created_at
+                 FROM assets WHERE id = 'midi'",
+                [],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                    ))
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            midi,
+            (
+                "piano".to_string(),
+                "midi".to_string(),
+                "failed".to_string(),
+                Some("out of memory".to_string()),
+                3
+            )
+        );
+
+        // later tables and columns are there
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM settings"), 0);
+        let asset_columns = columns(&conn, "assets");
+        for column in [
+            "worker_id",
+            "lease_expires_at",
+            "clipped_samples",
+            "total_samples",
+            "peak",
+        ] {
+            assert!(
+                asset_columns.iter().any(|c| c == column),
+                "assets has no {} column",
+                column
+            );
+        }
+
+        // and running it again changes nothing
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM assets"), 4);
+    }
+
+    #[test]
+    fn deletes_cascade_after_upgrading() {
+        let mut conn = version_0_database();
+        migrate(&mut conn).unwrap();
+        conn.pragma_update(None, "foreign_keys", true).unwrap();
+
+        // an asset takes what was derived from it along
+        conn.execute("DELETE FROM assets WHERE id = 'piano'", [])
+            .unwrap();
+        assert_eq!(
+            count(&conn, "SELECT COUNT(*) FROM assets WHERE file_id = 'song'"),
+            1
+        );
+
+        // and a file all of its assets, leaving other files alone
+        conn.execute("DELETE FROM files WHERE id = 'song'", [])
+            .unwrap();
+        assert_eq!(
+            count(&conn, "SELECT COUNT(*) FROM assets WHERE file_id = 'song'"),
+            0
+        );
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM assets"), 1);
+
+        // dangling references are refused
+        assert!(conn
+            .execute(
+                "INSERT INTO assets (id, file_id, asset_type, file_path, status, created_at)
+                 VALUES ('stray', 'missing', 'midi', 'x.mid', 'queued', 5)",
+                [],
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn creates_an_empty_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), SCHEMA_VERSION);
+        assert_eq!(count(&conn, "SELECT COUNT(*) FROM assets"), 0);
+    }
+
+    #[test]
+    fn refuses_a_newer_database() {
+        let mut conn = version_0_database();
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+
+        let err = migrate(&mut conn).unwrap_err();
+        assert!(err.to_string().contains("newer version"), "{}", err);
+        // and leaves it alone
+        assert_eq!(user_version(&conn), SCHEMA_VERSION + 1);
+        assert!(!columns(&conn, "assets").iter().any(|c| c == "worker_id"));
+    }
+}
@@ -0,0 +1,151 @@
+use crate::separator::{Separator, SeparatorBackend};
+use anyhow::Result;
+use std::time::{Duration, Instant, SystemTime};
+use sysinfo::System;
+use tch::Device;
+
+/// separation models kept loaded at once, counting each ensemble member. a
+/// model holds a few hundred mb of host or gpu memory, so only the most
+/// recently used one stays resident and switching models frees the previous
+/// one before the next is loaded. an ensemble is the one backend allowed past
+/// this, with all its members loaded and nothing else
+const MAX_CACHED_MODELS: usize = 1;
+
+/// unload models that haven't been used for this long
+const IDLE_EVICTION: Duration = Duration::from_secs(10 * 60);
+
+/// drop cached models when the system has less memory than this available
+/// per model about to be loaded
+const MIN_AVAILABLE_MEMORY: u64 = 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ModelKey {
+    id: &'static str,
+    device: Device,
+}
+
+struct CachedModel {
+    key: ModelKey,
+    model: Box<dyn Separator>,
+    /// separation models behind `model`, more than one for an ensemble
+    models: usize,
+    /// modification time of the weights when loaded, a changed file is reloaded
+    modified: Option<SystemTime>,
+    last_used: Instant,
+}
+
+/// long-lived separation backends, owned by the worker thread and reused across jobs
+pub struct ModelCache {
+    entries: Vec<CachedModel>,
+    system: System,
+}
+
+impl ModelCache {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            system: System::new(),
+        }
+    }
+
+    /// returns the model for `backend` on `device`, loading it on first use
+    pub fn get_or_load(
+        &mut self,
+        backend: &SeparatorBackend,
+        device: Device,
+    ) -> Result<&dyn Separator> {
+        let key = ModelKey {
+            id: backend.id(),
+            device,
+        };
+        let modified = weights_modified(backend);
+
+        if let Some(idx) = self.entries.iter().position(|e| e.key == key) {
+            if self.entries[idx].modified == modified {
+                println!("reusing cached model {} on {:?}", key.id, device);
+                let entry = &mut self.entries[idx];
+                entry.last_used = Instant::now();
+                return Ok(entry.model.as_ref());
+            }
+
+            println!("weights for {} changed on disk, reloading", key.id);
+            self.entries.remove(idx);
+        }
+
+        // make room before loading so nothing outlives the switch to another
+        // model, an ensemble empties the cache and loads its members together
+        let models = backend.model_count();
+        while !self.entries.is_empty() && self.loaded_models() + models > MAX_CACHED_MODELS {
+            self.evict_least_recently_used();
+        }
+        if self.memory_pressure(models) {
+            self.clear();
+        }
+
+        let model = backend.load(device)?;
+        self.entries.push(CachedModel {
+            key,
+            model,
+            models,
+            modified,
+            last_used: Instant::now(),
+        });
+
+        Ok(self.entries.last().unwrap().model.as_ref())
+    }
+
+    /// called by the worker between jobs: unloads idle models, or everything
+    /// if the system is running low on memory
+    pub fn evict_idle(&mut self) {
+        if self.entries.is_empty() {
+            return;
+        }
+
+        let before = self.entries.len();
+        self.entries
+            .retain(|e| e.last_used.elapsed() < IDLE_EVICTION);
+        if self.entries.len() < before {
+            println!("unloaded {} idle models", before - self.entries.len());
+        }
+
+        if !self.entries.is_empty() && self.memory_pressure(self.loaded_models()) {
+            println!("low on memory, unloading cached models");
+            self.clear();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        if let Some(idx) = self
+            .entries
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, e)| e.last_used)
+            .map(|(idx, _)| idx)
+        {
+            let entry = self.entries.remove(idx);
+            println!("unloading model {} on {:?}", entry.key.id, entry.key.device);
+        }
+    }
+
+    fn loaded_models(&self) -> usize {
+        self.entries.iter().map(|e| e.models).sum()
+    }
+
+    /// whether there's too little memory left to keep `models` loaded
+    fn memory_pressure(&mut self, models: usize) -> bool {
+        self.system.refresh_memory();
+        let available = self.system.available_memory();
+        // some platforms report 0 when the value is unknown
+        available > 0 && available < MIN_AVAILABLE_MEMORY * models as u64
+    }
+}
+
+fn weights_modified(backend: &SeparatorBackend) -> Option<SystemTime> {
+    std::fs::metadata(backend.weights_path()?)
+        .and_then(|m| m.modified())
+        .ok()
+}
@@ -1,3 +1,4 @@
+use crate::encoding::Clipping;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,7 +34,7 @@ impl ProcessingStatus {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum AssetType {
     #[serde(rename = "original")]
     Original,
@@ -45,8 +46,18 @@ pub enum AssetType {
     StemDrums,
     #[serde(rename = "stem_bass")]
     StemBass,
+    #[serde(rename = "stem_guitar")]
+    StemGuitar,
+    #[serde(rename = "stem_other")]
+    StemOther,
+    #[serde(rename = "instrumental")]
+    Instrumental,
+    #[serde(rename = "accompaniment")]
+    Accompaniment,
     #[serde(rename = "midi")]
     Midi,
+    #[serde(rename = "musicxml")]
+    MusicXml,
     #[serde(rename = "pdf")]
     Pdf,
 }
@@ -59,7 +70,12 @@ impl AssetType {
             AssetType::StemVocals => "stem_vocals".to_string(),
             AssetType::StemDrums => "stem_drums".to_string(),
             AssetType::StemBass => "stem_bass".to_string(),
+            AssetType::StemGuitar => "stem_guitar".to_string(),
+            AssetType::StemOther => "stem_other".to_string(),
+            AssetType::Instrumental => "instrumental".to_string(),
+            AssetType::Accompaniment => "accompaniment".to_string(),
             AssetType::Midi => "midi".to_string(),
+            AssetType::MusicXml => "musicxml".to_string(),
             AssetType::Pdf => "pdf".to_string(),
         }
     }
@@ -71,11 +87,50 @@ impl AssetType {
             "stem_vocals" => AssetType::StemVocals,
             "stem_drums" => AssetType::StemDrums,
             "stem_bass" => AssetType::StemBass,
+            "stem_guitar" => AssetType::StemGuitar,
+            "stem_other" => AssetType::StemOther,
+            "instrumental" => AssetType::Instrumental,
+            "accompaniment" => AssetType::Accompaniment,
             "midi" => AssetType::Midi,
+            "musicxml" => AssetType::MusicXml,
             "pdf" => AssetType::Pdf,
             _ => AssetType::Original,
         }
     }
+
+    /// maps a separation model's stem name to the asset it is stored as
+    pub fn from_stem_name(stem_name: &str) -> Option<Self> {
+        match stem_name {
+            "piano" => Some(AssetType::StemPiano),
+            "vocals" => Some(AssetType::StemVocals),
+            "drums" => Some(AssetType::StemDrums),
+            "bass" => Some(AssetType::StemBass),
+            "guitar" => Some(AssetType::StemGuitar),
+            "other" => Some(AssetType::StemOther),
+            _ => None,
+        }
+    }
+
+    /// maps a residual id to the asset it is stored as
+    pub fn from_residual_id(id: &str) -> Option<Self> {
+        match id {
+            "instrumental" => Some(AssetType::Instrumental),
+            "accompaniment" => Some(AssetType::Accompaniment),
+            _ => None,
+        }
+    }
+
+    pub fn is_stem(&self) -> bool {
+        matches!(
+            self,
+            AssetType::StemPiano
+                | AssetType::StemVocals
+                | AssetType::StemDrums
+                | AssetType::StemBass
+                | AssetType::StemGuitar
+                | AssetType::StemOther
+        )
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -96,4 +151,6 @@ pub struct Asset {
     pub status: ProcessingStatus,
     pub error_message: Option<String>,
     pub created_at: i64,
+    /// what clipped when a stem was written, None for everything else
+    pub clipping: Option<Clipping>,
 }
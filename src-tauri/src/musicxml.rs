@@ -0,0 +1,267 @@
+use crate::notation::{Clef, Event, NoteType, Score, DIVISIONS};
+use anyhow::Result;
+use std::fmt::Write;
+use std::path::Path;
+
+pub const MUSICXML_EXTENSION: &str = "musicxml";
+
+/// writes the score as a single two-staff piano part (musicxml 4.0 partwise),
+/// so it opens in musescore, sibelius, dorico and friends for editing
+pub fn write_musicxml(score: &Score, title: &str, output: &Path) -> Result<()> {
+    let mut xml = String::new();
+
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n");
+    xml.push_str(
+        "<!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 4.0 Partwise//EN\" \
+         \"http://www.musicxml.org/dtds/partwise.dtd\">\n",
+    );
+    xml.push_str("<score-partwise version=\"4.0\">\n");
+    writeln!(
+        xml,
+        "  <work>\n    <work-title>{}</work-title>\n  </work>",
+        escape(title)
+    )?;
+    xml.push_str("  <identification>\n    <encoding>\n");
+    xml.push_str("      <software>lala</software>\n");
+    writeln!(
+        xml,
+        "      <encoding-date>{}</encoding-date>",
+        chrono::Local::now().format("%Y-%m-%d")
+    )?;
+    xml.push_str("    </encoding>\n  </identification>\n");
+    xml.push_str("  <part-list>\n    <score-part id=\"P1\">\n");
+    xml.push_str("      <part-name>Piano</part-name>\n");
+    xml.push_str("    </score-part>\n  </part-list>\n");
+    xml.push_str("  <part id=\"P1\">\n");
+
+    for (idx, measure) in score.measures.iter().enumerate() {
+        writeln!(xml, "    <measure number=\"{}\">", measure.number)?;
+
+        if idx == 0 {
+            write_attributes(&mut xml, score, measure.staves.iter().map(|s| s.clef))?;
+            writeln!(
+                xml,
+                "      <direction placement=\"above\">\n        <direction-type>\n          \
+                 <metronome>\n            <beat-unit>quarter</beat-unit>\n            \
+                 <per-minute>{}</per-minute>\n          </metronome>\n        </direction-type>\n        \
+                 <staff>1</staff>\n        <sound tempo=\"{}\"/>\n      </direction>",
+                score.tempo_bpm.round(),
+                score.tempo_bpm.round()
+            )?;
+        }
+
+        for (staff_idx, staff) in measure.staves.iter().enumerate() {
+            if staff_idx > 0 {
+                // rewind to the start of the measure for the next staff
+                writeln!(
+                    xml,
+                    "      <backup>\n        <duration>{}</duration>\n      </backup>",
+                    score.measure_length
+                )?;
+            }
+
+            let staff_number = staff_idx + 1;
+            // one voice per staff, numbered the way notation programs expect
+            let voice = staff_idx * 4 + 1;
+
+            for (event_idx, event) in staff.events.iter().enumerate() {
+                let beams = beam_states(&staff.events, event_idx);
+                write_event(&mut xml, event, staff_number, voice, &beams)?;
+            }
+        }
+
+        if idx == score.measures.len() - 1 {
+            xml.push_str(
+                "      <barline location=\"right\">\n        \
+                 <bar-style>light-heavy</bar-style>\n      </barline>\n",
+            );
+        }
+
+        xml.push_str("    </measure>\n");
+    }
+
+    xml.push_str("  </part>\n</score-partwise>\n");
+
+    std::fs::write(output, xml)?;
+    Ok(())
+}
+
+fn write_attributes(
+    xml: &mut String,
+    score: &Score,
+    clefs: impl Iterator<Item = Clef>,
+) -> Result<()> {
+    xml.push_str("      <attributes>\n");
+    writeln!(xml, "        <divisions>{}</divisions>", DIVISIONS)?;
+    writeln!(
+        xml,
+        "        <key>\n          <fifths>{}</fifths>\n          <mode>{}</mode>\n        </key>",
+        score.key_signature.fifths,
+        if score.key_signature.minor {
+            "minor"
+        } else {
+            "major"
+        }
+    )?;
+    writeln!(
+        xml,
+        "        <time>\n          <beats>{}</beats>\n          <beat-type>{}</beat-type>\n        </time>",
+        score.time_signature.numerator, score.time_signature.denominator
+    )?;
+    xml.push_str("        <staves>2</staves>\n");
+    for (idx, clef) in clefs.enumerate() {
+        let (sign, line) = match clef {
+            Clef::Treble => ("G", 2),
+            Clef::Bass => ("F", 4),
+        };
+        writeln!(
+            xml,
+            "        <clef number=\"{}\">\n          <sign>{}</sign>\n          <line>{}</line>\n        </clef>",
+            idx + 1,
+            sign,
+            line
+        )?;
+    }
+    xml.push_str("      </attributes>\n");
+    Ok(())
+}
+
+/// begin/continue/end (or hook) for each beam level of an event
+fn beam_states(events: &[Event], idx: usize) -> Vec<&'static str> {
+    let event = &events[idx];
+    let Some(group) = event.beam_group else {
+        return Vec::new();
+    };
+
+    let beams_at = |i: usize| -> u32 {
+        events
+            .get(i)
+            .filter(|e| e.beam_group == Some(group))
+            .map_or(0, |e| e.value.note_type.beams())
+    };
+    let before = if idx > 0 { beams_at(idx - 1) } else { 0 };
+    let after = beams_at(idx + 1);
+
+    (1..=event.value.note_type.beams())
+        .map(|level| match (before >= level, after >= level) {
+            (false, true) => "begin",
+            (true, true) => "continue",
+            (true, false) => "end",
+            // a lone sixteenth in a group gets a hook pointing into it
+            (false, false) if after > 0 => "forward hook",
+            (false, false) => "backward hook",
+        })
+        .collect()
+}
+
+fn write_event(
+    xml: &mut String,
+    event: &Event,
+    staff: usize,
+    voice: usize,
+    beams: &[&str],
+) -> Result<()> {
+    if event.is_rest() {
+        xml.push_str("      <note>\n");
+        if event.measure_rest {
+            xml.push_str("        <rest measure=\"yes\"/>\n");
+        } else {
+            xml.push_str("        <rest/>\n");
+        }
+        writeln!(xml, "        <duration>{}</duration>", event.duration)?;
+        writeln!(xml, "        <voice>{}</voice>", voice)?;
+        if !event.measure_rest {
+            write_type(xml, event)?;
+        }
+        writeln!(xml, "        <staff>{}</staff>", staff)?;
+        xml.push_str("      </note>\n");
+        return Ok(());
+    }
+
+    for (note_idx, note) in event.notes.iter().enumerate() {
+        xml.push_str("      <note>\n");
+        if note_idx > 0 {
+            xml.push_str("        <chord/>\n");
+        }
+
+        xml.push_str("        <pitch>\n");
+        writeln!(xml, "          <step>{}</step>", note.pitch.step_name())?;
+        if note.pitch.alter != 0 {
+            writeln!(xml, "          <alter>{}</alter>", note.pitch.alter)?;
+        }
+        writeln!(xml, "          <octave>{}</octave>", note.pitch.octave)?;
+        xml.push_str("        </pitch>\n");
+
+        writeln!(xml, "        <duration>{}</duration>", event.duration)?;
+        if note.tie_stop {
+            xml.push_str("        <tie type=\"stop\"/>\n");
+        }
+        if note.tie_start {
+            xml.push_str("        <tie type=\"start\"/>\n");
+        }
+        writeln!(xml, "        <voice>{}</voice>", voice)?;
+        write_type(xml, event)?;
+
+        if let Some(alter) = note.accidental {
+            let name = match alter {
+                2 => "double-sharp",
+                1 => "sharp",
+                -1 => "flat",
+                -2 => "flat-flat",
+                _ => "natural",
+            };
+            writeln!(xml, "        <accidental>{}</accidental>", name)?;
+        }
+
+        writeln!(xml, "        <staff>{}</staff>", staff)?;
+
+        // beams belong to the chord as a whole, written on its first note only
+        if note_idx == 0 {
+            for (level, state) in beams.iter().enumerate() {
+                writeln!(
+                    xml,
+                    "        <beam number=\"{}\">{}</beam>",
+                    level + 1,
+                    state
+                )?;
+            }
+        }
+
+        if note.tie_start || note.tie_stop {
+            xml.push_str("        <notations>\n");
+            if note.tie_stop {
+                xml.push_str("          <tied type=\"stop\"/>\n");
+            }
+            if note.tie_start {
+                xml.push_str("          <tied type=\"start\"/>\n");
+            }
+            xml.push_str("        </notations>\n");
+        }
+
+        xml.push_str("      </note>\n");
+    }
+
+    Ok(())
+}
+
+fn write_type(xml: &mut String, event: &Event) -> Result<()> {
+    let name = match event.value.note_type {
+        NoteType::Whole => "whole",
+        NoteType::Half => "half",
+        NoteType::Quarter => "quarter",
+        NoteType::Eighth => "eighth",
+        NoteType::Sixteenth => "16th",
+    };
+    writeln!(xml, "        <type>{}</type>", name)?;
+    if event.value.dotted {
+        xml.push_str("        <dot/>\n");
+    }
+    Ok(())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
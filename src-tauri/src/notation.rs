@@ -0,0 +1,515 @@
+use crate::midi::{KeySignature, MidiScore, TimeSignature};
+use std::collections::HashMap;
+
+/// quantization grid in divisions of a quarter note (16th notes)
+pub const DIVISIONS: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clef {
+    Treble,
+    Bass,
+}
+
+impl Clef {
+    /// diatonic index (octave * 7 + step) of the note on the bottom staff line
+    pub fn bottom_line(self) -> i32 {
+        match self {
+            Clef::Treble => 4 * 7 + 2, // e4
+            Clef::Bass => 2 * 7 + 4,   // g2
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteType {
+    Whole,
+    Half,
+    Quarter,
+    Eighth,
+    Sixteenth,
+}
+
+impl NoteType {
+    pub fn units(self) -> u32 {
+        match self {
+            NoteType::Whole => 4 * DIVISIONS,
+            NoteType::Half => 2 * DIVISIONS,
+            NoteType::Quarter => DIVISIONS,
+            NoteType::Eighth => DIVISIONS / 2,
+            NoteType::Sixteenth => DIVISIONS / 4,
+        }
+    }
+
+    /// number of flags or beams this value carries
+    pub fn beams(self) -> u32 {
+        match self {
+            NoteType::Eighth => 1,
+            NoteType::Sixteenth => 2,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct NoteValue {
+    pub note_type: NoteType,
+    pub dotted: bool,
+}
+
+impl NoteValue {
+    pub fn units(self) -> u32 {
+        let units = self.note_type.units();
+        if self.dotted {
+            units + units / 2
+        } else {
+            units
+        }
+    }
+}
+
+/// notatable values, largest first, with the grid alignment their start needs.
+/// the alignment keeps syncopations readable (no half notes starting off the beat)
+const VALUE_TABLE: [(NoteType, bool, u32); 8] = [
+    (NoteType::Whole, false, 16),
+    (NoteType::Half, true, 4),
+    (NoteType::Half, false, 4),
+    (NoteType::Quarter, true, 2),
+    (NoteType::Quarter, false, 2),
+    (NoteType::Eighth, true, 2),
+    (NoteType::Eighth, false, 1),
+    (NoteType::Sixteenth, false, 1),
+];
+
+/// spelled pitch, step 0..7 is c..b
+#[derive(Debug, Clone, Copy)]
+pub struct Pitch {
+    pub step: u8,
+    pub alter: i8,
+    pub octave: i8,
+}
+
+impl Pitch {
+    /// diatonic steps from c0, used for vertical placement
+    pub fn diatonic(&self) -> i32 {
+        self.octave as i32 * 7 + self.step as i32
+    }
+
+    pub fn step_name(&self) -> &'static str {
+        ["C", "D", "E", "F", "G", "A", "B"][self.step as usize]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Note {
+    pub midi: u8,
+    pub pitch: Pitch,
+    /// alteration to print in front of the note, if any (0 = natural sign)
+    pub accidental: Option<i8>,
+    pub tie_start: bool,
+    pub tie_stop: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    /// offset from the start of the measure in grid units
+    pub position: u32,
+    pub duration: u32,
+    pub value: NoteValue,
+    /// empty for rests
+    pub notes: Vec<Note>,
+    /// rest filling the whole measure, drawn centred whatever the meter
+    pub measure_rest: bool,
+    /// events sharing an id within a staff are beamed together
+    pub beam_group: Option<u32>,
+}
+
+impl Event {
+    pub fn is_rest(&self) -> bool {
+        self.notes.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Staff {
+    pub clef: Clef,
+    pub events: Vec<Event>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Measure {
+    pub number: u32,
+    /// treble then bass
+    pub staves: Vec<Staff>,
+}
+
+/// quantized grand-staff notation ready for engraving or export
+#[derive(Debug, Clone)]
+pub struct Score {
+    pub measures: Vec<Measure>,
+    pub time_signature: TimeSignature,
+    pub key_signature: KeySignature,
+    pub tempo_bpm: f64,
+    /// grid units per measure
+    pub measure_length: u32,
+}
+
+const NATURAL_PITCH_CLASS: [i8; 7] = [0, 2, 4, 5, 7, 9, 11];
+const SHARP_ORDER: [usize; 7] = [3, 0, 4, 1, 5, 2, 6]; // f c g d a e b
+const FLAT_ORDER: [usize; 7] = [6, 2, 5, 1, 4, 0, 3]; // b e a d g c f
+
+/// alteration the key signature applies to each step
+pub fn key_alters(key: KeySignature) -> [i8; 7] {
+    let mut alters = [0i8; 7];
+    let count = key.fifths.unsigned_abs().min(7) as usize;
+    if key.fifths > 0 {
+        for &step in &SHARP_ORDER[..count] {
+            alters[step] = 1;
+        }
+    } else {
+        for &step in &FLAT_ORDER[..count] {
+            alters[step] = -1;
+        }
+    }
+    alters
+}
+
+/// picks a spelling for each pitch class: notes of the key scale keep their key
+/// spelling, chromatic notes use sharps in sharp keys and flats in flat keys
+fn spelling_table(key: KeySignature) -> [(u8, i8); 12] {
+    let mut table = if key.fifths >= 0 {
+        [
+            (0, 0),
+            (0, 1),
+            (1, 0),
+            (1, 1),
+            (2, 0),
+            (3, 0),
+            (3, 1),
+            (4, 0),
+            (4, 1),
+            (5, 0),
+            (5, 1),
+            (6, 0),
+        ]
+    } else {
+        [
+            (0, 0),
+            (1, -1),
+            (1, 0),
+            (2, -1),
+            (2, 0),
+            (3, 0),
+            (4, -1),
+            (4, 0),
+            (5, -1),
+            (5, 0),
+            (6, -1),
+            (6, 0),
+        ]
+    };
+
+    for (step, alter) in key_alters(key).iter().enumerate() {
+        let pitch_class = (NATURAL_PITCH_CLASS[step] + alter).rem_euclid(12) as usize;
+        table[pitch_class] = (step as u8, *alter);
+    }
+
+    table
+}
+
+fn spell(midi: u8, table: &[(u8, i8); 12]) -> Pitch {
+    let (step, alter) = table[midi as usize % 12];
+    // cb and b# sit in a different octave than their pitch class suggests
+    let octave = (midi as i32 - alter as i32).div_euclid(12) - 1;
+    Pitch {
+        step,
+        alter,
+        octave: octave as i8,
+    }
+}
+
+/// splits `length` units starting at `position` into notatable values
+fn decompose(mut position: u32, mut length: u32) -> Vec<NoteValue> {
+    let mut values = Vec::new();
+    while length > 0 {
+        let (note_type, dotted, _) = VALUE_TABLE
+            .iter()
+            .copied()
+            .find(|&(note_type, dotted, align)| {
+                let value = NoteValue { note_type, dotted };
+                value.units() <= length && position.is_multiple_of(align)
+            })
+            .unwrap_or((NoteType::Sixteenth, false, 1));
+
+        let value = NoteValue { note_type, dotted };
+        values.push(value);
+        position += value.units();
+        length -= value.units();
+    }
+    values
+}
+
+/// a chord or rest on the quantized timeline, in absolute grid units
+struct Span {
+    start: u32,
+    end: u32,
+    pitches: Vec<u8>,
+}
+
+/// quantizes one hand into non-overlapping chords. notes struck together form
+/// a chord that lasts until its shortest note ends or the next chord starts
+fn quantize_voice(notes: &[(u32, u32, u8)]) -> Vec<Span> {
+    let mut chords: Vec<Span> = Vec::new();
+    for &(start, end, pitch) in notes {
+        match chords.last_mut() {
+            Some(chord) if chord.start == start => {
+                chord.end = chord.end.min(end);
+                if !chord.pitches.contains(&pitch) {
+                    chord.pitches.push(pitch);
+                }
+            }
+            _ => chords.push(Span {
+                start,
+                end,
+                pitches: vec![pitch],
+            }),
+        }
+    }
+
+    for i in 0..chords.len().saturating_sub(1) {
+        let next_start = chords[i + 1].start;
+        chords[i].end = chords[i].end.min(next_start);
+    }
+
+    for chord in &mut chords {
+        chord.pitches.sort_unstable();
+    }
+
+    chords
+}
+
+/// builds a two-staff score from midi: quantizes to 16ths, splits hands at
+/// `hand_split`, cuts at barlines with ties, spells accidentals and beams
+pub fn build_score(midi: &MidiScore, hand_split: u8) -> Score {
+    let mut time_signature = midi.time_signature;
+    let mut measure_length =
+        time_signature.numerator as u32 * 4 * DIVISIONS / time_signature.denominator.max(1) as u32;
+    if measure_length == 0 || time_signature.denominator > 16 {
+        // meters finer than our grid fall back to common time
+        time_signature = TimeSignature {
+            numerator: 4,
+            denominator: 4,
+        };
+        measure_length = 4 * DIVISIONS;
+    }
+
+    let to_units = |seconds: f64| {
+        (midi.seconds_to_beats(seconds) * DIVISIONS as f64)
+            .round()
+            .max(0.0) as u32
+    };
+
+    let mut hands: [Vec<(u32, u32, u8)>; 2] = [Vec::new(), Vec::new()];
+    for note in &midi.notes {
+        let start = to_units(note.onset);
+        let end = to_units(note.offset).max(start + 1);
+        let hand = if note.pitch >= hand_split { 0 } else { 1 };
+        hands[hand].push((start, end, note.pitch));
+    }
+
+    let voices: Vec<Vec<Span>> = hands
+        .iter_mut()
+        .map(|notes| {
+            notes.sort_unstable();
+            quantize_voice(notes)
+        })
+        .collect();
+
+    let total_units = voices
+        .iter()
+        .filter_map(|v| v.last().map(|c| c.end))
+        .max()
+        .unwrap_or(0);
+    let n_measures = total_units.div_ceil(measure_length).max(1);
+    let score_end = n_measures * measure_length;
+
+    let spelling = spelling_table(midi.key_signature);
+    let alters = key_alters(midi.key_signature);
+
+    let mut measures: Vec<Measure> = (0..n_measures)
+        .map(|i| Measure {
+            number: i + 1,
+            staves: vec![
+                Staff {
+                    clef: Clef::Treble,
+                    events: Vec::new(),
+                },
+                Staff {
+                    clef: Clef::Bass,
+                    events: Vec::new(),
+                },
+            ],
+        })
+        .collect();
+
+    for (staff_idx, voice) in voices.iter().enumerate() {
+        // fill gaps with rests so every staff covers the whole score
+        let mut timeline: Vec<Span> = Vec::new();
+        let mut cursor = 0;
+        for chord in voice {
+            if chord.start > cursor {
+                timeline.push(Span {
+                    start: cursor,
+                    end: chord.start,
+                    pitches: Vec::new(),
+                });
+            }
+            timeline.push(Span {
+                start: chord.start,
+                end: chord.end,
+                pitches: chord.pitches.clone(),
+            });
+            cursor = chord.end;
+        }
+        if cursor < score_end {
+            timeline.push(Span {
+                start: cursor,
+                end: score_end,
+                pitches: Vec::new(),
+            });
+        }
+
+        for span in &timeline {
+            // pieces of the span, cut at barlines and into notatable values
+            let mut pieces: Vec<(usize, u32, NoteValue)> = Vec::new();
+            let mut start = span.start;
+            while start < span.end {
+                let measure_idx = (start / measure_length) as usize;
+                let measure_start = measure_idx as u32 * measure_length;
+                let end = span.end.min(measure_start + measure_length);
+                let position = start - measure_start;
+
+                if span.pitches.is_empty() && end - start == measure_length {
+                    measures[measure_idx].staves[staff_idx].events.push(Event {
+                        position: 0,
+                        duration: measure_length,
+                        value: NoteValue {
+                            note_type: NoteType::Whole,
+                            dotted: false,
+                        },
+                        notes: Vec::new(),
+                        measure_rest: true,
+                        beam_group: None,
+                    });
+                } else {
+                    let mut offset = position;
+                    for value in decompose(position, end - start) {
+                        pieces.push((measure_idx, offset, value));
+                        offset += value.units();
+                    }
+                }
+                start = end;
+            }
+
+            let n_pieces = pieces.len();
+            for (i, (measure_idx, position, value)) in pieces.into_iter().enumerate() {
+                let notes = span
+                    .pitches
+                    .iter()
+                    .map(|&midi| Note {
+                        midi,
+                        pitch: spell(midi, &spelling),
+                        accidental: None,
+                        tie_start: i + 1 < n_pieces,
+                        tie_stop: i > 0,
+                    })
+                    .collect();
+
+                measures[measure_idx].staves[staff_idx].events.push(Event {
+                    position,
+                    duration: value.units(),
+                    value,
+                    notes,
+                    measure_rest: false,
+                    beam_group: None,
+                });
+            }
+        }
+    }
+
+    // beats for beaming: dotted quarters in compound meters, else the denominator
+    let beat_length =
+        if time_signature.denominator == 8 && time_signature.numerator.is_multiple_of(3) {
+            3 * DIVISIONS / 2
+        } else {
+            4 * DIVISIONS / time_signature.denominator as u32
+        };
+
+    let mut next_beam_id = 0;
+    for measure in &mut measures {
+        for staff in &mut measure.staves {
+            assign_accidentals(staff, &alters);
+            assign_beams(staff, beat_length.max(1), &mut next_beam_id);
+        }
+    }
+
+    Score {
+        measures,
+        time_signature,
+        key_signature: midi.key_signature,
+        tempo_bpm: midi.tempo_map.first().map_or(120.0, |t| t.bpm),
+        measure_length,
+    }
+}
+
+/// marks notes whose alteration differs from what the key signature and
+/// earlier accidentals in the measure imply. tied continuations never print one
+fn assign_accidentals(staff: &mut Staff, key_alters: &[i8; 7]) {
+    let mut state: HashMap<(u8, i8), i8> = HashMap::new();
+    for event in &mut staff.events {
+        for note in &mut event.notes {
+            let key = (note.pitch.step, note.pitch.octave);
+            let current = state
+                .get(&key)
+                .copied()
+                .unwrap_or(key_alters[note.pitch.step as usize]);
+            if note.pitch.alter != current && !note.tie_stop {
+                note.accidental = Some(note.pitch.alter);
+            }
+            state.insert(key, note.pitch.alter);
+        }
+    }
+}
+
+/// beams runs of two or more eighths/sixteenths that fall inside the same beat
+fn assign_beams(staff: &mut Staff, beat_length: u32, next_id: &mut u32) {
+    let mut group: Vec<usize> = Vec::new();
+    let mut group_beat = 0;
+
+    for idx in 0..staff.events.len() {
+        let event = &staff.events[idx];
+        let beat = event.position / beat_length;
+        let end_beat = (event.position + event.duration - 1) / beat_length;
+        let beamable = !event.is_rest() && event.value.note_type.beams() > 0 && beat == end_beat;
+
+        if !beamable || (!group.is_empty() && beat != group_beat) {
+            close_beam_group(&mut group, &mut staff.events, next_id);
+        }
+        if beamable {
+            if group.is_empty() {
+                group_beat = beat;
+            }
+            group.push(idx);
+        }
+    }
+    close_beam_group(&mut group, &mut staff.events, next_id);
+}
+
+fn close_beam_group(group: &mut Vec<usize>, events: &mut [Event], next_id: &mut u32) {
+    if group.len() >= 2 {
+        for &idx in group.iter() {
+            events[idx].beam_group = Some(*next_id);
+        }
+        *next_id += 1;
+    }
+    group.clear();
+}
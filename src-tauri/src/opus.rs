@@ -0,0 +1,374 @@
+use crate::audio_io::StreamResampler;
+use crate::streaming::Stereo;
+use anyhow::{anyhow, bail, Context, Result};
+use audiopus::coder::{Decoder, Encoder};
+use audiopus::packet::Packet;
+use audiopus::{Application, Bitrate, Channels, MutSignals, SampleRate};
+use ogg::reading::PacketReader;
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// opus always runs at 48 khz, other rates are converted on the way in
+pub const OPUS_RATE: u32 = 48000;
+
+/// longest duration a single packet can decode to, 120 ms
+const MAX_PACKET_FRAMES: usize = 5760;
+
+/// 20 ms per packet, what most encoders use for music
+const FRAME_SIZE: usize = 960;
+
+/// stereo bitrate, transparent for nearly all material
+const OPUS_BITRATE: i32 = 160_000;
+
+/// upper bound of a single opus packet
+const MAX_PACKET_SIZE: usize = 4000;
+
+/// serial number of the one logical stream in the file
+const STREAM_SERIAL: u32 = 0x6c61_6c61;
+
+/// stereo ogg opus writer, see rfc 7845 for the container mapping
+pub struct OpusWriter {
+    writer: PacketWriter<BufWriter<File>>,
+    encoder: Encoder,
+    resamplers: Option<[StreamResampler; 2]>,
+    /// interleaved 48 khz samples not encoded yet
+    pending: Vec<f32>,
+    /// samples the decoder drops at the start, the encoder's lookahead
+    pre_skip: u64,
+    /// input frames at 48 khz, the decoded length once pre-skip and padding are trimmed
+    input_frames: u64,
+    encoded_frames: u64,
+    /// the latest packet and its granule position. held back so the last one can
+    /// end the stream with the exact length
+    queued: Option<(Vec<u8>, u64)>,
+}
+
+impl OpusWriter {
+    pub fn create(path: &Path, sample_rate: u32) -> Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+
+        let mut encoder = Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio)?;
+        encoder.set_bitrate(Bitrate::BitsPerSecond(OPUS_BITRATE))?;
+        let pre_skip = encoder.lookahead()? as u64;
+
+        let mut writer = PacketWriter::new(BufWriter::new(file));
+
+        // identification header, alone on the first page
+        let mut head = Vec::with_capacity(19);
+        head.extend_from_slice(b"OpusHead");
+        head.push(1); // version
+        head.push(2); // channels
+        head.extend_from_slice(&(pre_skip as u16).to_le_bytes());
+        head.extend_from_slice(&sample_rate.to_le_bytes()); // original rate, informational
+        head.extend_from_slice(&0i16.to_le_bytes()); // output gain
+        head.push(0); // channel mapping family 0, mono or stereo
+        writer.write_packet(
+            head.into_boxed_slice(),
+            STREAM_SERIAL,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )?;
+
+        // comment header with no comments
+        let vendor = concat!("lala ", env!("CARGO_PKG_VERSION"));
+        let mut tags = Vec::new();
+        tags.extend_from_slice(b"OpusTags");
+        tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        tags.extend_from_slice(vendor.as_bytes());
+        tags.extend_from_slice(&0u32.to_le_bytes());
+        writer.write_packet(
+            tags.into_boxed_slice(),
+            STREAM_SERIAL,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )?;
+
+        let resamplers = (sample_rate != OPUS_RATE).then(|| {
+            [
+                StreamResampler::new(sample_rate, OPUS_RATE),
+                StreamResampler::new(sample_rate, OPUS_RATE),
+            ]
+        });
+
+        Ok(Self {
+            writer,
+            encoder,
+            resamplers,
+            pending: Vec::new(),
+            pre_skip,
+            input_frames: 0,
+            encoded_frames: 0,
+            queued: None,
+        })
+    }
+
+    pub fn write(&mut self, block: &Stereo) -> Result<()> {
+        match &mut self.resamplers {
+            Some([left, right]) => {
+                let resampled = [left.push(&block[0]), right.push(&block[1])];
+                self.push(&resampled)
+            }
+            None => self.push(block),
+        }
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        if let Some([left, right]) = &mut self.resamplers {
+            let tail = [left.finish(), right.finish()];
+            self.push(&tail)?;
+        }
+
+        // feed the lookahead's worth of silence so the last real samples get
+        // encoded, then pad to a whole packet
+        let padding = self.pre_skip as usize * 2;
+        self.pending.resize(self.pending.len() + padding, 0.0);
+        let partial = self.pending.len() % (FRAME_SIZE * 2);
+        if partial > 0 {
+            self.pending
+                .resize(self.pending.len() + FRAME_SIZE * 2 - partial, 0.0);
+        }
+        self.encode_pending()?;
+
+        // the last granule position trims the padding off again
+        if let Some((packet, _)) = self.queued.take() {
+            self.writer.write_packet(
+                packet.into_boxed_slice(),
+                STREAM_SERIAL,
+                PacketWriteEndInfo::EndStream,
+                self.pre_skip + self.input_frames,
+            )?;
+        }
+
+        self.writer.into_inner().flush()?;
+        Ok(())
+    }
+
+    fn push(&mut self, block: &Stereo) -> Result<()> {
+        for (left, right) in block[0].iter().zip(&block[1]) {
+            self.pending.push(*left);
+            self.pending.push(*right);
+        }
+        self.input_frames += block[0].len() as u64;
+        self.encode_pending()
+    }
+
+    /// encodes every whole packet in `pending`
+    fn encode_pending(&mut self) -> Result<()> {
+        let mut packet = vec![0u8; MAX_PACKET_SIZE];
+        let whole = self.pending.len() / (FRAME_SIZE * 2) * (FRAME_SIZE * 2);
+
+        for frame in self.pending[..whole].chunks_exact(FRAME_SIZE * 2) {
+            let len = self.encoder.encode_float(frame, &mut packet)?;
+            self.encoded_frames += FRAME_SIZE as u64;
+
+            if let Some((previous, granule)) = self
+                .queued
+                .replace((packet[..len].to_vec(), self.encoded_frames))
+            {
+                self.writer.write_packet(
+                    previous.into_boxed_slice(),
+                    STREAM_SERIAL,
+                    PacketWriteEndInfo::NormalPacket,
+                    granule,
+                )?;
+            }
+        }
+
+        self.pending.drain(..whole);
+        Ok(())
+    }
+}
+
+/// mono or stereo ogg opus reader. symphonia finds opus in ogg but can't decode
+/// it, so the packets go to libopus here, with pre-skip and end padding trimmed
+/// the way rfc 7845 describes
+pub struct OpusReader {
+    reader: PacketReader<BufReader<File>>,
+    decoder: Decoder,
+    serial: u32,
+    channels: usize,
+    pre_skip: u64,
+    /// output gain from the header, as a linear factor
+    gain: f32,
+    /// granule position after the last decoded packet, pre-skip included
+    position: u64,
+    buffer: Vec<f32>,
+}
+
+impl OpusReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let mut reader = PacketReader::new(BufReader::new(file));
+
+        let head = reader
+            .read_packet()?
+            .ok_or_else(|| anyhow!("{} is empty", path.display()))?;
+        if head.data.len() < 19 || &head.data[..8] != b"OpusHead" {
+            bail!("{} is not an ogg opus file", path.display());
+        }
+        let channels = head.data[9] as usize;
+        let pre_skip = u16::from_le_bytes([head.data[10], head.data[11]]) as u64;
+        let gain_q8 = i16::from_le_bytes([head.data[16], head.data[17]]);
+        let mapping_family = head.data[18];
+        let decoder_channels = match (channels, mapping_family) {
+            (1, 0 | 1) => Channels::Mono,
+            (2, 0 | 1) => Channels::Stereo,
+            _ => bail!(
+                "opus with {} channels isn't supported, please convert {} to flac or wav",
+                channels,
+                path.display()
+            ),
+        };
+
+        let serial = head.stream_serial();
+        let tags = reader
+            .read_packet()?
+            .ok_or_else(|| anyhow!("{} has no opus comment header", path.display()))?;
+        if !tags.data.starts_with(b"OpusTags") {
+            bail!("{} has no opus comment header", path.display());
+        }
+
+        Ok(Self {
+            reader,
+            decoder: Decoder::new(SampleRate::Hz48000, decoder_channels)?,
+            serial,
+            channels,
+            pre_skip,
+            gain: 10f32.powf(gain_q8 as f32 / (20.0 * 256.0)),
+            position: 0,
+            buffer: vec![0.0; MAX_PACKET_FRAMES * channels],
+        })
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// interleaved 48 khz samples of the next packet, None at the end of the stream
+    pub fn next_samples(&mut self) -> Result<Option<Vec<f32>>> {
+        loop {
+            let Some(packet) = self.reader.read_packet()? else {
+                return Ok(None);
+            };
+            if packet.stream_serial() != self.serial {
+                continue;
+            }
+
+            let input = Packet::try_from(&packet.data[..])?;
+            let output = MutSignals::try_from(&mut self.buffer[..])?;
+            let frames = match self.decoder.decode_float(Some(input), output, false) {
+                Ok(frames) => frames as u64,
+                Err(e) => {
+                    // a corrupt packet shouldn't sink the whole file
+                    eprintln!("skipping undecodable packet: {}", e);
+                    continue;
+                }
+            };
+
+            let start = self.position;
+            self.position += frames;
+
+            // the first pre_skip frames are the encoder's warm-up, and the last
+            // granule position cuts the padding the final packet was filled with
+            let first = self.pre_skip.saturating_sub(start).min(frames);
+            let end = if packet.last_in_stream() {
+                packet
+                    .absgp_page()
+                    .saturating_sub(start)
+                    .clamp(first, frames)
+            } else {
+                frames
+            };
+            if first == end {
+                continue;
+            }
+
+            let samples = self.buffer[first as usize * self.channels..end as usize * self.channels]
+                .iter()
+                .map(|s| s * self.gain)
+                .collect();
+            return Ok(Some(samples));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a steady tone, so its level right up to the end can be checked
+    fn tone(n: usize, sample_rate: u32) -> Stereo {
+        let channel = (0..n)
+            .map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                (2.0 * std::f32::consts::PI * 440.0 * t).sin() * 0.5
+            })
+            .collect::<Vec<_>>();
+        [channel.clone(), channel]
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn granule_position_gives_the_exact_length() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // lengths that aren't whole packets, at the native rate and resampled
+        for (sample_rate, n) in [(48000, 48000 + 123), (44100, 2 * 44100 + 77)] {
+            let input = tone(n, sample_rate);
+            let path = dir.path().join(format!("{}.opus", sample_rate));
+
+            let mut writer = OpusWriter::create(&path, sample_rate).unwrap();
+            // blocks that don't line up with the packets either
+            for start in (0..n).step_by(5000) {
+                let end = (start + 5000).min(n);
+                writer
+                    .write(&[input[0][start..end].to_vec(), input[1][start..end].to_vec()])
+                    .unwrap();
+            }
+            writer.finish().unwrap();
+
+            let mut reader = PacketReader::new(File::open(&path).unwrap());
+            let head = reader.read_packet_expected().unwrap();
+            assert_eq!(&head.data[..8], b"OpusHead");
+            let pre_skip = u16::from_le_bytes([head.data[10], head.data[11]]) as u64;
+            let tags = reader.read_packet_expected().unwrap();
+            assert_eq!(&tags.data[..8], b"OpusTags");
+
+            let mut decoder = Decoder::new(SampleRate::Hz48000, Channels::Stereo).unwrap();
+            let mut buffer = vec![0.0f32; FRAME_SIZE * 2];
+            let mut decoded = Vec::new();
+            let mut last = None;
+            while let Some(packet) = reader.read_packet().unwrap() {
+                let input = Packet::try_from(&packet.data[..]).unwrap();
+                let output = MutSignals::try_from(&mut buffer[..]).unwrap();
+                let frames = decoder.decode_float(Some(input), output, false).unwrap();
+                decoded.extend_from_slice(&buffer[..frames * 2]);
+                last = Some((packet.absgp_page(), packet.last_in_stream()));
+            }
+
+            // the last granule position minus pre-skip is the input length at 48 khz
+            let (granule, end_of_stream) = last.unwrap();
+            assert!(end_of_stream);
+            let expected = (n as u64 * OPUS_RATE as u64).div_ceil(sample_rate as u64);
+            assert_eq!(granule - pre_skip, expected, "{} hz", sample_rate);
+
+            // and every one of those frames was encoded, the tone lasts to the end
+            assert!(decoded.len() as u64 >= granule * 2);
+            let tail = &decoded[(granule as usize - 480) * 2..granule as usize * 2];
+            let level = rms(tail);
+            assert!(
+                (level - 0.35).abs() < 0.05,
+                "the last 10 ms have level {} at {} hz",
+                level,
+                sample_rate
+            );
+        }
+    }
+}
@@ -1,68 +1,334 @@
-use crate::audio_io::{load_wav_to_tensor, save_tensor_to_wav};
-use crate::demucs_model::DemucsModel;
-use anyhow::Result;
+use crate::audio_io::{load_audio_to_tensor, resample_tensor};
+use crate::cancellation::CancellationToken;
+use crate::encoding::{Clipping, OutputFormat};
+use crate::engrave::engrave_pdf;
+use crate::midi::{read_midi, write_midi, MidiScore, MidiWriteOptions};
+use crate::musicxml::write_musicxml;
+use crate::notation::build_score;
+use crate::progress::{
+    Progress, ProgressStage, ProgressTracker, MUSICXML_STAGES, PDF_STAGES, SEPARATION_STAGES,
+    TRANSCRIPTION_STAGES,
+};
+use crate::residual::{find_residual, ResidualSpec};
+use crate::separator::{separate_with_shifts, SeparationOptions, Separator};
+use crate::streaming::{
+    block_weights, spool_track, OverlapAddRing, StemWriter, Stereo, StereoReader, TrackStats,
+    BLOCK_SEGMENTS, CROSSFADE_SECONDS,
+};
+use crate::transcription::{PianoTranscriber, PIANO_TRANSCRIPTION_MODEL};
+use crate::wiener::wiener_filter;
+use anyhow::{anyhow, Result};
 use std::collections::HashMap;
 use std::path::Path;
-use std::thread;
-use std::time::Duration;
-use tch::Device;
+use tch::{Device, Tensor};
 
-/// wrapper around demucs separation that returns file paths
+/// a stem written by `separate_audio`
+pub struct SeparatedStem {
+    pub path: String,
+    pub clipping: Clipping,
+}
+
+/// runs a separation backend over a file and writes the stems in `format`, plus
+/// the residual stems `options` asks for, keyed by their id.
+/// audio is resampled to the model rate for inference; with `restore_sample_rate`
+/// the stems are converted back to the source rate before writing.
+/// the file is decoded once to a spool at the model rate, then streamed in blocks
+/// of a few model segments that are crossfaded in a small ring buffer and flushed
+/// to the stem files as they finish, so memory stays bounded however long the
+/// recording is.
+/// a cancelled run removes any stems it already wrote
+#[allow(clippy::too_many_arguments)]
 pub fn separate_audio<F>(
     input_path: &Path,
     output_dir: &Path,
-    model_path: &Path,
-    mut progress_callback: F,
-) -> Result<HashMap<String, String>>
+    separator: &dyn Separator,
+    options: &SeparationOptions,
+    format: OutputFormat,
+    restore_sample_rate: bool,
+    cancel: &CancellationToken,
+    progress_callback: F,
+) -> Result<HashMap<String, SeparatedStem>>
 where
-    F: FnMut(f32),
+    F: FnMut(&Progress),
 {
-    let device = if tch::Cuda::is_available() {
-        Device::Cuda(0)
+    let mut progress = ProgressTracker::new(SEPARATION_STAGES, progress_callback);
+    progress.stage(ProgressStage::Loading);
+
+    // one pass for the length and the whole-track level every block is normalized
+    // with, leaving the model-rate audio for the blocks to read
+    let model_rate = separator.sample_rate();
+    let spool_path = output_dir.join("separation.spool");
+    let stats = match spool_track(input_path, &spool_path, model_rate, cancel) {
+        Ok(stats) => stats,
+        Err(e) => {
+            let _ = std::fs::remove_file(&spool_path);
+            return Err(e);
+        }
+    };
+    progress.set_audio_seconds(stats.seconds());
+
+    let output_rate = if restore_sample_rate {
+        stats.sample_rate
+    } else {
+        model_rate
+    };
+    let n_output_frames = if restore_sample_rate {
+        stats.n_frames
     } else {
-        Device::Cpu
+        stats.frames_at(model_rate)
     };
 
-    progress_callback(0.03); // loading
+    // a residual needs every stem it leaves out, skip the ones this backend can't make
+    let residuals = options
+        .residuals
+        .iter()
+        .map(|id| find_residual(id))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|spec| {
+            let supported = spec.is_supported_by(separator.stems());
+            if !supported {
+                println!(
+                    "skipping {} stem, the backend doesn't separate {}",
+                    spec.id,
+                    spec.excludes.join(", ")
+                );
+            }
+            supported
+        })
+        .collect::<Vec<_>>();
 
-    let (audio_tensor, sample_rate) = load_wav_to_tensor(input_path, device)?;
+    // write in the backend's stem order so runs are reproducible, residuals after
+    let output_paths = separator
+        .stems()
+        .iter()
+        .map(|stem_name| {
+            (
+                stem_name.clone(),
+                output_dir.join(format!("stem_{}.{}", stem_name, format.extension())),
+            )
+        })
+        .chain(residuals.iter().map(|spec| {
+            (
+                spec.id.to_string(),
+                output_dir.join(format!("{}.{}", spec.id, format.extension())),
+            )
+        }))
+        .collect::<Vec<_>>();
 
-    progress_callback(0.06); // loaded
+    let written = cancel.check().and_then(|_| {
+        let writers = output_paths
+            .iter()
+            .map(|(_, path)| {
+                StemWriter::create(path, format, model_rate, output_rate, n_output_frames)
+            })
+            .collect::<Result<Vec<_>>>()?;
 
-    let demucs = DemucsModel::new(model_path)?;
+        stream_stems(
+            &spool_path,
+            &stats,
+            separator,
+            options,
+            &residuals,
+            writers,
+            cancel,
+            &mut progress,
+        )
+    });
 
-    progress_callback(0.1); // model loaded
+    let _ = std::fs::remove_file(&spool_path);
+    let clipping = match written {
+        Ok(clipping) => clipping,
+        Err(e) => {
+            // don't leave a partial set of stems behind
+            for (_, path) in &output_paths {
+                let _ = std::fs::remove_file(path);
+            }
+            return Err(e);
+        }
+    };
 
-    let stems = demucs.separate(&audio_tensor, |current, total| {
-        // map separation progress to 0.3 - 0.9 range
-        let separation_progress = current as f32 / total as f32;
-        let overall_progress = 0.1 + (separation_progress * 0.85 / 100.0);
-        progress_callback(overall_progress);
-    })?;
+    progress.finish();
+
+    Ok(output_paths
+        .into_iter()
+        .zip(clipping)
+        .map(|((stem_name, path), clipping)| {
+            let stem = SeparatedStem {
+                path: path.to_string_lossy().to_string(),
+                clipping,
+            };
+            (stem_name, stem)
+        })
+        .collect())
+}
+
+/// separates the spooled track block by block into `writers`, one per stem of
+/// `separator` followed by one per residual. returns what clipped in each stem
+#[allow(clippy::too_many_arguments)]
+fn stream_stems<F>(
+    spool_path: &Path,
+    stats: &TrackStats,
+    separator: &dyn Separator,
+    options: &SeparationOptions,
+    residuals: &[&ResidualSpec],
+    mut writers: Vec<StemWriter>,
+    cancel: &CancellationToken,
+    progress: &mut ProgressTracker<F>,
+) -> Result<Vec<Clipping>>
+where
+    F: FnMut(&Progress),
+{
+    let model_rate = separator.sample_rate();
+    let fade = (CROSSFADE_SECONDS * model_rate as f64) as usize;
+    let hop = BLOCK_SEGMENTS * separator.segment_length();
+    let block_length = hop + fade;
+
+    let n_frames = stats.frames_at(model_rate);
+    let n_blocks = if n_frames <= block_length {
+        1
+    } else {
+        (n_frames - block_length).div_ceil(hop) + 1
+    };
+
+    // blocks only see part of the track, hand them the level of all of it
+    let options = SeparationOptions {
+        track_stats: Some((stats.mean, stats.std)),
+        ..options.clone()
+    };
+
+    progress.stage(ProgressStage::Separating);
+
+    let mut reader = StereoReader::open(spool_path)?;
+    let mut ring = OverlapAddRing::new(writers.len());
+    let mut block = reader.read(block_length)?;
+    let mut offset = 0;
+
+    for block_idx in 0.. {
+        cancel.check()?;
+
+        let length = block[0].len();
+        let is_last = reader.at_end()?;
+        println!(
+            "block {}/{}: {:.1}s from {:.1}s",
+            block_idx + 1,
+            n_blocks,
+            length as f64 / model_rate as f64,
+            offset as f64 / model_rate as f64
+        );
+
+        let audio = Tensor::stack(
+            &[Tensor::from_slice(&block[0]), Tensor::from_slice(&block[1])],
+            0,
+        )
+        .to_device(separator.device());
+
+        // each block is separated, then filtered, both within the separating span
+        let n_blocks = n_blocks.max(block_idx + 1) as u32;
+        let filter_steps = options.wiener_iterations.map_or(0, |n| n + 1);
+        let mut separation_steps = 0;
+        let mut stems =
+            separate_with_shifts(separator, &audio, &options, cancel, &mut |done, total| {
+                separation_steps = total;
+                let steps = total + filter_steps;
+                progress.chunk(block_idx as u32 * steps + done, n_blocks * steps);
+            })?;
+
+        let mut ordered = separator
+            .stems()
+            .iter()
+            .map(|stem_name| {
+                stems
+                    .remove(stem_name)
+                    .ok_or_else(|| anyhow!("separator returned no {} stem", stem_name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // refine against this block's mix before anything is derived from the stems
+        if let Some(iterations) = options.wiener_iterations {
+            let steps = separation_steps + filter_steps;
+            ordered = wiener_filter(&audio, &ordered, iterations, cancel, &mut |done, _| {
+                progress.chunk_as(
+                    ProgressStage::Filtering,
+                    block_idx as u32 * steps + separation_steps + done,
+                    n_blocks * steps,
+                );
+            })?;
+        }
+
+        let mut separated = ordered
+            .iter()
+            .map(|stem| {
+                let stem = stem.to_device(Device::Cpu);
+                Ok([
+                    Vec::<f32>::try_from(stem.select(0, 0).contiguous())?,
+                    Vec::<f32>::try_from(stem.select(0, 1).contiguous())?,
+                ])
+            })
+            .collect::<Result<Vec<Stereo>>>()?;
+
+        // derived from this block's mix, so they crossfade like the other stems
+        let derived = residuals
+            .iter()
+            .map(|spec| {
+                spec.derive(
+                    options.residual_method,
+                    &block,
+                    separator.stems(),
+                    &separated,
+                )
+            })
+            .collect::<Vec<_>>();
+        separated.extend(derived);
 
-    progress_callback(0.95); // separation complete, saving
+        let weights = block_weights(length, fade, block_idx > 0, !is_last);
+        ring.add(offset, &separated, &weights);
 
-    let mut output_paths = HashMap::new();
+        // the next block starts at `offset + hop`, nothing before that changes anymore
+        let finished = if is_last {
+            offset + length
+        } else {
+            offset + hop
+        };
+        for (writer, stem) in writers.iter_mut().zip(ring.flush(finished)) {
+            writer.write(&stem)?;
+        }
 
-    for (stem_name, tensor) in stems {
-        let output_path = output_dir.join(format!("stem_{}.wav", stem_name));
-        save_tensor_to_wav(output_path.to_str().unwrap(), &tensor, sample_rate)?;
-        output_paths.insert(stem_name, output_path.to_string_lossy().to_string());
+        if is_last {
+            break;
+        }
+
+        // keep the crossfade region and read on from the end of this block
+        let next = reader.read(hop)?;
+        for (channel, more) in block.iter_mut().zip(next) {
+            channel.drain(..hop.min(channel.len()));
+            channel.extend(more);
+        }
+        offset += hop;
     }
 
-    progress_callback(1.0); // done
+    progress.stage(ProgressStage::Saving);
+    let n_stems = writers.len() as u32;
+    let mut clipping = Vec::with_capacity(writers.len());
+    for (idx, writer) in writers.into_iter().enumerate() {
+        cancel.check()?;
+        clipping.push(writer.finish()?);
+        progress.chunk(idx as u32 + 1, n_stems);
+    }
 
-    Ok(output_paths)
+    Ok(clipping)
 }
 
-/// fake transcription with progress
+/// transcribes a piano stem into a midi file with the piano transcription model on `device`
 pub fn transcribe_to_midi<F>(
     input_wav: &Path,
     output_midi: &Path,
-    mut progress_callback: F,
+    device: Device,
+    cancel: &CancellationToken,
+    progress_callback: F,
 ) -> Result<()>
 where
-    F: FnMut(f32),
+    F: FnMut(&Progress),
 {
     println!(
         "transcribing {} to {}...",
@@ -70,23 +336,79 @@ where
         output_midi.display()
     );
 
-    // simulate progress over 10 seconds
-    for i in 0..=10 {
-        thread::sleep(Duration::from_secs(1));
-        progress_callback(i as f32 / 10.0);
-    }
+    let mut progress = ProgressTracker::new(TRANSCRIPTION_STAGES, progress_callback);
+    progress.stage(ProgressStage::Loading);
+
+    let (audio_tensor, sample_rate) = load_audio_to_tensor(input_wav, device)?;
+    let transcriber = PianoTranscriber::new(&PIANO_TRANSCRIPTION_MODEL, device)?;
+    progress.set_audio_seconds(audio_tensor.size()[1] as f64 / sample_rate as f64);
+
+    // the model listens to a mono mixdown at its own rate
+    progress.stage(ProgressStage::Resampling);
+    let mono = ((audio_tensor.select(0, 0) + audio_tensor.select(0, 1)) * 0.5).unsqueeze(0);
+    let mono = resample_tensor(&mono, sample_rate, transcriber.sample_rate())?.squeeze_dim(0);
+
+    progress.stage(ProgressStage::Transcribing);
+    let notes = transcriber.transcribe(&mono, cancel, |done, total| {
+        progress.chunk(done, total);
+    })?;
+
+    progress.stage(ProgressStage::Saving);
+    let score = MidiScore::from_notes(notes);
+    write_midi(output_midi, &score, &MidiWriteOptions::default())?;
 
-    // fake output: just create an empty file
-    std::fs::write(output_midi, b"fake midi data")?;
+    progress.finish();
 
     println!("transcription complete");
     Ok(())
 }
 
-/// fake midi → pdf conversion with progress
-pub fn midi_to_pdf<F>(input_midi: &Path, output_pdf: &Path, mut progress_callback: F) -> Result<()>
+/// exports a midi file as editable musicxml notation. `title` becomes the work title
+pub fn midi_to_musicxml<F>(
+    input_midi: &Path,
+    output_musicxml: &Path,
+    title: &str,
+    cancel: &CancellationToken,
+    progress_callback: F,
+) -> Result<()>
 where
-    F: FnMut(f32),
+    F: FnMut(&Progress),
+{
+    println!(
+        "exporting {} to {}...",
+        input_midi.display(),
+        output_musicxml.display()
+    );
+
+    let mut progress = ProgressTracker::new(MUSICXML_STAGES, progress_callback);
+    progress.stage(ProgressStage::Loading);
+
+    let midi = read_midi(input_midi)?;
+    println!("loaded {} notes from midi", midi.notes.len());
+
+    progress.stage(ProgressStage::Notating);
+    let score = build_score(&midi, MidiWriteOptions::default().hand_split);
+
+    cancel.check()?;
+    progress.stage(ProgressStage::Saving);
+    write_musicxml(&score, title, output_musicxml)?;
+
+    progress.finish();
+
+    println!("export complete");
+    Ok(())
+}
+
+/// engraves a midi file as piano sheet music. `title` is printed on the first page
+pub fn midi_to_pdf<F>(
+    input_midi: &Path,
+    output_pdf: &Path,
+    title: &str,
+    cancel: &CancellationToken,
+    progress_callback: F,
+) -> Result<()>
+where
+    F: FnMut(&Progress),
 {
     println!(
         "converting {} to {}...",
@@ -94,14 +416,21 @@ where
         output_pdf.display()
     );
 
-    // simulate progress over 10 seconds
-    for i in 0..=10 {
-        thread::sleep(Duration::from_secs(1));
-        progress_callback(i as f32 / 10.0);
-    }
+    let mut progress = ProgressTracker::new(PDF_STAGES, progress_callback);
+    progress.stage(ProgressStage::Loading);
+
+    let midi = read_midi(input_midi)?;
+    println!("loaded {} notes from midi", midi.notes.len());
+
+    progress.stage(ProgressStage::Notating);
+    let score = build_score(&midi, MidiWriteOptions::default().hand_split);
+
+    progress.stage(ProgressStage::Engraving);
+    engrave_pdf(&score, title, output_pdf, cancel, |done, total| {
+        progress.chunk(done, total);
+    })?;
 
-    // fake output: just create an empty file
-    std::fs::write(output_pdf, b"fake pdf data")?;
+    progress.finish();
 
     println!("conversion complete");
     Ok(())
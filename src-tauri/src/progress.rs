@@ -0,0 +1,314 @@
+use serde::Serialize;
+use std::time::Instant;
+
+/// pipeline step a progress update belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressStage {
+    Loading,
+    Resampling,
+    Separating,
+    Filtering,
+    Transcribing,
+    Notating,
+    Engraving,
+    Saving,
+}
+
+impl ProgressStage {
+    pub fn label(self) -> &'static str {
+        match self {
+            ProgressStage::Loading => "loading",
+            ProgressStage::Resampling => "resampling",
+            ProgressStage::Separating => "separating",
+            ProgressStage::Filtering => "filtering",
+            ProgressStage::Transcribing => "transcribing",
+            ProgressStage::Notating => "notating",
+            ProgressStage::Engraving => "engraving",
+            ProgressStage::Saving => "saving",
+        }
+    }
+
+    /// stages whose chunks walk through the audio, throughput only makes sense there
+    fn processes_audio(self) -> bool {
+        matches!(
+            self,
+            ProgressStage::Separating | ProgressStage::Filtering | ProgressStage::Transcribing
+        )
+    }
+}
+
+/// stage layouts, each stage gets the given share of the overall bar
+pub const SEPARATION_STAGES: &[(ProgressStage, f32)] = &[
+    (ProgressStage::Loading, 0.05),
+    (ProgressStage::Separating, 0.9),
+    (ProgressStage::Saving, 0.05),
+];
+
+pub const TRANSCRIPTION_STAGES: &[(ProgressStage, f32)] = &[
+    (ProgressStage::Loading, 0.05),
+    (ProgressStage::Resampling, 0.05),
+    (ProgressStage::Transcribing, 0.85),
+    (ProgressStage::Saving, 0.05),
+];
+
+pub const MUSICXML_STAGES: &[(ProgressStage, f32)] = &[
+    (ProgressStage::Loading, 0.2),
+    (ProgressStage::Notating, 0.4),
+    (ProgressStage::Saving, 0.4),
+];
+
+pub const PDF_STAGES: &[(ProgressStage, f32)] = &[
+    (ProgressStage::Loading, 0.1),
+    (ProgressStage::Notating, 0.1),
+    (ProgressStage::Engraving, 0.8),
+];
+
+/// one progress update of a running job
+#[derive(Debug, Clone, Serialize)]
+pub struct Progress {
+    pub stage: ProgressStage,
+    /// overall completion 0.0 to 1.0, never decreases within a job
+    pub fraction: f32,
+    /// chunks done and total in the current stage, for chunked stages
+    pub chunk: Option<(u32, u32)>,
+    pub eta_seconds: Option<f64>,
+    /// seconds of audio processed per second of wall time
+    pub throughput: Option<f64>,
+}
+
+impl Progress {
+    /// short human readable summary, e.g. "separating 3/12, ~40s left"
+    pub fn describe(&self) -> String {
+        let mut text = self.stage.label().to_string();
+        if let Some((done, total)) = self.chunk {
+            text.push_str(&format!(" {}/{}", done, total));
+        }
+        if let Some(throughput) = self.throughput {
+            text.push_str(&format!(" ({:.1}x)", throughput));
+        }
+        if let Some(eta) = self.eta_seconds {
+            let eta = eta.round() as u64;
+            if eta >= 60 {
+                text.push_str(&format!(", ~{}m{:02}s left", eta / 60, eta % 60));
+            } else {
+                text.push_str(&format!(", ~{}s left", eta));
+            }
+        }
+        text
+    }
+}
+
+/// turns stage-local updates into a monotonic overall fraction. each stage
+/// owns a fixed span of the bar, and updates never move the bar backwards
+pub struct ProgressTracker<F: FnMut(&Progress)> {
+    stages: &'static [(ProgressStage, f32)],
+    current: usize,
+    /// overall fraction at the start of the current stage
+    stage_start: f32,
+    stage_started_at: Instant,
+    fraction: f32,
+    audio_seconds: Option<f64>,
+    callback: F,
+}
+
+impl<F: FnMut(&Progress)> ProgressTracker<F> {
+    pub fn new(stages: &'static [(ProgressStage, f32)], callback: F) -> Self {
+        Self {
+            stages,
+            current: 0,
+            stage_start: 0.0,
+            stage_started_at: Instant::now(),
+            fraction: 0.0,
+            audio_seconds: None,
+            callback,
+        }
+    }
+
+    /// length of the audio being processed, enables throughput reporting
+    pub fn set_audio_seconds(&mut self, seconds: f64) {
+        self.audio_seconds = Some(seconds);
+    }
+
+    /// enters `stage`, completing every stage before it in the layout
+    pub fn stage(&mut self, stage: ProgressStage) {
+        let Some(idx) = self.stages.iter().position(|(s, _)| *s == stage) else {
+            return;
+        };
+
+        self.current = idx;
+        // folded from +0.0, an empty float sum is -0.0
+        self.stage_start =
+            self.stages[..idx].iter().fold(0.0, |acc, (_, w)| acc + w) / self.total();
+        self.stage_started_at = Instant::now();
+        self.emit(self.stage_start, None);
+    }
+
+    /// reports `done` of `total` chunks finished in the current stage
+    pub fn chunk(&mut self, done: u32, total: u32) {
+        self.chunk_as(self.stages[self.current].0, done, total);
+    }
+
+    /// like `chunk`, but labelled `stage`. for steps that run interleaved with the
+    /// current stage and share its span, e.g. filtering each block right after
+    /// separating it
+    pub fn chunk_as(&mut self, stage: ProgressStage, done: u32, total: u32) {
+        let total = total.max(1);
+        let done = done.min(total);
+        let span = self.stages[self.current].1 / self.total();
+        let fraction = self.stage_start + span * done as f32 / total as f32;
+        self.emit_as(stage, fraction, Some((done, total)));
+    }
+
+    /// marks the job complete
+    pub fn finish(&mut self) {
+        self.current = self.stages.len() - 1;
+        self.emit(1.0, None);
+    }
+
+    fn total(&self) -> f32 {
+        self.stages
+            .iter()
+            .map(|(_, w)| w)
+            .sum::<f32>()
+            .max(f32::EPSILON)
+    }
+
+    fn emit(&mut self, fraction: f32, chunk: Option<(u32, u32)>) {
+        self.emit_as(self.stages[self.current].0, fraction, chunk);
+    }
+
+    fn emit_as(&mut self, stage: ProgressStage, fraction: f32, chunk: Option<(u32, u32)>) {
+        self.fraction = fraction.clamp(self.fraction, 1.0);
+
+        let elapsed = self.stage_started_at.elapsed().as_secs_f64();
+        let stage_done = chunk.map(|(done, total)| done as f64 / total as f64);
+
+        // rate of the current chunked stage, extrapolated over the rest of the bar
+        let eta_seconds = match stage_done {
+            Some(_) if elapsed > 0.0 && self.fraction > self.stage_start => {
+                let rate = (self.fraction - self.stage_start) as f64 / elapsed;
+                Some((1.0 - self.fraction as f64) / rate)
+            }
+            _ => None,
+        };
+
+        let throughput = match (stage_done, self.audio_seconds) {
+            (Some(done), Some(seconds))
+                if stage.processes_audio() && elapsed > 0.0 && done > 0.0 =>
+            {
+                Some(seconds * done / elapsed)
+            }
+            _ => None,
+        };
+
+        let progress = Progress {
+            stage,
+            fraction: self.fraction,
+            chunk,
+            eta_seconds,
+            throughput,
+        };
+        (self.callback)(&progress);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cancellation::CancellationToken;
+    use crate::encoding::OutputFormat;
+    use crate::processing::separate_audio;
+    use crate::separator::mock::MockSeparator;
+    use crate::separator::SeparationOptions;
+    use std::f32::consts::PI;
+    use std::path::Path;
+    use tch::Device;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    /// a stereo sine, long enough for the mock to report several chunks
+    fn write_sine(path: &Path, seconds: u32) {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for i in 0..seconds * SAMPLE_RATE {
+            let t = i as f32 / SAMPLE_RATE as f32;
+            let sample = ((2.0 * PI * 440.0 * t).sin() * 0.5 * i16::MAX as f32) as i16;
+            writer.write_sample(sample).unwrap();
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    #[test]
+    fn separation_progress_is_monotonic_and_finite() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input.wav");
+        write_sine(&input, 25);
+
+        let mut updates = Vec::new();
+        separate_audio(
+            &input,
+            dir.path(),
+            &MockSeparator::new(Device::Cpu),
+            &SeparationOptions::default(),
+            OutputFormat::WavFloat,
+            true,
+            &CancellationToken::new(),
+            |progress| updates.push(progress.clone()),
+        )
+        .unwrap();
+
+        for pair in updates.windows(2) {
+            assert!(
+                pair[1].fraction >= pair[0].fraction,
+                "progress went from {} back to {}",
+                pair[0].fraction,
+                pair[1].fraction
+            );
+        }
+        assert_eq!(updates.last().unwrap().fraction, 1.0);
+
+        let chunks = updates
+            .iter()
+            .filter(|p| p.stage == ProgressStage::Separating)
+            .filter_map(|p| p.chunk)
+            .collect::<Vec<_>>();
+        let (_, total) = *chunks.last().expect("no chunk updates");
+        assert!(total > 1);
+        assert_eq!(chunks, (1..=total).map(|i| (i, total)).collect::<Vec<_>>());
+
+        for progress in &updates {
+            assert!(progress
+                .eta_seconds
+                .is_none_or(|eta| eta.is_finite() && eta >= 0.0));
+            assert!(progress.throughput.is_none_or(|t| t.is_finite() && t > 0.0));
+        }
+    }
+
+    #[test]
+    fn stage_spans_follow_the_layout() {
+        let mut fractions = Vec::new();
+        {
+            let mut tracker =
+                ProgressTracker::new(SEPARATION_STAGES, |p: &Progress| fractions.push(p.fraction));
+            tracker.stage(ProgressStage::Loading);
+            tracker.stage(ProgressStage::Separating);
+            tracker.chunk(1, 2);
+            // a stage that's already behind doesn't pull the bar back
+            tracker.stage(ProgressStage::Loading);
+            tracker.finish();
+        }
+
+        let expected = [0.0, 0.05, 0.5, 0.5, 1.0];
+        assert_eq!(fractions.len(), expected.len());
+        for (fraction, expected) in fractions.iter().zip(expected) {
+            assert!((fraction - expected).abs() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use crate::streaming::Stereo;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// a stem derived from the mix and the separated stems rather than from the model
+#[derive(Debug)]
+pub struct ResidualSpec {
+    /// stored as the asset type of the same name
+    pub id: &'static str,
+    /// separated stems left out of it
+    pub excludes: &'static [&'static str],
+}
+
+pub const RESIDUAL_REGISTRY: &[ResidualSpec] = &[
+    ResidualSpec {
+        id: "instrumental",
+        excludes: &["vocals"],
+    },
+    ResidualSpec {
+        id: "accompaniment",
+        excludes: &["piano"],
+    },
+];
+
+/// resolves a residual id from settings
+pub fn find_residual(id: &str) -> Result<&'static ResidualSpec> {
+    RESIDUAL_REGISTRY
+        .iter()
+        .find(|spec| spec.id == id)
+        .ok_or_else(|| anyhow!("unknown residual stem: {}", id))
+}
+
+/// how a residual is put together
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResidualMethod {
+    /// the mix minus the excluded stems. keeps whatever the model assigned to no
+    /// stem, along with any bleed of the excluded stems it missed
+    Subtract,
+    /// the sum of every other stem. only holds what the model separated
+    Sum,
+}
+
+impl ResidualMethod {
+    pub fn to_string(&self) -> String {
+        match self {
+            ResidualMethod::Subtract => "subtract".to_string(),
+            ResidualMethod::Sum => "sum".to_string(),
+        }
+    }
+
+    pub fn from_string(s: &str) -> Self {
+        match s {
+            "sum" => ResidualMethod::Sum,
+            _ => ResidualMethod::Subtract,
+        }
+    }
+}
+
+impl ResidualSpec {
+    /// whether a backend with `stems` separates everything this residual leaves out
+    pub fn is_supported_by(&self, stems: &[String]) -> bool {
+        self.excludes
+            .iter()
+            .all(|excluded| stems.iter().any(|stem| stem == excluded))
+    }
+
+    /// the residual of one block. `stems` are the separated blocks named by `names`,
+    /// all the same length as `mix`
+    pub fn derive(
+        &self,
+        method: ResidualMethod,
+        mix: &Stereo,
+        names: &[String],
+        stems: &[Stereo],
+    ) -> Stereo {
+        let excluded = |name: &String| self.excludes.contains(&name.as_str());
+
+        let (mut residual, sign, included): (Stereo, f32, Vec<&Stereo>) = match method {
+            ResidualMethod::Subtract => (
+                mix.clone(),
+                -1.0,
+                names
+                    .iter()
+                    .zip(stems)
+                    .filter(|(name, _)| excluded(name))
+                    .map(|(_, stem)| stem)
+                    .collect(),
+            ),
+            ResidualMethod::Sum => (
+                [vec![0.0; mix[0].len()], vec![0.0; mix[1].len()]],
+                1.0,
+                names
+                    .iter()
+                    .zip(stems)
+                    .filter(|(name, _)| !excluded(name))
+                    .map(|(_, stem)| stem)
+                    .collect(),
+            ),
+        };
+
+        for stem in included {
+            for (channel, samples) in residual.iter_mut().zip(stem) {
+                for (x, s) in channel.iter_mut().zip(samples) {
+                    *x += sign * s;
+                }
+            }
+        }
+        residual
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names() -> Vec<String> {
+        ["vocals", "drums", "piano"].map(String::from).to_vec()
+    }
+
+    /// distinct powers of two per stem and sample, so every sum is exact
+    fn stems() -> Vec<Stereo> {
+        (0..3)
+            .map(|j| {
+                let channel = |c: i32| (0..4).map(|i| 2f32.powi(j * 8 + c * 4 + i)).collect();
+                [channel(0), channel(1)]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn subtract_takes_the_excluded_stems_out_of_the_mix() {
+        let spec = find_residual("instrumental").unwrap();
+        let mix: Stereo = [vec![1e6; 4], vec![-1e6; 4]];
+        let stems = stems();
+
+        let residual = spec.derive(ResidualMethod::Subtract, &mix, &names(), &stems);
+        for c in 0..2 {
+            let expected = (0..4)
+                .map(|i| mix[c][i] - stems[0][c][i])
+                .collect::<Vec<_>>();
+            assert_eq!(residual[c], expected);
+        }
+    }
+
+    #[test]
+    fn sum_adds_up_the_included_stems() {
+        let spec = find_residual("instrumental").unwrap();
+        let mix: Stereo = [vec![1e6; 4], vec![-1e6; 4]];
+        let stems = stems();
+
+        // the mix plays no part, only what was separated
+        let residual = spec.derive(ResidualMethod::Sum, &mix, &names(), &stems);
+        for c in 0..2 {
+            let expected = (0..4)
+                .map(|i| stems[1][c][i] + stems[2][c][i])
+                .collect::<Vec<_>>();
+            assert_eq!(residual[c], expected);
+        }
+    }
+
+    #[test]
+    fn unknown_residuals_are_rejected() {
+        assert!(find_residual("karaoke").is_err());
+        assert!(find_residual("instrumental").is_ok());
+
+        let accompaniment = find_residual("accompaniment").unwrap();
+        assert!(accompaniment.is_supported_by(&names()));
+        assert!(!accompaniment.is_supported_by(&names()[..2]));
+    }
+}
@@ -13,3 +13,49 @@ dummy_input = torch.randn(1, 2, 441000)  # 10 seconds stereo
 # Export to TorchScript
 traced = torch.jit.trace(model, dummy_input)
 traced.save("hdemucs.pt")
+
+# 6-stem hybrid transformer demucs, adds dedicated guitar and piano stems
+# uv run --with torch --with demucs src-tauri/src/script.py
+from demucs.pretrained import get_model
+
+bag = get_model("htdemucs_6s")
+model_6s = bag.models[0]
+model_6s.eval()
+
+# htdemucs was trained on 7.8 second segments, this must match the registry
+dummy_input_6s = torch.randn(1, 2, 343980)
+
+traced_6s = torch.jit.trace(model_6s, dummy_input_6s)
+traced_6s.save("htdemucs_6s.pt")
+
+# piano transcription (bytedance high-resolution piano transcription)
+# uv run --with torch --with piano_transcription_inference src-tauri/src/script.py
+from piano_transcription_inference import PianoTranscription
+
+
+class TranscriptionExport(torch.nn.Module):
+    """returns the note rolls as a tuple, the order transcription.rs expects"""
+
+    def __init__(self, model):
+        super().__init__()
+        self.model = model
+
+    def forward(self, audio):
+        out = self.model(audio)
+        return (
+            out["frame_output"],
+            out["reg_onset_output"],
+            out["reg_offset_output"],
+            out["velocity_output"],
+        )
+
+
+transcriber = PianoTranscription(device="cpu", checkpoint_path=None)
+export = TranscriptionExport(transcriber.model.note_model)
+export.eval()
+
+# 10 seconds of mono audio at 16khz
+dummy_input_piano = torch.randn(1, 160000)
+
+traced_piano = torch.jit.trace(export, dummy_input_piano, strict=False)
+traced_piano.save("piano_transcription.pt")
@@ -0,0 +1,401 @@
+use crate::cancellation::CancellationToken;
+use crate::demucs_model::{default_model, DemucsModel, ModelSpec, MODEL_REGISTRY};
+use crate::ensemble::{EnsembleSeparator, EnsembleSpec, ENSEMBLE_REGISTRY};
+use crate::residual::ResidualMethod;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use tch::{Device, Tensor};
+
+/// settings id that picks the first demucs model with weights on disk
+pub const AUTO_SEPARATOR: &str = "auto";
+
+/// upper bound for `shifts`, every shift is a full extra pass over the track
+pub const MAX_SHIFTS: u32 = 10;
+
+/// the shift trick moves the input by up to this many seconds
+const MAX_SHIFT_SECONDS: f64 = 0.5;
+
+/// largest overlap between neighbouring chunks, more would mostly redo work
+pub const MAX_OVERLAP: f64 = 0.9;
+
+/// upper bound for `batch_size`, larger batches rarely run faster
+pub const MAX_BATCH_SIZE: usize = 16;
+
+/// how overlapping chunks are crossfaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WindowType {
+    /// raised-cosine ramps over the overlap, flat in between
+    Cosine,
+    /// straight ramps over the overlap, flat in between
+    Linear,
+    /// peaks in the middle of the chunk and ramps over its whole length, like upstream demucs
+    Triangular,
+}
+
+impl WindowType {
+    pub fn to_string(&self) -> String {
+        match self {
+            WindowType::Cosine => "cosine".to_string(),
+            WindowType::Linear => "linear".to_string(),
+            WindowType::Triangular => "triangular".to_string(),
+        }
+    }
+
+    pub fn from_string(s: &str) -> Self {
+        match s {
+            "linear" => WindowType::Linear,
+            "triangular" => WindowType::Triangular,
+            _ => WindowType::Cosine,
+        }
+    }
+}
+
+/// how a separation is run. backends apply what fits how they work
+#[derive(Debug, Clone)]
+pub struct SeparationOptions {
+    /// extra passes over randomly time-shifted input whose results are
+    /// shifted back and averaged. 0 runs a single plain pass
+    pub shifts: u32,
+    /// chunk length in seconds, None uses the model's own. never longer than
+    /// the segment the model was exported with
+    pub segment_seconds: Option<f64>,
+    /// fraction of each chunk shared with the next one
+    pub overlap: f64,
+    pub window: WindowType,
+    /// exponent applied to the window, higher values make the crossfades sharper
+    pub transition_power: f64,
+    /// chunks run through the model per forward pass. a batch that doesn't
+    /// fit in memory falls back to smaller ones
+    pub batch_size: usize,
+    /// mean and standard deviation of the whole track's mono mix, for callers that
+    /// hand the separator one part of a track at a time. None measures the input
+    pub track_stats: Option<(f64, f64)>,
+    /// em iterations of the wiener post-filter run on every separated block,
+    /// None skips it. 0 only applies ratio masks
+    pub wiener_iterations: Option<u32>,
+    /// residual stems to derive alongside the separated ones, by id
+    pub residuals: Vec<String>,
+    pub residual_method: ResidualMethod,
+}
+
+impl Default for SeparationOptions {
+    fn default() -> Self {
+        Self {
+            shifts: 0,
+            segment_seconds: None,
+            overlap: 0.25,
+            window: WindowType::Cosine,
+            transition_power: 1.0,
+            batch_size: 1,
+            track_stats: None,
+            wiener_iterations: None,
+            residuals: Vec::new(),
+            residual_method: ResidualMethod::Subtract,
+        }
+    }
+}
+
+/// a source separation backend. audio goes in and stems come out as
+/// [channels, samples] tensors at the backend's sample rate
+pub trait Separator {
+    /// stem names in the order the backend emits them
+    fn stems(&self) -> &[String];
+
+    /// input must be resampled to this rate before separating
+    fn sample_rate(&self) -> u32;
+
+    /// device input tensors should be loaded on
+    fn device(&self) -> Device;
+
+    /// samples at `sample_rate` the model sees per chunk, callers that feed the
+    /// track in parts size them from it
+    fn segment_length(&self) -> usize;
+
+    /// `progress` receives (chunks done, total chunks) after each chunk.
+    /// stops between chunks with `Cancelled` once `cancel` is triggered
+    fn separate(
+        &self,
+        audio: &Tensor,
+        options: &SeparationOptions,
+        cancel: &CancellationToken,
+        progress: &mut dyn FnMut(u32, u32),
+    ) -> Result<HashMap<String, Tensor>>;
+}
+
+/// a backend that can be selected by id in settings
+#[derive(Debug, Clone, Copy)]
+pub enum SeparatorBackend {
+    Demucs(&'static ModelSpec),
+    Ensemble(&'static EnsembleSpec),
+}
+
+impl SeparatorBackend {
+    pub fn id(&self) -> &'static str {
+        match self {
+            SeparatorBackend::Demucs(spec) => spec.id,
+            SeparatorBackend::Ensemble(spec) => spec.id,
+        }
+    }
+
+    /// weights the backend loads from disk, if it's a single model
+    pub fn weights_path(&self) -> Option<&'static Path> {
+        match self {
+            SeparatorBackend::Demucs(spec) => Some(Path::new(spec.path)),
+            SeparatorBackend::Ensemble(_) => None,
+        }
+    }
+
+    /// separation models the backend holds in memory once loaded
+    pub fn model_count(&self) -> usize {
+        match self {
+            SeparatorBackend::Demucs(_) => 1,
+            SeparatorBackend::Ensemble(spec) => spec.members.len(),
+        }
+    }
+
+    /// whether the backend can be loaded on this machine
+    pub fn is_available(&self) -> bool {
+        match self {
+            SeparatorBackend::Ensemble(spec) => spec.weights_paths().all(|path| path.exists()),
+            _ => self.weights_path().is_none_or(|path| path.exists()),
+        }
+    }
+
+    pub fn load(&self, device: Device) -> Result<Box<dyn Separator>> {
+        Ok(match self {
+            SeparatorBackend::Demucs(spec) => Box::new(DemucsModel::new(spec, device)?),
+            SeparatorBackend::Ensemble(spec) => Box::new(EnsembleSeparator::new(spec, device)?),
+        })
+    }
+}
+
+/// every backend in the order they're offered
+pub fn all_backends() -> impl Iterator<Item = SeparatorBackend> {
+    MODEL_REGISTRY
+        .iter()
+        .map(SeparatorBackend::Demucs)
+        .chain(ENSEMBLE_REGISTRY.iter().map(SeparatorBackend::Ensemble))
+}
+
+/// resolves a backend id from settings
+pub fn find_backend(id: &str) -> Result<SeparatorBackend> {
+    if id == AUTO_SEPARATOR {
+        return default_model().map(SeparatorBackend::Demucs);
+    }
+
+    all_backends()
+        .find(|backend| backend.id() == id)
+        .ok_or_else(|| anyhow!("unknown separation backend: {}", id))
+}
+
+/// backend info for the settings screen
+#[derive(Serialize, Clone)]
+pub struct SeparatorInfo {
+    pub id: &'static str,
+    pub available: bool,
+}
+
+#[tauri::command]
+pub fn list_separators() -> Vec<SeparatorInfo> {
+    all_backends()
+        .map(|backend| SeparatorInfo {
+            id: backend.id(),
+            available: backend.is_available(),
+        })
+        .collect()
+}
+
+/// runs `separator` once per shift on the input delayed by a random offset, moves
+/// each result back and averages them. this smooths out artifacts tied to where
+/// chunk boundaries fall, at the cost of one full pass per shift
+pub fn separate_with_shifts(
+    separator: &dyn Separator,
+    audio: &Tensor,
+    options: &SeparationOptions,
+    cancel: &CancellationToken,
+    progress: &mut dyn FnMut(u32, u32),
+) -> Result<HashMap<String, Tensor>> {
+    let shifts = options.shifts.min(MAX_SHIFTS);
+    if shifts == 0 {
+        return separator.separate(audio, options, cancel, progress);
+    }
+
+    let n_samples = audio.size()[1];
+    let max_shift = (MAX_SHIFT_SECONDS * separator.sample_rate() as f64) as i64;
+    let padded = audio.constant_pad_nd([max_shift, max_shift]);
+
+    let mut sums: HashMap<String, Tensor> = HashMap::new();
+    for (pass, offset) in shift_offsets(shifts, max_shift).into_iter().enumerate() {
+        println!("shift {}/{}: offset {} samples", pass + 1, shifts, offset);
+
+        // the input starts `max_shift - offset` samples late, undo that on the way out
+        let shifted = padded.narrow(1, offset, n_samples + max_shift - offset);
+        let stems = separator.separate(&shifted, options, cancel, &mut |done, total| {
+            progress(pass as u32 * total + done, shifts * total);
+        })?;
+
+        for (name, stem) in stems {
+            let restored = stem.narrow(1, max_shift - offset, n_samples);
+            match sums.get_mut(&name) {
+                Some(sum) => *sum += restored,
+                None => {
+                    sums.insert(name, restored);
+                }
+            }
+        }
+    }
+
+    Ok(sums
+        .into_iter()
+        .map(|(name, sum)| (name, sum / shifts as f64))
+        .collect())
+}
+
+/// pseudo-random offsets in [0, max_shift), the same on every run so
+/// re-separating a file gives the same stems
+fn shift_offsets(count: u32, max_shift: i64) -> Vec<i64> {
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    (0..count)
+        .map(|_| {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % max_shift.max(1) as u64) as i64
+        })
+        .collect()
+}
+
+/// backends that need no weights, for tests
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use tch::Kind;
+
+    const MOCK_SAMPLE_RATE: u32 = 44100;
+
+    /// samples per simulated chunk, so progress and cancellation behave like a model
+    const MOCK_CHUNK_LENGTH: i64 = 441000;
+
+    /// deterministic stand-in that needs no weights, for tests. every stem gets an
+    /// equal share of the mix, so the stems always sum back to the input
+    pub struct MockSeparator {
+        device: Device,
+        stems: Vec<String>,
+    }
+
+    impl MockSeparator {
+        pub fn new(device: Device) -> Self {
+            Self {
+                device,
+                stems: ["drums", "bass", "other", "vocals", "guitar", "piano"]
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect(),
+            }
+        }
+    }
+
+    impl Separator for MockSeparator {
+        fn stems(&self) -> &[String] {
+            &self.stems
+        }
+
+        fn sample_rate(&self) -> u32 {
+            MOCK_SAMPLE_RATE
+        }
+
+        fn device(&self) -> Device {
+            self.device
+        }
+
+        fn segment_length(&self) -> usize {
+            MOCK_CHUNK_LENGTH as usize
+        }
+
+        fn separate(
+            &self,
+            audio: &Tensor,
+            _options: &SeparationOptions,
+            cancel: &CancellationToken,
+            progress: &mut dyn FnMut(u32, u32),
+        ) -> Result<HashMap<String, Tensor>> {
+            let n_samples = audio.size()[1];
+            let n_chunks = (n_samples as f64 / MOCK_CHUNK_LENGTH as f64)
+                .ceil()
+                .max(1.0) as u32;
+
+            for chunk in 1..=n_chunks {
+                cancel.check()?;
+                progress(chunk, n_chunks);
+            }
+
+            let share = audio.to_kind(Kind::Float) / self.stems.len() as f64;
+            Ok(self
+                .stems
+                .iter()
+                .map(|name| (name.clone(), share.copy()))
+                .collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockSeparator;
+    use super::*;
+    use crate::cancellation::Cancelled;
+    use tch::Kind;
+
+    #[test]
+    fn mock_is_not_offered() {
+        assert!(find_backend("mock").is_err());
+        assert!(list_separators().iter().all(|info| info.id != "mock"));
+    }
+
+    #[test]
+    fn shifted_stems_add_up_to_the_mix() {
+        let separator = MockSeparator::new(Device::Cpu);
+        let audio = Tensor::randn([2, 44100], (Kind::Float, Device::Cpu));
+        let options = SeparationOptions {
+            shifts: 3,
+            ..SeparationOptions::default()
+        };
+
+        let stems = separate_with_shifts(
+            &separator,
+            &audio,
+            &options,
+            &CancellationToken::new(),
+            &mut |_, _| {},
+        )
+        .unwrap();
+
+        assert_eq!(stems.len(), separator.stems().len());
+        let sum = stems
+            .values()
+            .fold(audio.zeros_like(), |sum, stem| sum + stem);
+        assert!(sum.allclose(&audio, 1e-5, 1e-5, false));
+    }
+
+    #[test]
+    fn cancelled_separation_stops() {
+        let separator = MockSeparator::new(Device::Cpu);
+        let audio = Tensor::zeros([2, 44100], (Kind::Float, Device::Cpu));
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+
+        let err = separate_with_shifts(
+            &separator,
+            &audio,
+            &SeparationOptions::default(),
+            &cancel,
+            &mut |_, _| {},
+        )
+        .unwrap_err();
+        assert!(err.is::<Cancelled>());
+    }
+}
@@ -0,0 +1,246 @@
+use crate::db::{get_setting, set_settings, DbPool};
+use crate::device::{DevicePolicy, AUTO_DEVICE};
+use crate::encoding::OutputFormat;
+use crate::residual::{find_residual, ResidualMethod};
+use crate::separator::{
+    find_backend, SeparationOptions, WindowType, AUTO_SEPARATOR, MAX_BATCH_SIZE, MAX_OVERLAP,
+    MAX_SHIFTS,
+};
+use crate::wiener::MAX_WIENER_ITERATIONS;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use tch::Device;
+
+const SEPARATOR_KEY: &str = "separator";
+const SHIFTS_KEY: &str = "shifts";
+const SEGMENT_SECONDS_KEY: &str = "segment_seconds";
+const OVERLAP_KEY: &str = "overlap";
+const WINDOW_KEY: &str = "window";
+const TRANSITION_POWER_KEY: &str = "transition_power";
+const BATCH_SIZE_KEY: &str = "batch_size";
+const DEVICE_KEY: &str = "device";
+const INTRA_OP_THREADS_KEY: &str = "intra_op_threads";
+const INTER_OP_THREADS_KEY: &str = "inter_op_threads";
+const BACKGROUND_PRIORITY_KEY: &str = "background_priority";
+const WIENER_ITERATIONS_KEY: &str = "wiener_iterations";
+const RESIDUALS_KEY: &str = "residuals";
+const RESIDUAL_METHOD_KEY: &str = "residual_method";
+const STEM_FORMAT_KEY: &str = "stem_format";
+const EXPORT_FORMAT_KEY: &str = "export_format";
+
+/// user preferences, stored as key/value rows in the settings table
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Settings {
+    /// separation backend id, or "auto" for the first model with weights on disk
+    pub separator: String,
+    /// extra time-shifted separation passes, trades speed for quality
+    pub shifts: u32,
+    /// separation chunk length, None uses the model's own
+    pub segment_seconds: Option<f64>,
+    pub overlap: f64,
+    pub window: WindowType,
+    pub transition_power: f64,
+    /// separation chunks per forward pass, more is faster on a gpu with memory to spare
+    pub batch_size: usize,
+    /// "auto", "cpu" or "cuda:N"
+    pub device: String,
+    /// cpu threads per op, None uses one per core
+    pub intra_op_threads: Option<usize>,
+    /// ops run in parallel, None lets libtorch decide. applies after a restart
+    pub inter_op_threads: Option<usize>,
+    /// run jobs below normal priority so the rest of the desktop stays responsive.
+    /// turning it off applies after a restart, raising priority takes privileges
+    pub background_priority: bool,
+    /// wiener post-filter iterations, None leaves the model output as is
+    pub wiener_iterations: Option<u32>,
+    /// residual stem ids derived after each separation, e.g. "instrumental"
+    pub residuals: Vec<String>,
+    pub residual_method: ResidualMethod,
+    /// format separated stems are stored in. lossless only, later stages read them
+    pub stem_format: OutputFormat,
+    /// format stems are exported in unless an export picks its own
+    pub export_format: OutputFormat,
+}
+
+impl Settings {
+    pub fn separation_options(&self) -> SeparationOptions {
+        SeparationOptions {
+            shifts: self.shifts,
+            segment_seconds: self.segment_seconds,
+            overlap: self.overlap,
+            window: self.window,
+            transition_power: self.transition_power,
+            batch_size: self.batch_size,
+            track_stats: None,
+            wiener_iterations: self.wiener_iterations,
+            residuals: self.residuals.clone(),
+            residual_method: self.residual_method,
+        }
+    }
+
+    /// device the device policy resolves to on this machine
+    pub fn inference_device(&self) -> Result<Device> {
+        DevicePolicy::parse(&self.device)?.resolve()
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        let separation = SeparationOptions::default();
+
+        Self {
+            separator: AUTO_SEPARATOR.to_string(),
+            shifts: separation.shifts,
+            segment_seconds: separation.segment_seconds,
+            overlap: separation.overlap,
+            window: separation.window,
+            transition_power: separation.transition_power,
+            batch_size: separation.batch_size,
+            device: AUTO_DEVICE.to_string(),
+            intra_op_threads: None,
+            inter_op_threads: None,
+            background_priority: true,
+            wiener_iterations: separation.wiener_iterations,
+            residuals: separation.residuals,
+            residual_method: separation.residual_method,
+            stem_format: OutputFormat::WavFloat,
+            export_format: OutputFormat::Wav24,
+        }
+    }
+}
+
+pub fn load_settings(pool: &DbPool) -> Result<Settings> {
+    let defaults = Settings::default();
+
+    Ok(Settings {
+        separator: get_setting(pool, SEPARATOR_KEY)?.unwrap_or(defaults.separator),
+        shifts: parsed(pool, SHIFTS_KEY)?.unwrap_or(defaults.shifts),
+        // stored empty when unset
+        segment_seconds: parsed(pool, SEGMENT_SECONDS_KEY)?.or(defaults.segment_seconds),
+        overlap: parsed(pool, OVERLAP_KEY)?.unwrap_or(defaults.overlap),
+        window: get_setting(pool, WINDOW_KEY)?
+            .map_or(defaults.window, |v| WindowType::from_string(&v)),
+        transition_power: parsed(pool, TRANSITION_POWER_KEY)?.unwrap_or(defaults.transition_power),
+        batch_size: parsed(pool, BATCH_SIZE_KEY)?.unwrap_or(defaults.batch_size),
+        device: get_setting(pool, DEVICE_KEY)?.unwrap_or(defaults.device),
+        // stored empty when unset
+        intra_op_threads: parsed(pool, INTRA_OP_THREADS_KEY)?.or(defaults.intra_op_threads),
+        inter_op_threads: parsed(pool, INTER_OP_THREADS_KEY)?.or(defaults.inter_op_threads),
+        background_priority: parsed(pool, BACKGROUND_PRIORITY_KEY)?
+            .unwrap_or(defaults.background_priority),
+        // stored empty when off
+        wiener_iterations: parsed(pool, WIENER_ITERATIONS_KEY)?.or(defaults.wiener_iterations),
+        // comma separated, stored empty when none
+        residuals: get_setting(pool, RESIDUALS_KEY)?.map_or(defaults.residuals, |v| {
+            v.split(',')
+                .filter(|id| !id.is_empty())
+                .map(str::to_string)
+                .collect()
+        }),
+        residual_method: get_setting(pool, RESIDUAL_METHOD_KEY)?
+            .map_or(defaults.residual_method, |v| {
+                ResidualMethod::from_string(&v)
+            }),
+        stem_format: stored_format(pool, STEM_FORMAT_KEY)?.unwrap_or(defaults.stem_format),
+        export_format: stored_format(pool, EXPORT_FORMAT_KEY)?.unwrap_or(defaults.export_format),
+    })
+}
+
+pub fn save_settings(pool: &DbPool, settings: &Settings) -> Result<()> {
+    // reject unknown backends now rather than failing the next job
+    if settings.separator != AUTO_SEPARATOR {
+        find_backend(&settings.separator)?;
+    }
+    if settings.shifts > MAX_SHIFTS {
+        bail!("shifts must be at most {}", MAX_SHIFTS);
+    }
+    if settings.segment_seconds.is_some_and(|s| s <= 0.0) {
+        bail!("segment length must be positive");
+    }
+    if !(0.0..=MAX_OVERLAP).contains(&settings.overlap) {
+        bail!("overlap must be between 0 and {}", MAX_OVERLAP);
+    }
+    if settings.transition_power <= 0.0 {
+        bail!("transition power must be positive");
+    }
+    if !(1..=MAX_BATCH_SIZE).contains(&settings.batch_size) {
+        bail!("batch size must be between 1 and {}", MAX_BATCH_SIZE);
+    }
+    // only the syntax, a gpu that's missing now may be back by the next job
+    DevicePolicy::parse(&settings.device)?;
+    if settings.intra_op_threads == Some(0) || settings.inter_op_threads == Some(0) {
+        bail!("thread counts must be at least 1");
+    }
+    if settings
+        .wiener_iterations
+        .is_some_and(|n| n > MAX_WIENER_ITERATIONS)
+    {
+        bail!(
+            "wiener filter iterations must be at most {}",
+            MAX_WIENER_ITERATIONS
+        );
+    }
+    for id in &settings.residuals {
+        find_residual(id)?;
+    }
+    if !settings.stem_format.is_lossless() {
+        bail!(
+            "stems must be stored lossless, {} is only available for exports",
+            settings.stem_format.to_string()
+        );
+    }
+
+    set_settings(
+        pool,
+        &[
+            (SEPARATOR_KEY, settings.separator.clone()),
+            (SHIFTS_KEY, settings.shifts.to_string()),
+            (SEGMENT_SECONDS_KEY, optional(settings.segment_seconds)),
+            (OVERLAP_KEY, settings.overlap.to_string()),
+            (WINDOW_KEY, settings.window.to_string()),
+            (TRANSITION_POWER_KEY, settings.transition_power.to_string()),
+            (BATCH_SIZE_KEY, settings.batch_size.to_string()),
+            (DEVICE_KEY, settings.device.clone()),
+            (INTRA_OP_THREADS_KEY, optional(settings.intra_op_threads)),
+            (INTER_OP_THREADS_KEY, optional(settings.inter_op_threads)),
+            (
+                BACKGROUND_PRIORITY_KEY,
+                settings.background_priority.to_string(),
+            ),
+            (WIENER_ITERATIONS_KEY, optional(settings.wiener_iterations)),
+            (RESIDUALS_KEY, settings.residuals.join(",")),
+            (RESIDUAL_METHOD_KEY, settings.residual_method.to_string()),
+            (STEM_FORMAT_KEY, settings.stem_format.to_string()),
+            (EXPORT_FORMAT_KEY, settings.export_format.to_string()),
+        ],
+    )
+}
+
+/// optional values are stored empty when unset
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map_or(String::new(), |v| v.to_string())
+}
+
+/// a stored value parsed as `T`, None if missing or unparseable
+fn parsed<T: FromStr>(pool: &DbPool, key: &str) -> Result<Option<T>> {
+    Ok(get_setting(pool, key)?.and_then(|v| v.parse().ok()))
+}
+
+/// a stored output format, None if missing or unknown
+fn stored_format(pool: &DbPool, key: &str) -> Result<Option<OutputFormat>> {
+    Ok(get_setting(pool, key)?.and_then(|v| OutputFormat::parse(&v).ok()))
+}
+
+#[tauri::command]
+pub async fn get_settings(pool: tauri::State<'_, DbPool>) -> Result<Settings, String> {
+    load_settings(&pool).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn update_settings(
+    pool: tauri::State<'_, DbPool>,
+    settings: Settings,
+) -> Result<(), String> {
+    save_settings(&pool, &settings).map_err(|e| e.to_string())
+}
@@ -0,0 +1,389 @@
+use crate::audio_io::{AudioStream, Resampler, StreamResampler};
+use crate::cancellation::CancellationToken;
+use crate::encoding::{create_encoder, AudioEncoder, Clipping, OutputFormat};
+use anyhow::{anyhow, Context, Result};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// model segments separated per block, plus the crossfade. memory use scales
+/// with this instead of the track length
+pub const BLOCK_SEGMENTS: usize = 3;
+
+/// neighbouring blocks overlap by this much and are crossfaded there, so the
+/// model has context on both sides of every block boundary
+pub const CROSSFADE_SECONDS: f64 = 5.0;
+
+/// a stereo signal as one sample vector per channel
+pub type Stereo = [Vec<f32>; 2];
+
+/// bytes per frame in a spool file, stereo little-endian f32
+const SPOOL_FRAME_BYTES: usize = 8;
+
+/// what the separation needs to know about the whole track before its first block
+pub struct TrackStats {
+    pub sample_rate: u32,
+    pub n_frames: usize,
+    /// mean and standard deviation of the mono mix, for normalization
+    pub mean: f64,
+    pub std: f64,
+}
+
+impl TrackStats {
+    pub fn seconds(&self) -> f64 {
+        self.n_frames as f64 / self.sample_rate as f64
+    }
+
+    /// track length once resampled to `rate`
+    pub fn frames_at(&self, rate: u32) -> usize {
+        Resampler::new(self.sample_rate, rate).output_len(self.n_frames)
+    }
+}
+
+/// decodes the file once, writing it to `spool_path` as stereo at `rate` for
+/// `StereoReader` and measuring it on the way. the whole track is then known
+/// before the first block without decoding it twice or keeping it in memory
+pub fn spool_track(
+    path: &Path,
+    spool_path: &Path,
+    rate: u32,
+    cancel: &CancellationToken,
+) -> Result<TrackStats> {
+    let mut stream = AudioStream::open(path)?;
+    let channels = stream.channels();
+    let mut resamplers = [
+        StreamResampler::new(stream.sample_rate(), rate),
+        StreamResampler::new(stream.sample_rate(), rate),
+    ];
+
+    let file = File::create(spool_path)
+        .with_context(|| format!("failed to create {}", spool_path.display()))?;
+    let mut spool = BufWriter::new(file);
+
+    let mut n_frames = 0;
+    let mut n_spooled = 0;
+    let mut sum = 0.0f64;
+    let mut sum_squares = 0.0f64;
+    let mut write = |block: &Stereo| -> Result<()> {
+        for (left, right) in block[0].iter().zip(&block[1]) {
+            spool.write_all(&left.to_le_bytes())?;
+            spool.write_all(&right.to_le_bytes())?;
+
+            let mono = (*left as f64 + *right as f64) * 0.5;
+            sum += mono;
+            sum_squares += mono * mono;
+        }
+        n_spooled += block[0].len();
+        Ok(())
+    };
+
+    while let Some(samples) = stream.next_samples()? {
+        cancel.check()?;
+        let planar = to_stereo(&samples, channels);
+        n_frames += planar[0].len();
+        let [left, right] = &mut resamplers;
+        write(&[left.push(&planar[0]), right.push(&planar[1])])?;
+    }
+    let [left, right] = &mut resamplers;
+    write(&[left.finish(), right.finish()])?;
+    spool.flush()?;
+
+    if n_frames == 0 {
+        return Err(anyhow!("no audio decoded from {}", path.display()));
+    }
+
+    let mean = sum / n_spooled as f64;
+    let variance = (sum_squares / n_spooled as f64 - mean * mean).max(0.0);
+
+    Ok(TrackStats {
+        sample_rate: stream.sample_rate(),
+        n_frames,
+        mean,
+        std: variance.sqrt(),
+    })
+}
+
+/// reads a file written by `spool_track`, a block at a time
+pub struct StereoReader {
+    spool: BufReader<File>,
+}
+
+impl StereoReader {
+    pub fn open(spool_path: &Path) -> Result<Self> {
+        let file = File::open(spool_path)
+            .with_context(|| format!("failed to open {}", spool_path.display()))?;
+        Ok(Self {
+            spool: BufReader::new(file),
+        })
+    }
+
+    /// up to `n_frames` frames, fewer only at the end of the file
+    pub fn read(&mut self, n_frames: usize) -> Result<Stereo> {
+        let mut bytes = Vec::new();
+        (&mut self.spool)
+            .take((n_frames * SPOOL_FRAME_BYTES) as u64)
+            .read_to_end(&mut bytes)?;
+
+        let mut block = [
+            Vec::with_capacity(bytes.len() / SPOOL_FRAME_BYTES),
+            Vec::with_capacity(bytes.len() / SPOOL_FRAME_BYTES),
+        ];
+        for frame in bytes.chunks_exact(SPOOL_FRAME_BYTES) {
+            block[0].push(f32::from_le_bytes(frame[..4].try_into().unwrap()));
+            block[1].push(f32::from_le_bytes(frame[4..].try_into().unwrap()));
+        }
+        Ok(block)
+    }
+
+    /// whether every frame has been read
+    pub fn at_end(&mut self) -> Result<bool> {
+        Ok(self.spool.fill_buf()?.is_empty())
+    }
+}
+
+/// splits interleaved samples into stereo. mono is duplicated and surround
+/// keeps front left/right, like `load_audio_to_tensor`
+pub fn to_stereo(interleaved: &[f32], channels: usize) -> Stereo {
+    let right = if channels == 1 { 0 } else { 1 };
+    [0, right].map(|c| {
+        interleaved
+            .chunks_exact(channels)
+            .map(|frame| frame[c])
+            .collect()
+    })
+}
+
+/// crossfade weights for a block, ramping over `fade` frames on the sides that
+/// overlap a neighbour. never zero, so every frame keeps some weight
+pub fn block_weights(length: usize, fade: usize, fade_in: bool, fade_out: bool) -> Vec<f32> {
+    let fade = fade.min(length);
+    let ramp = |i: usize| (i + 1) as f32 / (fade + 1) as f32;
+
+    (0..length)
+        .map(|i| {
+            let mut weight: f32 = 1.0;
+            if fade_in && i < fade {
+                weight = ramp(i);
+            }
+            let from_end = length - 1 - i;
+            if fade_out && from_end < fade {
+                weight = weight.min(ramp(from_end));
+            }
+            weight
+        })
+        .collect()
+}
+
+/// weighted overlap-add of separated blocks. only holds the frames that a later
+/// block can still add to, everything before that is flushed to the writers
+pub struct OverlapAddRing {
+    /// first frame still held
+    start: usize,
+    /// per stem, per channel
+    stems: Vec<[VecDeque<f32>; 2]>,
+    weights: VecDeque<f32>,
+}
+
+impl OverlapAddRing {
+    pub fn new(n_stems: usize) -> Self {
+        Self {
+            start: 0,
+            stems: (0..n_stems)
+                .map(|_| [VecDeque::new(), VecDeque::new()])
+                .collect(),
+            weights: VecDeque::new(),
+        }
+    }
+
+    /// adds one block of every stem, starting at frame `offset` of the track
+    pub fn add(&mut self, offset: usize, stems: &[Stereo], weights: &[f32]) {
+        let skip = self.start.saturating_sub(offset);
+        let at = offset.max(self.start) - self.start;
+        let end = at + weights.len().saturating_sub(skip);
+
+        if self.weights.len() < end {
+            self.weights.resize(end, 0.0);
+            for channels in &mut self.stems {
+                for channel in channels {
+                    channel.resize(end, 0.0);
+                }
+            }
+        }
+
+        for (i, w) in weights.iter().skip(skip).enumerate() {
+            self.weights[at + i] += w;
+        }
+        for (channels, block) in self.stems.iter_mut().zip(stems) {
+            for (channel, samples) in channels.iter_mut().zip(block) {
+                for (i, (x, w)) in samples.iter().zip(weights).skip(skip).enumerate() {
+                    channel[at + i] += x * w;
+                }
+            }
+        }
+    }
+
+    /// removes and returns the normalized frames before `until`, per stem
+    pub fn flush(&mut self, until: usize) -> Vec<Stereo> {
+        let n = until.saturating_sub(self.start).min(self.weights.len());
+        let weights: Vec<f32> = self.weights.drain(..n).collect();
+        self.start += n;
+
+        self.stems
+            .iter_mut()
+            .map(|channels| {
+                channels.each_mut().map(|channel| {
+                    channel
+                        .drain(..n)
+                        .zip(&weights)
+                        .map(|(x, w)| x / (w + 1e-8))
+                        .collect()
+                })
+            })
+            .collect()
+    }
+}
+
+/// writes one stem as it's flushed, converting back to `output_rate` on the way
+pub struct StemWriter {
+    encoder: Box<dyn AudioEncoder>,
+    resamplers: Option<[StreamResampler; 2]>,
+    /// frames still to write, keeps the stem exactly as long as the source
+    remaining: usize,
+}
+
+impl StemWriter {
+    pub fn create(
+        path: &Path,
+        format: OutputFormat,
+        input_rate: u32,
+        output_rate: u32,
+        n_frames: usize,
+    ) -> Result<Self> {
+        let resamplers = (input_rate != output_rate).then(|| {
+            [
+                StreamResampler::new(input_rate, output_rate),
+                StreamResampler::new(input_rate, output_rate),
+            ]
+        });
+
+        Ok(Self {
+            encoder: create_encoder(path, format, output_rate)?,
+            resamplers,
+            remaining: n_frames,
+        })
+    }
+
+    pub fn write(&mut self, block: &Stereo) -> Result<()> {
+        match &mut self.resamplers {
+            Some([left, right]) => {
+                let resampled = [left.push(&block[0]), right.push(&block[1])];
+                self.write_frames(&resampled)
+            }
+            None => self.write_frames(block),
+        }
+    }
+
+    /// completes the file, returns what clipped in it
+    pub fn finish(mut self) -> Result<Clipping> {
+        if let Some([left, right]) = &mut self.resamplers {
+            let tail = [left.finish(), right.finish()];
+            self.write_frames(&tail)?;
+        }
+        self.encoder.finish()
+    }
+
+    fn write_frames(&mut self, block: &Stereo) -> Result<()> {
+        let n = block[0].len().min(self.remaining);
+        if n < block[0].len() {
+            let trimmed = block.each_ref().map(|channel| channel[..n].to_vec());
+            self.encoder.write(&trimmed)?;
+        } else {
+            self.encoder.write(block)?;
+        }
+        self.remaining -= n;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hound::{SampleFormat, WavSpec, WavWriter};
+
+    fn write_wav(path: &Path, left: &[f32], right: &[f32]) {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        let mut writer = WavWriter::create(path, spec).unwrap();
+        for (l, r) in left.iter().zip(right) {
+            writer.write_sample(*l).unwrap();
+            writer.write_sample(*r).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    #[test]
+    fn spool_reads_back_in_blocks_with_the_track_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("mix.wav");
+        let spool = dir.path().join("mix.spool");
+
+        let n = 10_000;
+        let left = (0..n).map(|i| (i % 100) as f32 / 100.0).collect::<Vec<_>>();
+        let right = vec![0.25; n];
+        write_wav(&input, &left, &right);
+
+        let stats = spool_track(&input, &spool, 44100, &CancellationToken::new()).unwrap();
+        assert_eq!(stats.sample_rate, 44100);
+        assert_eq!(stats.n_frames, n);
+
+        let mono = left
+            .iter()
+            .map(|l| (*l as f64 + 0.25) * 0.5)
+            .collect::<Vec<_>>();
+        let mean = mono.iter().sum::<f64>() / n as f64;
+        let std = (mono.iter().map(|m| (m - mean).powi(2)).sum::<f64>() / n as f64).sqrt();
+        assert!((stats.mean - mean).abs() < 1e-6);
+        assert!((stats.std - std).abs() < 1e-6);
+
+        let mut reader = StereoReader::open(&spool).unwrap();
+        let mut read: Stereo = [Vec::new(), Vec::new()];
+        while !reader.at_end().unwrap() {
+            let block = reader.read(3000).unwrap();
+            assert!(!block[0].is_empty() && block[0].len() <= 3000);
+            for (all, part) in read.iter_mut().zip(block) {
+                all.extend(part);
+            }
+        }
+        assert_eq!(read, [left, right]);
+    }
+
+    #[test]
+    fn spool_at_the_model_rate_has_the_resampled_length() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("mix.wav");
+        let spool = dir.path().join("mix.spool");
+        write_wav(&input, &[0.1; 4410], &[0.1; 4410]);
+
+        let stats = spool_track(&input, &spool, 48000, &CancellationToken::new()).unwrap();
+        let frames = StereoReader::open(&spool).unwrap().read(10_000).unwrap();
+        assert_eq!(frames[0].len(), stats.frames_at(48000));
+        assert_eq!(frames[0].len(), 4800);
+    }
+
+    #[test]
+    fn cancelled_spool_stops() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("mix.wav");
+        write_wav(&input, &[0.1; 4410], &[0.1; 4410]);
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let result = spool_track(&input, &dir.path().join("mix.spool"), 44100, &cancel);
+        assert!(result.is_err());
+    }
+}
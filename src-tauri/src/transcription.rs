@@ -0,0 +1,246 @@
+use crate::cancellation::CancellationToken;
+use crate::midi::NoteEvent;
+use anyhow::{anyhow, Result};
+use std::path::Path;
+use tch::{CModule, Device, IValue, Tensor};
+
+/// describes the torchscript piano transcription model and its export contract.
+/// the model takes mono audio [batch, samples] and returns a tuple of
+/// (frame, reg_onset, reg_offset, velocity) rolls, each [batch, frames, 88]
+#[derive(Debug, Clone)]
+pub struct TranscriptionModelSpec {
+    pub id: &'static str,
+    pub path: &'static str,
+    pub sample_rate: u32,
+    /// samples per forward pass, the model was traced with this input length
+    pub segment_length: i64,
+    pub frames_per_second: f64,
+    /// midi pitch of the lowest piano key (A0)
+    pub begin_note: u8,
+    pub classes: usize,
+}
+
+/// bytedance high-resolution piano transcription (regress onset/offset/frame/velocity crnn)
+pub const PIANO_TRANSCRIPTION_MODEL: TranscriptionModelSpec = TranscriptionModelSpec {
+    id: "piano_transcription",
+    path: "models/piano_transcription.pt",
+    sample_rate: 16000,
+    segment_length: 160000, // 10 seconds
+    frames_per_second: 100.0,
+    begin_note: 21,
+    classes: 88,
+};
+
+// decoding thresholds from the reference implementation
+const ONSET_THRESHOLD: f32 = 0.3;
+const OFFSET_THRESHOLD: f32 = 0.3;
+const FRAME_THRESHOLD: f32 = 0.1;
+
+/// frame-level model outputs for the whole track, each [frames][classes]
+struct PianoRolls {
+    frame: Vec<Vec<f32>>,
+    onset: Vec<Vec<f32>>,
+    offset: Vec<Vec<f32>>,
+    velocity: Vec<Vec<f32>>,
+}
+
+pub struct PianoTranscriber {
+    model: CModule,
+    device: Device,
+    spec: TranscriptionModelSpec,
+}
+
+impl PianoTranscriber {
+    pub fn new(spec: &TranscriptionModelSpec, device: Device) -> Result<Self> {
+        let model_path = Path::new(spec.path);
+        if !model_path.exists() {
+            return Err(anyhow!(
+                "model file not found at {}. please ensure {}.pt is available.",
+                model_path.display(),
+                spec.id
+            ));
+        }
+
+        println!("loading transcription model on {:?}", device);
+        let model = CModule::load_on_device(model_path, device)?;
+
+        Ok(Self {
+            model,
+            device,
+            spec: spec.clone(),
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.spec.sample_rate
+    }
+
+    /// transcribes mono audio [samples] at the model rate into note events.
+    /// progress_cb receives (chunks done, total chunks)
+    pub fn transcribe<F>(
+        &self,
+        audio: &Tensor,
+        cancel: &CancellationToken,
+        mut progress_cb: F,
+    ) -> Result<Vec<NoteEvent>>
+    where
+        F: FnMut(u32, u32),
+    {
+        let segment_length = self.spec.segment_length;
+        let hop_size = segment_length / 2;
+        let n_samples = audio.size()[0];
+
+        // pad so the track is covered by half-overlapping segments
+        let n_chunks = ((n_samples as f64 / hop_size as f64).ceil() as i64).max(1) + 1;
+        let padded_len = (n_chunks - 1) * hop_size + segment_length;
+        let padded = Tensor::cat(
+            &[
+                audio.to_device(self.device),
+                Tensor::zeros(&[padded_len - n_samples], (tch::Kind::Float, self.device)),
+            ],
+            0,
+        );
+
+        println!(
+            "transcribing {:.1}s of audio in {} chunks",
+            n_samples as f64 / self.spec.sample_rate as f64,
+            n_chunks
+        );
+
+        // models emit one extra frame per segment, drop it so hops line up
+        let frames_per_segment = (segment_length as f64 / self.spec.sample_rate as f64
+            * self.spec.frames_per_second)
+            .round() as usize;
+
+        let mut rolls = PianoRolls {
+            frame: Vec::new(),
+            onset: Vec::new(),
+            offset: Vec::new(),
+            velocity: Vec::new(),
+        };
+
+        for chunk_idx in 0..n_chunks {
+            cancel.check()?;
+
+            let chunk = padded.narrow(0, chunk_idx * hop_size, segment_length);
+            let outputs = self.forward_segment(&chunk)?;
+
+            // keep the middle half of each segment, plus the outer quarters at the ends
+            let n_frames = outputs[0].len().min(frames_per_segment);
+            let quarter = n_frames / 4;
+            let start = if chunk_idx == 0 { 0 } else { quarter };
+            let end = if chunk_idx == n_chunks - 1 {
+                n_frames
+            } else {
+                n_frames - quarter
+            };
+
+            let [frame, onset, offset, velocity] = outputs;
+            rolls.frame.extend_from_slice(&frame[start..end]);
+            rolls.onset.extend_from_slice(&onset[start..end]);
+            rolls.offset.extend_from_slice(&offset[start..end]);
+            rolls.velocity.extend_from_slice(&velocity[start..end]);
+
+            progress_cb((chunk_idx + 1) as u32, n_chunks as u32);
+        }
+
+        // drop frames that only cover padding
+        let total_frames = (n_samples as f64 / self.spec.sample_rate as f64
+            * self.spec.frames_per_second) as usize
+            + 1;
+        rolls.frame.truncate(total_frames);
+        rolls.onset.truncate(total_frames);
+        rolls.offset.truncate(total_frames);
+        rolls.velocity.truncate(total_frames);
+
+        let notes = self.decode_notes(&rolls);
+        println!("transcribed {} notes", notes.len());
+
+        Ok(notes)
+    }
+
+    /// runs one segment through the model, returning [frame, onset, offset, velocity] rolls
+    fn forward_segment(&self, chunk: &Tensor) -> Result<[Vec<Vec<f32>>; 4]> {
+        let input = IValue::Tensor(chunk.unsqueeze(0));
+        let output = tch::no_grad(|| self.model.forward_is(&[input]))?;
+
+        let tensors = match output {
+            IValue::Tuple(values) if values.len() == 4 => values
+                .into_iter()
+                .map(|v| match v {
+                    IValue::Tensor(t) => Ok(t),
+                    other => Err(anyhow!("unexpected transcription output: {:?}", other)),
+                })
+                .collect::<Result<Vec<_>>>()?,
+            other => {
+                return Err(anyhow!(
+                    "transcription model must return a 4-tuple, got {:?}",
+                    other
+                ))
+            }
+        };
+
+        let mut rolls = tensors.into_iter().map(|t| {
+            let roll = t.squeeze_dim(0).to_device(Device::Cpu);
+            Vec::<Vec<f32>>::try_from(roll)
+        });
+
+        Ok([
+            rolls.next().unwrap()?,
+            rolls.next().unwrap()?,
+            rolls.next().unwrap()?,
+            rolls.next().unwrap()?,
+        ])
+    }
+
+    /// turns frame-level rolls into notes: a note starts at an onset peak and
+    /// lasts until an offset peak, the frame activation drops, or the key is re-struck
+    fn decode_notes(&self, rolls: &PianoRolls) -> Vec<NoteEvent> {
+        let n_frames = rolls.frame.len();
+        let fps = self.spec.frames_per_second;
+        let mut notes = Vec::new();
+
+        let is_peak = |roll: &[Vec<f32>], t: usize, k: usize, threshold: f32| {
+            let v = roll[t][k];
+            v > threshold
+                && (t == 0 || v >= roll[t - 1][k])
+                && (t + 1 >= roll.len() || v > roll[t + 1][k])
+        };
+
+        for key in 0..self.spec.classes {
+            let mut t = 0;
+            while t < n_frames {
+                if !is_peak(&rolls.onset, t, key, ONSET_THRESHOLD) {
+                    t += 1;
+                    continue;
+                }
+
+                let onset_frame = t;
+                let mut end = t + 1;
+                while end < n_frames {
+                    if is_peak(&rolls.offset, end, key, OFFSET_THRESHOLD)
+                        || is_peak(&rolls.onset, end, key, ONSET_THRESHOLD)
+                        || rolls.frame[end][key] < FRAME_THRESHOLD
+                    {
+                        break;
+                    }
+                    end += 1;
+                }
+
+                let velocity = (rolls.velocity[onset_frame][key] * 128.0).round() as i32;
+
+                notes.push(NoteEvent {
+                    onset: onset_frame as f64 / fps,
+                    offset: end as f64 / fps,
+                    pitch: self.spec.begin_note + key as u8,
+                    velocity: velocity.clamp(1, 127) as u8,
+                });
+
+                t = end;
+            }
+        }
+
+        notes.sort_by(|a, b| a.onset.total_cmp(&b.onset).then(a.pitch.cmp(&b.pitch)));
+        notes
+    }
+}
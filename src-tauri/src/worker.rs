@@ -1,11 +1,20 @@
+use crate::cancellation::{ActiveJobs, CancellationToken, Cancelled};
 use crate::db::{
-    create_asset, get_assets_by_file, get_next_queued_asset, update_asset_status, DbPool,
+    claim_next_asset, create_asset, create_stem_assets, delete_child_assets, get_assets_by_file,
+    get_file_original_filename, release_asset, renew_lease, DbPool, LeaseState, NewStem,
 };
-use crate::models::{AssetType, ProcessingStatus};
-use crate::processing::{midi_to_pdf, separate_audio, transcribe_to_midi};
+use crate::device::{configure_threads, set_background_priority};
+use crate::model_cache::ModelCache;
+use crate::models::{Asset, AssetType, ProcessingStatus};
+use crate::musicxml::MUSICXML_EXTENSION;
+use crate::processing::{midi_to_musicxml, midi_to_pdf, separate_audio, transcribe_to_midi};
+use crate::progress::{Progress, ProgressStage};
+use crate::separator::find_backend;
+use crate::settings::{load_settings, Settings};
 use anyhow::Result;
 use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
@@ -19,18 +28,54 @@ pub struct ProcessingProgress {
     pub asset_type: String,
     pub title: String,
     pub description: String,
-    pub progress: f32, // 0.0 to 1.0
+    pub progress: f32, // 0.0 to 1.0, never decreases while a job runs
+    // set while a job is running, absent on lifecycle events
+    pub stage: Option<ProgressStage>,
+    pub chunk_index: Option<u32>,
+    pub chunk_count: Option<u32>,
+    pub eta_seconds: Option<f64>,
+    pub throughput: Option<f64>, // seconds of audio per second
 }
 
-pub fn start_worker(app: AppHandle, pool: DbPool, shutdown: Arc<AtomicBool>) {
+/// where the worker reports job progress. the app forwards updates to the window,
+/// the cli prints them
+pub trait ProgressSink {
+    fn send(&self, progress: ProcessingProgress);
+}
+
+impl ProgressSink for AppHandle {
+    fn send(&self, progress: ProcessingProgress) {
+        let _ = self.emit("processing_progress", progress);
+    }
+}
+
+/// how long a claimed job stays with its worker without a renewal. a worker that
+/// died holds its jobs for at most this long
+const LEASE_DURATION: Duration = Duration::from_secs(60);
+
+/// how often a running job renews its lease, often enough to survive a few
+/// renewals failing on a busy database
+const LEASE_RENEWAL_INTERVAL: Duration = Duration::from_secs(15);
+
+/// unique per worker, recorded on the assets it claims
+pub fn new_worker_id() -> String {
+    format!("{}-{}", std::process::id(), Uuid::new_v4())
+}
+
+pub fn start_worker(app: AppHandle, pool: DbPool, jobs: ActiveJobs, shutdown: Arc<AtomicBool>) {
     thread::spawn(move || {
-        println!("background worker started");
+        let worker_id = new_worker_id();
+        println!("background worker {} started", worker_id);
+
+        // models stay loaded between jobs, so a queued batch only pays for loading once
+        let mut models = ModelCache::new();
 
         while !shutdown.load(Ordering::Relaxed) {
-            match process_next_job(&app, &pool) {
+            match process_next_job(&app, &pool, &worker_id, &mut models, &jobs) {
                 Ok(had_job) => {
                     if !had_job {
-                        // no jobs, sleep briefly
+                        // no jobs, free what we no longer need and sleep briefly
+                        models.evict_idle();
                         thread::sleep(Duration::from_millis(500));
                     }
                 }
@@ -45,8 +90,16 @@ pub fn start_worker(app: AppHandle, pool: DbPool, shutdown: Arc<AtomicBool>) {
     });
 }
 
-fn process_next_job(app: &AppHandle, pool: &DbPool) -> Result<bool> {
-    let asset = get_next_queued_asset(pool)?;
+/// claims the next queued job for `worker_id` and runs it to completion, returns
+/// false if the queue was empty
+pub fn process_next_job(
+    sink: &impl ProgressSink,
+    pool: &DbPool,
+    worker_id: &str,
+    models: &mut ModelCache,
+    jobs: &ActiveJobs,
+) -> Result<bool> {
+    let asset = claim_next_asset(pool, worker_id, LEASE_DURATION)?;
 
     if let Some(asset) = asset {
         println!(
@@ -54,11 +107,11 @@ fn process_next_job(app: &AppHandle, pool: &DbPool) -> Result<bool> {
             asset.id, asset.asset_type
         );
 
-        // mark as processing
-        update_asset_status(pool, &asset.id, ProcessingStatus::Processing, None)?;
+        // claimed as processing, cancel_processing can reach the job through its token
+        let cancel = jobs.start(&asset.file_id);
 
         emit_progress(
-            app,
+            sink,
             &asset.file_id,
             &asset.id,
             &asset.asset_type,
@@ -67,23 +120,92 @@ fn process_next_job(app: &AppHandle, pool: &DbPool) -> Result<bool> {
             0.0,
         );
 
-        // dispatch based on type
-        let result = match asset.asset_type {
-            AssetType::Original => process_separation(app, pool, &asset),
-            AssetType::Midi => process_transcription(app, pool, &asset),
-            AssetType::Pdf => process_pdf_conversion(app, pool, &asset),
-            _ => {
-                // other stems don't have follow-up processing
-                update_asset_status(pool, &asset.id, ProcessingStatus::Completed, None)?;
-                Ok(())
+        let result = thread::scope(|scope| {
+            // the lease is held for as long as the sender lives
+            let (_running, stopped) = mpsc::channel::<()>();
+            scope.spawn(|| hold_lease(pool, &asset.id, worker_id, &cancel, stopped));
+
+            // threads and priority are read per job, a settings change applies to the
+            // next one. inside the job, so settings that fail to load fail the job
+            // instead of leaving it claimed
+            apply_runtime_settings(&load_settings(pool)?);
+
+            // dispatch based on type
+            match asset.asset_type {
+                AssetType::Original => {
+                    process_separation(sink, pool, models, &asset, worker_id, &cancel)
+                }
+                AssetType::Midi => process_transcription(sink, pool, &asset, &cancel),
+                AssetType::MusicXml => process_musicxml_export(sink, pool, &asset, &cancel),
+                AssetType::Pdf => process_pdf_conversion(sink, pool, &asset, &cancel),
+                _ => {
+                    // other stems don't have follow-up processing
+                    Ok(())
+                }
             }
+        });
+
+        jobs.finish(&asset.file_id);
+
+        // a cancel that lands after the last check still wins over completion
+        let result = match result {
+            Ok(_) if cancel.is_cancelled() => Err(Cancelled.into()),
+            other => other,
         };
 
-        match result {
-            Ok(_) => {
-                update_asset_status(pool, &asset.id, ProcessingStatus::Completed, None)?;
+        let (status, err_msg) = match &result {
+            Ok(_) => (ProcessingStatus::Completed, None),
+            Err(e) if e.is::<Cancelled>() => (ProcessingStatus::Cancelled, None),
+            Err(e) => (ProcessingStatus::Failed, Some(format!("{:?}", e))),
+        };
+        let status = match release_asset(
+            pool,
+            &asset.id,
+            worker_id,
+            status.clone(),
+            err_msg.as_deref(),
+        )? {
+            LeaseState::Held => status,
+            // the job may have finished before it noticed, the cancel still wins
+            LeaseState::Cancelled => ProcessingStatus::Cancelled,
+            LeaseState::Lost => {
+                // outputs share their paths with the new owner's, so they're left alone
+                println!(
+                    "asset {} was taken over by another worker, dropping the result",
+                    asset.id
+                );
+                return Ok(true);
+            }
+        };
+
+        match status {
+            ProcessingStatus::Cancelled => {
+                println!("asset {} cancelled", asset.id);
+
+                // outputs are written at the end, anything there now is partial.
+                // the original's path is the upload itself, but stems registered
+                // just before the cancel landed go with it
+                if matches!(asset.asset_type, AssetType::Original) {
+                    for path in delete_child_assets(pool, &asset.id)? {
+                        let _ = std::fs::remove_file(path);
+                    }
+                } else {
+                    let _ = std::fs::remove_file(&asset.file_path);
+                }
+
                 emit_progress(
-                    app,
+                    sink,
+                    &asset.file_id,
+                    &asset.id,
+                    &asset.asset_type,
+                    "cancelled",
+                    "processing was cancelled",
+                    0.0,
+                );
+            }
+            ProcessingStatus::Completed => {
+                emit_progress(
+                    sink,
                     &asset.file_id,
                     &asset.id,
                     &asset.asset_type,
@@ -95,12 +217,11 @@ fn process_next_job(app: &AppHandle, pool: &DbPool) -> Result<bool> {
                 // check if we should queue the next stage
                 let _ = queue_next_stage_for_target(pool, &asset);
             }
-            Err(e) => {
-                let err_msg = format!("{:?}", e);
+            _ => {
+                let err_msg = err_msg.unwrap_or_default();
                 eprintln!("job failed: {}", err_msg);
-                update_asset_status(pool, &asset.id, ProcessingStatus::Failed, Some(&err_msg))?;
                 emit_progress(
-                    app,
+                    sink,
                     &asset.file_id,
                     &asset.id,
                     &asset.asset_type,
@@ -120,12 +241,34 @@ fn process_next_job(app: &AppHandle, pool: &DbPool) -> Result<bool> {
     }
 }
 
+/// renews the lease on a running job until `stopped` disconnects. a lost lease
+/// means the job was cancelled or its asset taken over, either way it's stopped
+/// rather than left to race the new owner
+fn hold_lease(
+    pool: &DbPool,
+    asset_id: &str,
+    worker_id: &str,
+    cancel: &CancellationToken,
+    stopped: Receiver<()>,
+) {
+    while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(LEASE_RENEWAL_INTERVAL) {
+        match renew_lease(pool, asset_id, worker_id, LEASE_DURATION) {
+            Ok(true) => {}
+            Ok(false) => {
+                println!("lost the lease on asset {}, stopping", asset_id);
+                cancel.cancel();
+                return;
+            }
+            Err(e) => eprintln!("failed to renew the lease on asset {}: {:?}", asset_id, e),
+        }
+    }
+}
+
 fn queue_next_stage_for_target(
     pool: &DbPool,
     completed_asset: &crate::models::Asset,
 ) -> Result<()> {
     use crate::db::{get_file_target_stage, set_target_stage};
-    use std::path::PathBuf;
 
     // get the target stage for this file
     let target_stage = match get_file_target_stage(pool, &completed_asset.file_id)? {
@@ -138,15 +281,19 @@ fn queue_next_stage_for_target(
     // get all assets for this file
     let assets = get_assets_by_file(pool, &completed_asset.file_id)?;
 
-    let has_stems = assets.iter().any(|a| {
-        matches!(a.asset_type, AssetType::StemPiano)
-            && matches!(a.status, ProcessingStatus::Completed)
-    });
+    let has_stems = assets
+        .iter()
+        .any(|a| a.asset_type.is_stem() && matches!(a.status, ProcessingStatus::Completed));
 
     let has_midi = assets.iter().any(|a| {
         matches!(a.asset_type, AssetType::Midi) && matches!(a.status, ProcessingStatus::Completed)
     });
 
+    let has_musicxml = assets.iter().any(|a| {
+        matches!(a.asset_type, AssetType::MusicXml)
+            && matches!(a.status, ProcessingStatus::Completed)
+    });
+
     let has_pdf = assets.iter().any(|a| {
         matches!(a.asset_type, AssetType::Pdf) && matches!(a.status, ProcessingStatus::Completed)
     });
@@ -155,6 +302,7 @@ fn queue_next_stage_for_target(
     let target_reached = match target_stage.as_str() {
         "stems" => has_stems,
         "midi" => has_midi,
+        "musicxml" => has_musicxml,
         "pdf" => has_pdf,
         _ => false,
     };
@@ -165,105 +313,52 @@ fn queue_next_stage_for_target(
         return Ok(());
     }
 
-    // figure out what to queue next
+    // figure out what to queue next. the pipeline is
+    // stems -> midi -> musicxml -> pdf, each stage hanging off the previous one
     match target_stage.as_str() {
         "stems" => {
             // shouldn't get here, but just in case
             println!("target is stems but not reached yet");
         }
-        "midi" => {
-            if has_stems && !has_midi {
-                // queue midi
-                let existing_midi = assets
-                    .iter()
-                    .find(|a| matches!(a.asset_type, AssetType::Midi));
-
-                if existing_midi.is_none() {
-                    println!("creating and queueing midi asset");
-                    let midi_id = Uuid::new_v4().to_string();
-
-                    // construct path
-                    let file_path = &completed_asset.file_path;
-                    let file_dir = PathBuf::from(file_path).parent().unwrap().to_path_buf();
-                    let midi_path = file_dir.join("stem_piano.midi");
-
-                    let piano_stem = assets
-                        .iter()
-                        .find(|a| matches!(a.asset_type, AssetType::StemPiano))
-                        .ok_or_else(|| anyhow::anyhow!("piano stem not found"))?;
-
-                    create_asset(
-                        pool,
-                        &midi_id,
-                        &completed_asset.file_id,
-                        Some(&piano_stem.id),
-                        AssetType::Midi,
-                        midi_path.to_str().unwrap(),
-                        ProcessingStatus::Queued,
-                    )?;
-                }
-            }
+        "midi" | "musicxml" | "pdf" if has_stems && !has_midi => {
+            let piano_stem = find_piano_source(&assets)
+                .ok_or_else(|| anyhow::anyhow!("piano stem not found"))?;
+            queue_derived_asset(
+                pool,
+                completed_asset,
+                &assets,
+                piano_stem,
+                AssetType::Midi,
+                "stem_piano.midi",
+            )?;
         }
-        "pdf" => {
-            if has_stems && !has_midi {
-                // queue midi first
-                let existing_midi = assets
-                    .iter()
-                    .find(|a| matches!(a.asset_type, AssetType::Midi));
-
-                if existing_midi.is_none() {
-                    println!("creating and queueing midi asset (for pdf)");
-                    let midi_id = Uuid::new_v4().to_string();
-
-                    let file_path = &completed_asset.file_path;
-                    let file_dir = PathBuf::from(file_path).parent().unwrap().to_path_buf();
-                    let midi_path = file_dir.join("stem_piano.midi");
-
-                    let piano_stem = assets
-                        .iter()
-                        .find(|a| matches!(a.asset_type, AssetType::StemPiano))
-                        .ok_or_else(|| anyhow::anyhow!("piano stem not found"))?;
-
-                    create_asset(
-                        pool,
-                        &midi_id,
-                        &completed_asset.file_id,
-                        Some(&piano_stem.id),
-                        AssetType::Midi,
-                        midi_path.to_str().unwrap(),
-                        ProcessingStatus::Queued,
-                    )?;
-                }
-            } else if has_midi && !has_pdf {
-                // queue pdf
-                let existing_pdf = assets
-                    .iter()
-                    .find(|a| matches!(a.asset_type, AssetType::Pdf));
-
-                if existing_pdf.is_none() {
-                    println!("creating and queueing pdf asset");
-                    let pdf_id = Uuid::new_v4().to_string();
-
-                    let file_path = &completed_asset.file_path;
-                    let file_dir = PathBuf::from(file_path).parent().unwrap().to_path_buf();
-                    let pdf_path = file_dir.join("stem_piano.pdf");
-
-                    let midi_asset = assets
-                        .iter()
-                        .find(|a| matches!(a.asset_type, AssetType::Midi))
-                        .ok_or_else(|| anyhow::anyhow!("midi asset not found"))?;
-
-                    create_asset(
-                        pool,
-                        &pdf_id,
-                        &completed_asset.file_id,
-                        Some(&midi_asset.id),
-                        AssetType::Pdf,
-                        pdf_path.to_str().unwrap(),
-                        ProcessingStatus::Queued,
-                    )?;
-                }
-            }
+        "musicxml" | "pdf" if has_midi && !has_musicxml => {
+            let midi_asset = assets
+                .iter()
+                .find(|a| matches!(a.asset_type, AssetType::Midi))
+                .ok_or_else(|| anyhow::anyhow!("midi asset not found"))?;
+            queue_derived_asset(
+                pool,
+                completed_asset,
+                &assets,
+                midi_asset,
+                AssetType::MusicXml,
+                &format!("stem_piano.{}", MUSICXML_EXTENSION),
+            )?;
+        }
+        "pdf" if has_musicxml && !has_pdf => {
+            let musicxml_asset = assets
+                .iter()
+                .find(|a| matches!(a.asset_type, AssetType::MusicXml))
+                .ok_or_else(|| anyhow::anyhow!("musicxml asset not found"))?;
+            queue_derived_asset(
+                pool,
+                completed_asset,
+                &assets,
+                musicxml_asset,
+                AssetType::Pdf,
+                "stem_piano.pdf",
+            )?;
         }
         _ => {}
     }
@@ -271,57 +366,145 @@ fn queue_next_stage_for_target(
     Ok(())
 }
 
-fn process_separation(app: &AppHandle, pool: &DbPool, asset: &crate::models::Asset) -> Result<()> {
+/// creates a queued asset of `asset_type` next to the completed one, unless
+/// the file already has one
+fn queue_derived_asset(
+    pool: &DbPool,
+    completed_asset: &Asset,
+    assets: &[Asset],
+    parent: &Asset,
+    asset_type: AssetType,
+    file_name: &str,
+) -> Result<()> {
+    if assets.iter().any(|a| a.asset_type == asset_type) {
+        return Ok(());
+    }
+
+    println!("creating and queueing {} asset", asset_type.to_string());
+    let file_dir = Path::new(&completed_asset.file_path)
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("asset has no parent directory"))?;
+    let path = file_dir.join(file_name);
+
+    create_asset(
+        pool,
+        &Uuid::new_v4().to_string(),
+        &completed_asset.file_id,
+        Some(&parent.id),
+        asset_type,
+        path.to_str().unwrap(),
+        ProcessingStatus::Queued,
+    )
+}
+
+/// runs ahead of any tch call of the job, the first one on the worker thread
+/// spawns libtorch's threads and they keep the priority they start with
+fn apply_runtime_settings(settings: &Settings) {
+    set_background_priority(settings.background_priority);
+    configure_threads(settings.intra_op_threads, settings.inter_op_threads);
+}
+
+/// the stem transcription should read from. models without a dedicated piano
+/// stem (4-stem demucs) only have "other", which is the closest we can get
+pub fn find_piano_source(assets: &[Asset]) -> Option<&Asset> {
+    let completed = || {
+        assets
+            .iter()
+            .filter(|a| matches!(a.status, ProcessingStatus::Completed))
+    };
+
+    completed()
+        .find(|a| matches!(a.asset_type, AssetType::StemPiano))
+        .or_else(|| completed().find(|a| matches!(a.asset_type, AssetType::StemOther)))
+}
+
+fn process_separation(
+    sink: &impl ProgressSink,
+    pool: &DbPool,
+    models: &mut ModelCache,
+    asset: &crate::models::Asset,
+    worker_id: &str,
+    cancel: &CancellationToken,
+) -> Result<()> {
     let input_path = Path::new(&asset.file_path);
     let output_dir = input_path.parent().unwrap();
-    let model_path = Path::new("models/hdemucs.pt");
-
-    let app_clone = app.clone();
-    let file_id = asset.file_id.clone();
-    let asset_id = asset.id.clone();
+    // the backend is read per job, a settings change applies to the next separation
+    let settings = load_settings(pool)?;
+    let backend = find_backend(&settings.separator)?;
+    let separator = models.get_or_load(&backend, settings.inference_device()?)?;
+
+    let stems = separate_audio(
+        input_path,
+        output_dir,
+        separator,
+        &settings.separation_options(),
+        settings.stem_format,
+        true,
+        cancel,
+        |progress| {
+            emit_job_progress(
+                sink,
+                &asset.file_id,
+                &asset.id,
+                &AssetType::Original,
+                "separating",
+                progress,
+            );
+        },
+    )?;
 
-    let stem_paths = separate_audio(input_path, output_dir, model_path, |progress| {
-        emit_progress(
-            &app_clone,
-            &file_id,
-            &asset_id,
-            &AssetType::Original,
-            "separating",
-            &format!("processing audio: {:.0}%", progress * 100.0),
-            progress,
-        );
-    })?;
+    // stems are complete, don't register them if the job was cancelled meanwhile.
+    // the cancel branch leaves the original's folder alone, so they're removed here
+    if cancel.is_cancelled() {
+        for stem in stems.values() {
+            let _ = std::fs::remove_file(&stem.path);
+        }
+        return Err(Cancelled.into());
+    }
 
     // create asset records for each stem (all marked as completed)
-    for (stem_name, stem_path) in stem_paths {
-        let asset_type = match stem_name.as_str() {
-            "other" => AssetType::StemPiano,
-            "vocals" => AssetType::StemVocals,
-            "drums" => AssetType::StemDrums,
-            "bass" => AssetType::StemBass,
-            _ => continue,
+    let mut new_stems = Vec::new();
+    for (stem_name, stem) in stems {
+        let asset_type = match AssetType::from_stem_name(&stem_name)
+            .or_else(|| AssetType::from_residual_id(&stem_name))
+        {
+            Some(asset_type) => asset_type,
+            None => continue,
         };
 
-        let stem_id = Uuid::new_v4().to_string();
+        // the stem is still usable, but whoever exports it should know. it's
+        // stored with the asset so the ui can keep warning
+        if stem.clipping.is_clipped() {
+            println!("{} stem clips: {}", stem_name, stem.clipping.describe());
+        }
 
-        create_asset(
-            pool,
-            &stem_id,
-            &asset.file_id,
-            Some(&asset.id),
+        new_stems.push(NewStem {
+            id: Uuid::new_v4().to_string(),
             asset_type,
-            &stem_path,
-            ProcessingStatus::Completed,
-        )?;
+            file_path: stem.path,
+            clipping: stem.clipping,
+        });
     }
 
-    Ok(())
+    // registered only while the lease is held, so a takeover never ends up with two sets
+    match create_stem_assets(pool, &asset.file_id, &asset.id, worker_id, &new_stems)? {
+        LeaseState::Held => Ok(()),
+        LeaseState::Cancelled => {
+            for stem in &new_stems {
+                let _ = std::fs::remove_file(&stem.file_path);
+            }
+            Err(Cancelled.into())
+        }
+        // the stem paths are the new owner's now, release_asset drops the result
+        LeaseState::Lost => Err(Cancelled.into()),
+    }
 }
 
 fn process_transcription(
-    app: &AppHandle,
+    sink: &impl ProgressSink,
     pool: &DbPool,
     asset: &crate::models::Asset,
+    cancel: &CancellationToken,
 ) -> Result<()> {
     // find parent piano stem
     let parent_id = asset
@@ -338,18 +521,14 @@ fn process_transcription(
     let input_wav = Path::new(&piano_stem.file_path);
     let midi_path = Path::new(&asset.file_path);
 
-    let app_clone = app.clone();
-    let file_id = asset.file_id.clone();
-    let asset_id = asset.id.clone();
-
-    transcribe_to_midi(input_wav, midi_path, |progress| {
-        emit_progress(
-            &app_clone,
-            &file_id,
-            &asset_id,
+    let device = load_settings(pool)?.inference_device()?;
+    transcribe_to_midi(input_wav, midi_path, device, cancel, |progress| {
+        emit_job_progress(
+            sink,
+            &asset.file_id,
+            &asset.id,
             &AssetType::Midi,
             "transcribing",
-            &format!("converting to MIDI: {:.0}%", progress * 100.0),
             progress,
         );
     })?;
@@ -357,16 +536,17 @@ fn process_transcription(
     Ok(())
 }
 
-fn process_pdf_conversion(
-    app: &AppHandle,
+fn process_musicxml_export(
+    sink: &impl ProgressSink,
     pool: &DbPool,
     asset: &crate::models::Asset,
+    cancel: &CancellationToken,
 ) -> Result<()> {
     // find parent midi asset
     let parent_id = asset
         .parent_asset_id
         .as_ref()
-        .ok_or_else(|| anyhow::anyhow!("pdf asset has no parent"))?;
+        .ok_or_else(|| anyhow::anyhow!("musicxml asset has no parent"))?;
 
     let assets = get_assets_by_file(pool, &asset.file_id)?;
     let midi_asset = assets
@@ -375,20 +555,66 @@ fn process_pdf_conversion(
         .ok_or_else(|| anyhow::anyhow!("parent midi asset not found"))?;
 
     let midi_path = Path::new(&midi_asset.file_path);
-    let pdf_path = Path::new(&asset.file_path);
+    let musicxml_path = Path::new(&asset.file_path);
+    let title = score_title(pool, &asset.file_id)?;
 
-    let app_clone = app.clone();
-    let file_id = asset.file_id.clone();
-    let asset_id = asset.id.clone();
+    midi_to_musicxml(midi_path, musicxml_path, &title, cancel, |progress| {
+        emit_job_progress(
+            sink,
+            &asset.file_id,
+            &asset.id,
+            &AssetType::MusicXml,
+            "exporting",
+            progress,
+        );
+    })?;
 
-    midi_to_pdf(midi_path, pdf_path, |progress| {
-        emit_progress(
-            &app_clone,
-            &file_id,
-            &asset_id,
+    Ok(())
+}
+
+fn process_pdf_conversion(
+    sink: &impl ProgressSink,
+    pool: &DbPool,
+    asset: &crate::models::Asset,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let parent_id = asset
+        .parent_asset_id
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("pdf asset has no parent"))?;
+
+    let assets = get_assets_by_file(pool, &asset.file_id)?;
+    let parent = assets
+        .iter()
+        .find(|a| &a.id == parent_id)
+        .ok_or_else(|| anyhow::anyhow!("parent asset not found"))?;
+
+    // the pdf hangs off the musicxml export, both are engraved from the same midi
+    // so the printed sheet matches the exported notation
+    let midi_asset = if matches!(parent.asset_type, AssetType::MusicXml) {
+        let midi_id = parent
+            .parent_asset_id
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("musicxml asset has no parent"))?;
+        assets
+            .iter()
+            .find(|a| &a.id == midi_id)
+            .ok_or_else(|| anyhow::anyhow!("parent midi asset not found"))?
+    } else {
+        parent
+    };
+
+    let midi_path = Path::new(&midi_asset.file_path);
+    let pdf_path = Path::new(&asset.file_path);
+    let title = score_title(pool, &asset.file_id)?;
+
+    midi_to_pdf(midi_path, pdf_path, &title, cancel, |progress| {
+        emit_job_progress(
+            sink,
+            &asset.file_id,
+            &asset.id,
             &AssetType::Pdf,
             "converting",
-            &format!("generating sheet music: {:.0}%", progress * 100.0),
             progress,
         );
     })?;
@@ -396,8 +622,23 @@ fn process_pdf_conversion(
     Ok(())
 }
 
+/// titles exported notation after the uploaded file, without its extension
+fn score_title(pool: &DbPool, file_id: &str) -> Result<String> {
+    let original_filename = get_file_original_filename(pool, file_id)?;
+    let title = original_filename
+        .as_deref()
+        .map(|name| {
+            Path::new(name)
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or(name)
+        })
+        .unwrap_or("untitled");
+    Ok(title.to_string())
+}
+
 fn emit_progress(
-    app: &AppHandle,
+    sink: &impl ProgressSink,
     file_id: &str,
     asset_id: &str,
     asset_type: &AssetType,
@@ -405,15 +646,41 @@ fn emit_progress(
     description: &str,
     progress: f32,
 ) {
-    let _ = app.emit(
-        "processing_progress",
-        ProcessingProgress {
-            file_id: file_id.to_string(),
-            asset_id: asset_id.to_string(),
-            asset_type: asset_type.to_string(),
-            title: title.to_string(),
-            description: description.to_string(),
-            progress,
-        },
-    );
+    sink.send(ProcessingProgress {
+        file_id: file_id.to_string(),
+        asset_id: asset_id.to_string(),
+        asset_type: asset_type.to_string(),
+        title: title.to_string(),
+        description: description.to_string(),
+        progress,
+        stage: None,
+        chunk_index: None,
+        chunk_count: None,
+        eta_seconds: None,
+        throughput: None,
+    });
+}
+
+/// forwards an update from a running job, with its stage, chunk and timing details
+fn emit_job_progress(
+    sink: &impl ProgressSink,
+    file_id: &str,
+    asset_id: &str,
+    asset_type: &AssetType,
+    title: &str,
+    progress: &Progress,
+) {
+    sink.send(ProcessingProgress {
+        file_id: file_id.to_string(),
+        asset_id: asset_id.to_string(),
+        asset_type: asset_type.to_string(),
+        title: title.to_string(),
+        description: progress.describe(),
+        progress: progress.fraction,
+        stage: Some(progress.stage),
+        chunk_index: progress.chunk.map(|(done, _)| done),
+        chunk_count: progress.chunk.map(|(_, total)| total),
+        eta_seconds: progress.eta_seconds,
+        throughput: progress.throughput,
+    });
 }
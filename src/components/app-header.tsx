@@ -6,7 +6,13 @@ import {
   Theme,
   Tooltip,
 } from "@carbon/react";
-import { Close, DownToBottom, Information, UpToTop } from "@carbon/icons-react";
+import {
+  Close,
+  DownToBottom,
+  Information,
+  Settings,
+  UpToTop,
+} from "@carbon/icons-react";
 import { exit } from "@tauri-apps/plugin-process";
 import { useStore } from "../utils/store";
 import { getCurrentWindow } from "@tauri-apps/api/window";
@@ -24,6 +30,12 @@ export const AppHeader = () => {
           </HeaderGlobalAction>
         </HeaderName>
         <HeaderGlobalBar data-tauri-drag-region>
+          <HeaderGlobalAction
+            data-tauri-drag-region
+            onClick={() => setCurrentView("settings")}
+          >
+            <Settings size={15} />
+          </HeaderGlobalAction>
           <HeaderGlobalAction
             data-tauri-drag-region
             onClick={() => setCurrentView("about")}
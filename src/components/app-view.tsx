@@ -1,6 +1,7 @@
 import { ReactNode } from "react";
 import { CurrentView } from "../utils/schema";
 import { MainView } from "./views/main-view";
+import { SettingsView } from "./views/settings-view";
 import { AboutView } from "./views/about-view";
 import { ErrorView } from "./views/error-view";
 import { useStore } from "../utils/store";
@@ -8,6 +9,7 @@ import { Theme } from "@carbon/react";
 
 export const viewMap: Record<CurrentView, ReactNode> = {
   main: <MainView />,
+  settings: <SettingsView />,
   about: <AboutView />,
   error: <ErrorView />,
 };
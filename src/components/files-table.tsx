@@ -26,9 +26,11 @@ import {
   CheckmarkFilled,
   TimeFilled,
   ErrorFilled,
+  WarningAltFilled,
 } from "@carbon/icons-react";
 import { FileWithStatus, TargetStage } from "../utils/schema";
 import { createElement, useState } from "react";
+import { describeClipping } from "../utils/files";
 
 // configuration records
 const STATUS_CONFIG: Record<
@@ -74,6 +76,7 @@ const STATUS_CONFIG: Record<
 const ACTION_BUTTON_TEXT: Record<TargetStage, string> = {
   stems: "separate",
   midi: "transcribe",
+  musicxml: "export",
   pdf: "convert",
 };
 
@@ -122,6 +125,14 @@ const StageCell = ({
     ((stage === "stems" && file.current_progress.asset_type === "original") ||
       file.current_progress.asset_type === stage);
 
+  // stems that clipped when they were written, exports of them clip too
+  const clippedStems =
+    stage === "stems"
+      ? file.assets.filter(
+          (a) => a.status === "completed" && a.clipping?.clipped_samples,
+        )
+      : [];
+
   // check if this stage has failed
   // const thisStageError = file.assets.find(
   //   (a) =>
@@ -150,6 +161,7 @@ const StageCell = ({
           )}
           {isThisStageProcessing && file.current_progress && (
             <span
+              title={file.current_progress.description}
               style={{
                 color: "var(--cds-support-info)",
               }}
@@ -157,6 +169,23 @@ const StageCell = ({
               {Math.round(file.current_progress.progress * 100)}%
             </span>
           )}
+          {clippedStems.length > 0 && (
+            <span
+              title={clippedStems
+                .map(
+                  (a) =>
+                    `${a.asset_type} clips: ${describeClipping(a.clipping!)}`,
+                )
+                .join("\n")}
+              style={{
+                display: "flex",
+                alignItems: "center",
+                color: "var(--cds-support-warning)",
+              }}
+            >
+              <WarningAltFilled size={16} />
+            </span>
+          )}
         </span>
 
         {/* action buttons */}
@@ -165,9 +194,7 @@ const StageCell = ({
             size="sm"
             kind="tertiary"
             label={`download ${stage === "stems" ? "piano" : stage === "pdf" ? "sheet music" : stage}`}
-            onClick={() =>
-              onDownload(file.id, stage === "stems" ? "stem_piano" : stage)
-            }
+            onClick={() => onDownload(file.id, stage)}
             style={{ visibility: isRowHovered ? "visible" : "hidden" }}
           >
             <Download />
@@ -231,10 +258,11 @@ const FileRow = ({
 }: FileRowProps) => {
   const [isRowHovered, setIsRowHovered] = useState(false);
 
-  const stages: TargetStage[] = ["stems", "midi", "pdf"];
+  const stages: TargetStage[] = ["stems", "midi", "musicxml", "pdf"];
   const stageInfo = {
     stems: getStageInfo(file, "stems"),
     midi: getStageInfo(file, "midi"),
+    musicxml: getStageInfo(file, "musicxml"),
     pdf: getStageInfo(file, "pdf"),
   };
 
@@ -292,6 +320,7 @@ export const FileTable = () => {
     { header: "initial audio", key: "original_filename" },
     { header: "piano audio", key: "has_stems" },
     { header: "midi", key: "has_midi" },
+    { header: "musicxml", key: "has_musicxml" },
     { header: "sheet music", key: "has_pdf" },
   ];
 
@@ -300,6 +329,7 @@ export const FileTable = () => {
     original_filename: file.original_filename,
     has_stems: "",
     has_midi: "",
+    has_musicxml: "",
     has_pdf: "",
   }));
 
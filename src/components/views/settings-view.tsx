@@ -0,0 +1,327 @@
+import {
+  Button,
+  NumberInput,
+  Select,
+  SelectItem,
+  Stack,
+  Toggle,
+} from "@carbon/react";
+import { useEffect, useState } from "react";
+import { SeparatorInfo, Settings } from "../../utils/schema";
+import {
+  getSettings,
+  listDevices,
+  listSeparators,
+  repairLibrary,
+  updateSettings,
+} from "../../utils/settings";
+
+export const SettingsView = () => {
+  const [settings, setSettings] = useState<Settings | null>(null);
+  const [separators, setSeparators] = useState<SeparatorInfo[]>([]);
+  const [devices, setDevices] = useState<string[]>([]);
+
+  useEffect(() => {
+    getSettings().then(setSettings);
+    listSeparators().then(setSeparators);
+    listDevices().then(setDevices);
+  }, []);
+
+  const save = async (next: Settings) => {
+    if (await updateSettings(next)) {
+      setSettings(next);
+    }
+  };
+
+  const toggleResidual = (
+    residual: Settings["residuals"][number],
+    enabled: boolean,
+  ) => {
+    if (!settings) return;
+    const residuals = settings.residuals.filter((r) => r !== residual);
+    save({
+      ...settings,
+      residuals: enabled ? [...residuals, residual] : residuals,
+    });
+  };
+
+  return (
+    <div style={{ padding: "1rem", width: "100%", maxWidth: "32rem" }}>
+      <h4 style={{ fontWeight: 800 }}>settings</h4>
+      {settings && (
+        <Stack gap={6} style={{ marginTop: "1rem" }}>
+          <Select
+            id="separator"
+            labelText="separation model"
+            helperText="applies from the next separation"
+            value={settings.separator}
+            onChange={(e) => save({ ...settings, separator: e.target.value })}
+          >
+            <SelectItem value="auto" text="auto" />
+            {separators.map((separator) => (
+              <SelectItem
+                key={separator.id}
+                value={separator.id}
+                text={
+                  separator.available
+                    ? separator.id
+                    : `${separator.id} (not installed)`
+                }
+                disabled={!separator.available}
+              />
+            ))}
+          </Select>
+          <NumberInput
+            id="shifts"
+            label="shifts"
+            helperText="extra passes over time-shifted audio, each one is a full separation. slower but cleaner"
+            min={0}
+            max={10}
+            value={settings.shifts}
+            onChange={(_, { value }) => {
+              const shifts = Number(value);
+              if (Number.isInteger(shifts) && shifts >= 0 && shifts <= 10) {
+                save({ ...settings, shifts });
+              }
+            }}
+          />
+          <NumberInput
+            id="segment-seconds"
+            label="segment length (seconds)"
+            helperText="chunk length per model pass. empty uses the model's own, which is also the maximum"
+            allowEmpty
+            min={1}
+            step={0.5}
+            value={settings.segment_seconds ?? ""}
+            onChange={(_, { value }) => {
+              if (value === "" || value === undefined) {
+                save({ ...settings, segment_seconds: null });
+                return;
+              }
+              const seconds = Number(value);
+              if (seconds > 0) {
+                save({ ...settings, segment_seconds: seconds });
+              }
+            }}
+          />
+          <NumberInput
+            id="overlap"
+            label="overlap"
+            helperText="share of each chunk crossfaded with the next one"
+            min={0}
+            max={0.9}
+            step={0.05}
+            value={settings.overlap}
+            onChange={(_, { value }) => {
+              const overlap = Number(value);
+              if (overlap >= 0 && overlap <= 0.9) {
+                save({ ...settings, overlap });
+              }
+            }}
+          />
+          <Select
+            id="window"
+            labelText="crossfade window"
+            value={settings.window}
+            onChange={(e) =>
+              save({
+                ...settings,
+                window: e.target.value as Settings["window"],
+              })
+            }
+          >
+            <SelectItem value="cosine" text="cosine" />
+            <SelectItem value="linear" text="linear" />
+            <SelectItem value="triangular" text="triangular" />
+          </Select>
+          <NumberInput
+            id="transition-power"
+            label="transition power"
+            helperText="higher values make the crossfades between chunks sharper"
+            min={0.1}
+            step={0.1}
+            value={settings.transition_power}
+            onChange={(_, { value }) => {
+              const power = Number(value);
+              if (power > 0) {
+                save({ ...settings, transition_power: power });
+              }
+            }}
+          />
+          <NumberInput
+            id="batch-size"
+            label="batch size"
+            helperText="chunks per model pass. higher is faster on a gpu, falls back to smaller batches when memory runs out"
+            min={1}
+            max={16}
+            step={1}
+            value={settings.batch_size}
+            onChange={(_, { value }) => {
+              const batchSize = Number(value);
+              if (Number.isInteger(batchSize) && batchSize >= 1 && batchSize <= 16) {
+                save({ ...settings, batch_size: batchSize });
+              }
+            }}
+          />
+          <Select
+            id="device"
+            labelText="device"
+            helperText="auto uses the first gpu if there is one"
+            value={settings.device}
+            onChange={(e) => save({ ...settings, device: e.target.value })}
+          >
+            {devices.map((device) => (
+              <SelectItem key={device} value={device} text={device} />
+            ))}
+          </Select>
+          <NumberInput
+            id="intra-op-threads"
+            label="cpu threads"
+            helperText="threads per operation. empty uses one per core"
+            allowEmpty
+            min={1}
+            step={1}
+            value={settings.intra_op_threads ?? ""}
+            onChange={(_, { value }) => {
+              if (value === "" || value === undefined) {
+                save({ ...settings, intra_op_threads: null });
+                return;
+              }
+              const threads = Number(value);
+              if (Number.isInteger(threads) && threads >= 1) {
+                save({ ...settings, intra_op_threads: threads });
+              }
+            }}
+          />
+          <NumberInput
+            id="inter-op-threads"
+            label="parallel operations"
+            helperText="operations run side by side on the cpu. empty lets libtorch decide. applies after a restart"
+            allowEmpty
+            min={1}
+            step={1}
+            value={settings.inter_op_threads ?? ""}
+            onChange={(_, { value }) => {
+              if (value === "" || value === undefined) {
+                save({ ...settings, inter_op_threads: null });
+                return;
+              }
+              const threads = Number(value);
+              if (Number.isInteger(threads) && threads >= 1) {
+                save({ ...settings, inter_op_threads: threads });
+              }
+            }}
+          />
+          <Toggle
+            id="background-priority"
+            labelText="background priority"
+            helperText="runs jobs at low cpu priority so the desktop stays responsive. turning it off applies after a restart"
+            labelA="off"
+            labelB="on"
+            toggled={settings.background_priority}
+            onToggle={(checked) =>
+              save({ ...settings, background_priority: checked })
+            }
+          />
+          <NumberInput
+            id="wiener-iterations"
+            label="wiener filter iterations"
+            helperText="post-filters stems to reduce bleed between them. empty turns it off, 0 only applies masks"
+            allowEmpty
+            min={0}
+            max={10}
+            step={1}
+            value={settings.wiener_iterations ?? ""}
+            onChange={(_, { value }) => {
+              if (value === "" || value === undefined) {
+                save({ ...settings, wiener_iterations: null });
+                return;
+              }
+              const iterations = Number(value);
+              if (Number.isInteger(iterations) && iterations >= 0 && iterations <= 10) {
+                save({ ...settings, wiener_iterations: iterations });
+              }
+            }}
+          />
+          <Toggle
+            id="residual-instrumental"
+            labelText="instrumental stem (everything but vocals)"
+            labelA="off"
+            labelB="on"
+            toggled={settings.residuals.includes("instrumental")}
+            onToggle={(checked) => toggleResidual("instrumental", checked)}
+          />
+          <Toggle
+            id="residual-accompaniment"
+            labelText="accompaniment stem (everything but piano)"
+            labelA="off"
+            labelB="on"
+            toggled={settings.residuals.includes("accompaniment")}
+            onToggle={(checked) => toggleResidual("accompaniment", checked)}
+          />
+          <Select
+            id="residual-method"
+            labelText="residual method"
+            helperText="subtract keeps what no stem picked up, sum only keeps separated stems"
+            value={settings.residual_method}
+            onChange={(e) =>
+              save({
+                ...settings,
+                residual_method: e.target.value as Settings["residual_method"],
+              })
+            }
+          >
+            <SelectItem value="subtract" text="mix minus excluded stems" />
+            <SelectItem value="sum" text="sum of the other stems" />
+          </Select>
+          <Select
+            id="stem-format"
+            labelText="stem format"
+            helperText="how separated stems are stored. float keeps peaks over full scale"
+            value={settings.stem_format}
+            onChange={(e) =>
+              save({
+                ...settings,
+                stem_format: e.target.value as Settings["stem_format"],
+              })
+            }
+          >
+            <SelectItem value="wav_float" text="wav, 32-bit float" />
+            <SelectItem value="wav24" text="wav, 24-bit" />
+            <SelectItem value="wav16" text="wav, 16-bit" />
+            <SelectItem value="flac24" text="flac, 24-bit" />
+            <SelectItem value="flac16" text="flac, 16-bit" />
+          </Select>
+          <Select
+            id="export-format"
+            labelText="export format"
+            helperText="format of downloaded stems. 16 and 24-bit are dithered"
+            value={settings.export_format}
+            onChange={(e) =>
+              save({
+                ...settings,
+                export_format: e.target.value as Settings["export_format"],
+              })
+            }
+          >
+            <SelectItem value="wav_float" text="wav, 32-bit float" />
+            <SelectItem value="wav24" text="wav, 24-bit" />
+            <SelectItem value="wav16" text="wav, 16-bit" />
+            <SelectItem value="flac24" text="flac, 24-bit" />
+            <SelectItem value="flac16" text="flac, 16-bit" />
+            <SelectItem value="opus" text="opus, 160 kbps" />
+          </Select>
+          <div>
+            <Button kind="tertiary" size="sm" onClick={() => repairLibrary()}>
+              repair library
+            </Button>
+            <p className="cds--form__helper-text">
+              moves folders no file points at into quarantine instead of
+              leaving them in the library
+            </p>
+          </div>
+        </Stack>
+      )}
+    </div>
+  );
+};
@@ -2,12 +2,15 @@ import { invoke } from "@tauri-apps/api/core";
 import { open, save } from "@tauri-apps/plugin-dialog";
 import {
   Asset,
+  Clipping,
   FileRecord,
   FileWithStatus,
   ProcessingProgress,
+  OutputFormat,
   ProcessingStatus,
   TargetStage,
 } from "./schema";
+import { getSettings } from "./settings";
 import { toast } from "./utils";
 
 export const uploadFile = async (): Promise<string | null> => {
@@ -90,11 +93,14 @@ export const getFilesWithStatus = async (
 
       const hasOriginal = assets.some((a) => a.asset_type === "original");
       const hasStems = assets.some(
-        (a) => a.asset_type === "stem_piano" && a.status === "completed",
+        (a) => a.asset_type.startsWith("stem_") && a.status === "completed",
       );
       const hasMidi = assets.some(
         (a) => a.asset_type === "midi" && a.status === "completed",
       );
+      const hasMusicXml = assets.some(
+        (a) => a.asset_type === "musicxml" && a.status === "completed",
+      );
       const hasPdf = assets.some(
         (a) => a.asset_type === "pdf" && a.status === "completed",
       );
@@ -130,6 +136,7 @@ export const getFilesWithStatus = async (
         has_original: hasOriginal,
         has_stems: hasStems,
         has_midi: hasMidi,
+        has_musicxml: hasMusicXml,
         has_pdf: hasPdf,
         current_status: currentStatus,
         current_asset_type: currentAssetType,
@@ -180,12 +187,52 @@ export const cancelProcessing = async (fileId: string): Promise<boolean> => {
   }
 };
 
+const FORMAT_EXTENSIONS: Record<OutputFormat, string> = {
+  wav_float: "wav",
+  wav24: "wav",
+  wav16: "wav",
+  flac24: "flac",
+  flac16: "flac",
+  opus: "opus",
+};
+
+const isAudioAsset = (asset: Asset) =>
+  asset.asset_type === "original" ||
+  asset.asset_type === "instrumental" ||
+  asset.asset_type === "accompaniment" ||
+  asset.asset_type.startsWith("stem_");
+
+// e.g. "1234 samples (0.05%) over full scale, peak +1.3 dbfs"
+export const describeClipping = (clipping: Clipping) => {
+  const percent =
+    (100 * clipping.clipped_samples) / Math.max(clipping.total_samples, 1);
+  const peak = 20 * Math.log10(Math.max(clipping.peak, Number.MIN_VALUE));
+  return `${clipping.clipped_samples} samples (${percent.toFixed(2)}%) over full scale, peak ${peak.toFixed(1)} dbfs`;
+};
+
+// the stem transcription reads, same rule as find_piano_source in the worker:
+// the piano stem, or "other" from models that don't separate piano
+export const findPianoSource = (assets: Asset[]): Asset | undefined => {
+  const completed = assets.filter((a) => a.status === "completed");
+  return (
+    completed.find((a) => a.asset_type === "stem_piano") ??
+    completed.find((a) => a.asset_type === "stem_other")
+  );
+};
+
+// audio is encoded in `format`, or the export format from settings
 export const downloadAsset = async (
   asset: Asset,
   defaultFileName: string,
+  format?: OutputFormat,
 ): Promise<boolean> => {
   try {
-    const extension = asset.file_path.split(".").pop() || "wav";
+    if (isAudioAsset(asset) && !format) {
+      format = (await getSettings())?.export_format;
+    }
+    const extension = format
+      ? FORMAT_EXTENSIONS[format]
+      : asset.file_path.split(".").pop() || "wav";
 
     const outputPath = await save({
       title: `Save ${asset.asset_type}`,
@@ -202,11 +249,22 @@ export const downloadAsset = async (
       return false; // user cancelled
     }
 
-    await invoke("download_asset", {
+    const clipping: Clipping | null = await invoke("download_asset", {
       assetPath: asset.file_path,
       destination: outputPath,
+      format,
     });
 
+    if (clipping && clipping.clipped_samples > 0) {
+      toast({
+        kind: "warning",
+        title: "exported audio clips",
+        subtitle: describeClipping(clipping),
+        actionButtonLabel: "ok",
+        actionCloses: true,
+      });
+    }
+
     return true;
   } catch (error) {
     console.error("failed to download asset:", error);
@@ -1,6 +1,6 @@
 import { z } from "zod";
 
-export const CurrentViewSchema = z.enum(["main", "about", "error"]);
+export const CurrentViewSchema = z.enum(["main", "settings", "about", "error"]);
 
 export const AppConfigSchema = z.object({
   file_upload: z.object({
@@ -24,11 +24,16 @@ export const AssetTypeSchema = z.enum([
   "stem_vocals",
   "stem_drums",
   "stem_bass",
+  "stem_guitar",
+  "stem_other",
+  "instrumental",
+  "accompaniment",
   "midi",
+  "musicxml",
   "pdf",
 ]);
 
-export const TargetStageSchema = z.enum(["stems", "midi", "pdf"]);
+export const TargetStageSchema = z.enum(["stems", "midi", "musicxml", "pdf"]);
 
 export const FileRecordSchema = z.object({
   id: z.string(),
@@ -37,6 +42,12 @@ export const FileRecordSchema = z.object({
   created_at: z.number(),
 });
 
+export const ClippingSchema = z.object({
+  clipped_samples: z.number(),
+  total_samples: z.number(),
+  peak: z.number(),
+});
+
 export const AssetSchema = z.object({
   id: z.string(),
   file_id: z.string(),
@@ -46,6 +57,7 @@ export const AssetSchema = z.object({
   status: ProcessingStatusSchema,
   error_message: z.string().nullable(),
   created_at: z.number(),
+  clipping: ClippingSchema.nullable(),
 });
 
 export const ProcessingProgressSchema = z.object({
@@ -55,6 +67,55 @@ export const ProcessingProgressSchema = z.object({
   title: z.string(),
   description: z.string(),
   progress: z.number().min(0).max(1),
+  stage: z
+    .enum([
+      "loading",
+      "resampling",
+      "separating",
+      "filtering",
+      "transcribing",
+      "notating",
+      "engraving",
+      "saving",
+    ])
+    .nullish(),
+  chunk_index: z.number().nullish(),
+  chunk_count: z.number().nullish(),
+  eta_seconds: z.number().nullish(),
+  throughput: z.number().nullish(),
+});
+
+export const OutputFormatSchema = z.enum([
+  "wav_float",
+  "wav24",
+  "wav16",
+  "flac24",
+  "flac16",
+  "opus",
+]);
+
+export const SettingsSchema = z.object({
+  separator: z.string(),
+  shifts: z.number().int().min(0),
+  segment_seconds: z.number().positive().nullable(),
+  overlap: z.number().min(0).max(0.9),
+  window: z.enum(["cosine", "linear", "triangular"]),
+  transition_power: z.number().positive(),
+  batch_size: z.number().int().min(1).max(16),
+  device: z.string(),
+  intra_op_threads: z.number().int().min(1).nullable(),
+  inter_op_threads: z.number().int().min(1).nullable(),
+  background_priority: z.boolean(),
+  wiener_iterations: z.number().int().min(0).max(10).nullable(),
+  residuals: z.array(z.enum(["instrumental", "accompaniment"])),
+  residual_method: z.enum(["subtract", "sum"]),
+  stem_format: OutputFormatSchema.exclude(["opus"]),
+  export_format: OutputFormatSchema,
+});
+
+export const SeparatorInfoSchema = z.object({
+  id: z.string(),
+  available: z.boolean(),
 });
 
 // derived type for table display
@@ -65,6 +126,7 @@ export const FileWithStatusSchema = z.object({
   has_original: z.boolean(),
   has_stems: z.boolean(),
   has_midi: z.boolean(),
+  has_musicxml: z.boolean(),
   has_pdf: z.boolean(),
   current_status: ProcessingStatusSchema.nullable(),
   current_asset_type: AssetTypeSchema.nullable(),
@@ -82,4 +144,8 @@ export type FileRecord = z.infer<typeof FileRecordSchema>;
 export type Asset = z.infer<typeof AssetSchema>;
 export type ProcessingProgress = z.infer<typeof ProcessingProgressSchema>;
 export type TargetStage = z.infer<typeof TargetStageSchema>;
+export type Settings = z.infer<typeof SettingsSchema>;
+export type OutputFormat = z.infer<typeof OutputFormatSchema>;
+export type Clipping = z.infer<typeof ClippingSchema>;
+export type SeparatorInfo = z.infer<typeof SeparatorInfoSchema>;
 export type FileWithStatus = z.infer<typeof FileWithStatusSchema>;
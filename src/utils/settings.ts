@@ -0,0 +1,84 @@
+import { invoke } from "@tauri-apps/api/core";
+import { SeparatorInfo, Settings } from "./schema";
+import { toast } from "./utils";
+
+export const getSettings = async (): Promise<Settings | null> => {
+  try {
+    const settings: Settings = await invoke("get_settings");
+    return settings;
+  } catch (error) {
+    console.error("failed to load settings:", error);
+    toast({
+      kind: "error",
+      title: "failed to load settings",
+      subtitle: String(error) || undefined,
+      actionButtonLabel: "ok",
+      actionCloses: true,
+    });
+    return null;
+  }
+};
+
+export const updateSettings = async (settings: Settings): Promise<boolean> => {
+  try {
+    await invoke("update_settings", { settings });
+    return true;
+  } catch (error) {
+    console.error("failed to save settings:", error);
+    toast({
+      kind: "error",
+      title: "failed to save settings",
+      subtitle: String(error) || undefined,
+      actionButtonLabel: "ok",
+      actionCloses: true,
+    });
+    return false;
+  }
+};
+
+export const listDevices = async (): Promise<string[]> => {
+  try {
+    const devices: string[] = await invoke("list_devices");
+    return devices;
+  } catch (error) {
+    console.error("failed to list devices:", error);
+    return [];
+  }
+};
+
+export const listSeparators = async (): Promise<SeparatorInfo[]> => {
+  try {
+    const separators: SeparatorInfo[] = await invoke("list_separators");
+    return separators;
+  } catch (error) {
+    console.error("failed to list separators:", error);
+    return [];
+  }
+};
+
+export const repairLibrary = async (): Promise<number | null> => {
+  try {
+    const moved: number = await invoke("repair_library");
+    toast({
+      kind: "success",
+      title: "library checked",
+      subtitle:
+        moved > 0
+          ? `moved ${moved} unreferenced folders to quarantine`
+          : "nothing to repair",
+      actionButtonLabel: "ok",
+      actionCloses: true,
+    });
+    return moved;
+  } catch (error) {
+    console.error("failed to repair library:", error);
+    toast({
+      kind: "error",
+      title: "failed to repair library",
+      subtitle: String(error) || undefined,
+      actionButtonLabel: "ok",
+      actionCloses: true,
+    });
+    return null;
+  }
+};
@@ -1,11 +1,16 @@
 import { create } from "zustand";
-import { AppConfig, AppConfigSchema, FileWithStatus } from "./schema";
+import {
+  AppConfig,
+  AppConfigSchema,
+  CurrentView,
+  FileWithStatus,
+} from "./schema";
 import { defaultAppConfig } from "./config";
 import { toast } from "./utils";
 
 interface AppStore {
-  currentView: "main" | "about" | "error";
-  setCurrentView: (view: "main" | "about" | "error") => void;
+  currentView: CurrentView;
+  setCurrentView: (view: CurrentView) => void;
 
   appConfig: AppConfig;
   setAppConfig: (config: unknown) => void;
@@ -1,11 +1,6 @@
 import { useEffect, useRef, useState } from "react";
 import { listen, UnlistenFn } from "@tauri-apps/api/event";
-import {
-  FileWithStatus,
-  ProcessingProgress,
-  TargetStage,
-  AssetType,
-} from "./schema";
+import { FileWithStatus, ProcessingProgress, TargetStage } from "./schema";
 import {
   getFilesWithStatus,
   uploadFile as uploadFileApi,
@@ -13,6 +8,7 @@ import {
   cancelProcessing as cancelProcessingApi,
   deleteFile as deleteFileApi,
   downloadAsset as downloadAssetApi,
+  findPianoSource,
 } from "./files";
 
 // helper types for stage status
@@ -100,16 +96,14 @@ export const useFiles = () => {
   // helper to get stage info for a file
   const getStageInfo = (
     file: FileWithStatus,
-    stage: "stems" | "midi" | "pdf",
+    stage: TargetStage,
   ): StageInfo => {
-    const stageAssetTypes: Record<string, AssetType[]> = {
-      stems: ["stem_piano"],
-      midi: ["midi"],
-      pdf: ["pdf"],
-    };
+    // the stems a model produces vary, any of them counts
+    const isStageAsset = (assetType: string) =>
+      stage === "stems" ? assetType.startsWith("stem_") : assetType === stage;
 
     const relevantAssets = file.assets.filter((a) =>
-      stageAssetTypes[stage].includes(a.asset_type as AssetType),
+      isStageAsset(a.asset_type),
     );
 
     const hasCompleted = relevantAssets.some((a) => a.status === "completed");
@@ -137,7 +131,7 @@ export const useFiles = () => {
       !hasProcessing &&
       !hasFailed &&
       !originalProcessing;
-    const stageOrder = { stems: 0, midi: 1, pdf: 2 };
+    const stageOrder = { stems: 0, midi: 1, musicxml: 2, pdf: 3 };
     const isInPath =
       file.target_stage &&
       stageOrder[stage] <=
@@ -210,7 +204,11 @@ export const useFiles = () => {
 
   const downloadAsset = async (fileId: string, assetType: string) => {
     const file = files.find((f) => f.id === fileId);
-    const asset = file?.assets.find((a) => a.asset_type === assetType);
+    // "stems" downloads the stem transcription works from
+    const asset =
+      assetType === "stems"
+        ? file && findPianoSource(file.assets)
+        : file?.assets.find((a) => a.asset_type === assetType);
     if (asset && file) {
       return await downloadAssetApi(
         asset,